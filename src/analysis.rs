@@ -0,0 +1,367 @@
+//! Static analysis of a ROM, for guessing how to run ROMs the database doesn't know.
+//!
+//! Code is found by following jumps, calls and skips from the entry point, so
+//! sprites and other data don't count. The platform is the newest one whose
+//! opcodes appear, and instructions whose result depends on a quirk are listed
+//! with their addresses. Findings that point to one setting of their quirk,
+//! such as `8XY6` naming a VY to shift, are used as run-time defaults unless
+//! the guessed platform says otherwise.
+
+use crate::config::Config;
+use crate::machine::MEMORY_SIZE;
+use crate::opcode::{self, Operation};
+use crate::quirks::Platform;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+/// How far past `FX55`/`FX65` to look for code that reads I again
+const I_REUSE_WINDOW: usize = 8;
+
+/// Something the platform guess is based on
+#[derive(Debug, Clone)]
+pub struct Evidence {
+    pub addr: usize,
+    pub platform: Platform,
+    pub what: &'static str,
+}
+
+/// An instruction whose behaviour depends on a quirk
+#[derive(Debug, Clone)]
+pub struct QuirkUse {
+    pub addr: usize,
+    /// the name accepted by `--quirk`
+    pub quirk: &'static str,
+    /// the setting the program relies on, `None` when it fits either
+    pub value: Option<bool>,
+    pub what: &'static str,
+}
+
+#[derive(Debug, Clone)]
+pub struct Analysis {
+    pub platform: Platform,
+    pub evidence: Vec<Evidence>,
+    /// `0NNN` calls into machine code
+    pub machine_calls: Vec<usize>,
+    pub quirks: Vec<QuirkUse>,
+    /// addresses of the instructions reached from the entry point
+    pub code: BTreeSet<usize>,
+}
+
+/// The ROM's bytes and the address they are loaded at
+struct Rom<'a> {
+    bytes: &'a [u8],
+    start: usize,
+}
+
+impl Rom<'_> {
+    fn word(&self, addr: usize) -> Option<u16> {
+        let offset = addr.checked_sub(self.start)?;
+        self.bytes
+            .get(offset..offset + 2)
+            .map(|w| u16::from_be_bytes([w[0], w[1]]))
+    }
+}
+
+/// Length of the instruction at `op`, XO-CHIP's `F000 NNNN` takes two words
+fn length(op: u16) -> usize {
+    if op == 0xF000 { 4 } else { 2 }
+}
+
+/// The platform that introduced `op`, for opcodes plain CHIP-8 lacks
+fn introduced_by(op: u16) -> Option<(Platform, &'static str)> {
+    let xochip = Platform::Xochip;
+    let schip = Platform::Schip;
+    Some(match op {
+        _ if op & 0xF00F == 0x5002 => (xochip, "5XY2 saves a register range"),
+        _ if op & 0xF00F == 0x5003 => (xochip, "5XY3 loads a register range"),
+        0xF000 => (xochip, "F000 NNNN loads a 16-bit I"),
+        0xF002 => (xochip, "F002 loads the audio pattern"),
+        _ if op & 0xF0FF == 0xF001 => (xochip, "FN01 selects drawing planes"),
+        _ if op & 0xF0FF == 0xF03A => (xochip, "FX3A sets the audio pitch"),
+        _ if op & 0xFFF0 == 0x00D0 => (xochip, "00DN scrolls up"),
+        _ if op & 0xFFF0 == 0x00C0 => (schip, "00CN scrolls down"),
+        0x00FB | 0x00FC => (schip, "00FB/00FC scroll sideways"),
+        0x00FD => (schip, "00FD exits"),
+        0x00FE | 0x00FF => (schip, "00FE/00FF switch resolution"),
+        _ if op & 0xF0FF == 0xF030 => (schip, "FX30 points I at a large digit"),
+        _ if op & 0xF0FF == 0xF075 || op & 0xF0FF == 0xF085 => {
+            (schip, "FX75/FX85 use the flag registers")
+        }
+        _ if op & 0xF00F == 0xD000 => (schip, "DXY0 draws a 16x16 sprite"),
+        _ => return None,
+    })
+}
+
+/// Newer platforms run the opcodes of the older ones
+fn rank(platform: Platform) -> u8 {
+    match platform {
+        Platform::Vip | Platform::Eti660 => 0,
+        Platform::Chip48 => 1,
+        Platform::Schip => 2,
+        Platform::Xochip => 3,
+    }
+}
+
+fn reads_vf(operation: &Operation) -> bool {
+    use Operation::*;
+    match *operation {
+        SkipEqC(x, _) | SkipNeC(x, _) => x == 0xF,
+        SkipEq(x, y) | SkipNe(x, y) => x == 0xF || y == 0xF,
+        Set(_, y) => y == 0xF,
+        Or(x, y) | And(x, y) | Xor(x, y) | Add(x, y) | Sub(x, y) | SubRev(x, y) => {
+            x == 0xF || y == 0xF
+        }
+        _ => false,
+    }
+}
+
+fn uses_i(operation: &Operation) -> bool {
+    use Operation::*;
+    matches!(
+        operation,
+        DrawC(..) | Bcd(_) | Store(_) | Restore(_) | AddI(_)
+    )
+}
+
+fn sets_i(operation: &Operation) -> bool {
+    matches!(operation, Operation::SetIC(_) | Operation::SetIFont(_))
+}
+
+fn ends_block(operation: &Operation) -> bool {
+    use Operation::*;
+    matches!(
+        operation,
+        JumpC(_) | CallC(_) | Return | JumpV0C(_) | CallSysC(_)
+    )
+}
+
+impl Analysis {
+    /// Follows the code of `rom` loaded at `start`
+    pub fn new(rom: &[u8], start: usize) -> Self {
+        let rom = Rom { bytes: rom, start };
+        let code = reachable(&rom);
+        let mut analysis = Analysis {
+            platform: Platform::Vip,
+            evidence: vec![],
+            machine_calls: vec![],
+            quirks: vec![],
+            code,
+        };
+        if rom.bytes.len() > MEMORY_SIZE.saturating_sub(start) {
+            analysis.evidence.push(Evidence {
+                addr: MEMORY_SIZE,
+                platform: Platform::Xochip,
+                what: "the ROM reaches past 0xFFF",
+            });
+        }
+
+        for &addr in &analysis.code {
+            let op = rom.word(addr).unwrap_or_default();
+            if let Some((platform, what)) = introduced_by(op) {
+                analysis.evidence.push(Evidence {
+                    addr,
+                    platform,
+                    what,
+                });
+                if op == 0xF000
+                    && rom
+                        .word(addr + 2)
+                        .is_some_and(|i| i as usize >= MEMORY_SIZE)
+                {
+                    analysis.evidence.push(Evidence {
+                        addr,
+                        platform: Platform::Xochip,
+                        what: "I is pointed past 0xFFF",
+                    });
+                }
+                continue;
+            }
+            let operation = opcode::parse_opcode(op);
+            let mut quirk = |quirk, value, what| {
+                analysis.quirks.push(QuirkUse {
+                    addr,
+                    quirk,
+                    value,
+                    what,
+                });
+            };
+            match operation {
+                Operation::CallSysC(_) if op != 0 => analysis.machine_calls.push(addr),
+                Operation::Shr(x, y) | Operation::Shl(x, y) if x != y => {
+                    quirk("shift_vy", Some(true), "8XY6/8XYE with X and Y different")
+                }
+                Operation::JumpV0C(nnn) if nnn >> 8 != 0 => {
+                    quirk("jump_vx", None, "BNNN whose top nibble isn't 0")
+                }
+                Operation::Or(..) | Operation::And(..) | Operation::Xor(..) => {
+                    let next = rom.word(addr + 2).map(opcode::parse_opcode);
+                    if next.is_some_and(|next| reads_vf(&next)) {
+                        quirk("logic_vf_reset", None, "VF read right after 8XY1-8XY3")
+                    }
+                }
+                Operation::Store(_) | Operation::Restore(_) if reuses_i(&rom, addr) => quirk(
+                    "load_store_increment",
+                    Some(true),
+                    "I used again after FX55/FX65 without being set",
+                ),
+                _ => {}
+            }
+        }
+
+        analysis.platform = analysis
+            .evidence
+            .iter()
+            .map(|e| e.platform)
+            .max_by_key(|p| rank(*p))
+            .unwrap_or(Platform::Vip);
+        analysis
+    }
+
+    /// The guessed platform and quirks as a config layer. Without evidence the
+    /// guess is only the default, and the global config's platform is left alone.
+    pub fn config(&self) -> Config {
+        Config {
+            platform: (!self.evidence.is_empty()).then_some(self.platform),
+            quirks: self.applied_quirks().collect(),
+            ..Default::default()
+        }
+    }
+
+    /// The quirk settings the program relies on, leaving out those the evidence
+    /// for the platform contradicts
+    fn applied_quirks(&self) -> impl Iterator<Item = (String, bool)> + '_ {
+        self.quirks.iter().filter_map(|q| {
+            let value = q.value?;
+            let platform = self.platform.quirks();
+            let mut relied_on = platform;
+            relied_on.set(q.quirk, value).ok()?;
+            (self.evidence.is_empty() || relied_on == platform)
+                .then(|| (q.quirk.to_string(), value))
+        })
+    }
+}
+
+/// Whether code after the `FX55`/`FX65` at `addr` reads I before setting it
+fn reuses_i(rom: &Rom, addr: usize) -> bool {
+    for n in 1..=I_REUSE_WINDOW {
+        let Some(op) = rom.word(addr + n * 2) else {
+            return false;
+        };
+        let operation = opcode::parse_opcode(op);
+        if uses_i(&operation) {
+            return true;
+        }
+        if sets_i(&operation) || ends_block(&operation) || op == 0xF000 {
+            return false;
+        }
+    }
+    false
+}
+
+/// Addresses of every instruction reached from the entry point
+fn reachable(rom: &Rom) -> BTreeSet<usize> {
+    use Operation::*;
+    let mut code = BTreeSet::new();
+    let mut pending = vec![rom.start];
+    while let Some(addr) = pending.pop() {
+        let Some(op) = rom.word(addr) else {
+            continue;
+        };
+        if !code.insert(addr) {
+            continue;
+        }
+        let next = addr + length(op);
+        // skips jump over a whole instruction, which may be a long one
+        let after_next = next + rom.word(next).map(length).unwrap_or(2);
+        match opcode::parse_opcode(op) {
+            _ if op == 0x00FD => {}
+            // 5XY2 and 5XY3 decode as skips but aren't
+            _ if introduced_by(op).is_some() => pending.push(next),
+            JumpC(a) => pending.push(a as usize),
+            CallC(a) => pending.extend([a as usize, next]),
+            // targets of BNNN depend on a register
+            Return | JumpV0C(_) => {}
+            SkipEqC(..) | SkipNeC(..) | SkipEq(..) | SkipNe(..) | SkipEqKey(_) | SkipNeKey(_) => {
+                pending.extend([next, after_next])
+            }
+            _ => pending.push(next),
+        }
+    }
+    code
+}
+
+impl fmt::Display for Analysis {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "code      {} instructions reachable", self.code.len())?;
+        for e in &self.evidence {
+            writeln!(f, "  {:#05x}  {}: {}", e.addr, e.platform, e.what)?;
+        }
+        if !self.machine_calls.is_empty() {
+            let addrs: Vec<String> = self
+                .machine_calls
+                .iter()
+                .map(|a| format!("{:#05x}", a))
+                .collect();
+            writeln!(
+                f,
+                "  0NNN machine code calls at {}, run with --machine-code",
+                addrs.join(" ")
+            )?;
+        }
+        if !self.quirks.is_empty() {
+            writeln!(f, "quirks    the program depends on")?;
+            for q in &self.quirks {
+                writeln!(f, "  {:#05x}  {}: {}", q.addr, q.quirk, q.what)?;
+            }
+            for (quirk, value) in self.applied_quirks().collect::<BTreeMap<_, _>>() {
+                writeln!(f, "  run with {}={}", quirk, value)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::CARTRIDGE_ADDRESS;
+
+    #[test]
+    fn only_evidence_sets_the_platform() {
+        // v0 := 5, then a jump to itself
+        let plain = Analysis::new(&[0x60, 0x05, 0x12, 0x02], CARTRIDGE_ADDRESS);
+        assert_eq!(plain.platform, Platform::Vip);
+        assert_eq!(plain.config().platform, None);
+
+        // hires, then a jump to itself
+        let schip = Analysis::new(&[0x00, 0xFF, 0x12, 0x02], CARTRIDGE_ADDRESS);
+        assert_eq!(schip.config().platform, Some(Platform::Schip));
+    }
+
+    #[test]
+    fn quirks_the_program_relies_on_become_defaults() {
+        // v0 >>= v1, then a jump to itself
+        let vip = Analysis::new(&[0x80, 0x16, 0x12, 0x02], CARTRIDGE_ADDRESS);
+        assert_eq!(vip.config().quirks, [("shift_vy".to_string(), true)].into());
+
+        // the same shift in a SUPER-CHIP program, which shifts VX in place
+        let schip = Analysis::new(&[0x00, 0xFF, 0x80, 0x16, 0x12, 0x04], CARTRIDGE_ADDRESS);
+        assert!(schip.config().quirks.is_empty());
+
+        // BNNN fits either jump quirk
+        let jump = Analysis::new(&[0xB3, 0x00], CARTRIDGE_ADDRESS);
+        assert_eq!(jump.quirks.len(), 1);
+        assert!(jump.config().quirks.is_empty());
+    }
+
+    #[test]
+    fn code_is_followed_from_the_program_start() {
+        // a call to 0x606, which returns, then a jump to itself
+        let rom = [0x26, 0x06, 0x16, 0x02, 0x00, 0x00, 0x00, 0xEE];
+        let analysis = Analysis::new(&rom, 0x600);
+        assert_eq!(
+            analysis.code.into_iter().collect::<Vec<_>>(),
+            [0x600, 0x602, 0x606]
+        );
+    }
+}
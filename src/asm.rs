@@ -0,0 +1,303 @@
+//! Assembler for the mnemonics printed by the disassembler.
+//!
+//! One instruction per line, operands separated by commas or spaces. `name:`
+//! defines a label at the next address, `db` and `dw` emit bytes and words and
+//! `;` starts a comment. Numbers are hex with a `#` or `0x` prefix, so a
+//! misspelled label isn't taken for one, and a label can stand wherever an address or constant goes:
+//! ```text
+//! main:
+//!     set vI, sprite
+//!     draw v0, v1, #5
+//!     jmp main
+//! sprite:
+//!     db #f0 #90 #f0 #90 #90
+//! ```
+//! Lines of a `disasm` listing, `ADDRESS: [OPCODE] mnemonic`, are read too.
+
+use crate::machine::MEMORY_SIZE;
+use crate::opcode;
+use crate::symbols::SymbolMap;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+pub struct Program {
+    pub bytes: Vec<u8>,
+    pub labels: BTreeMap<String, usize>,
+}
+
+impl Program {
+    /// Labels as a symbol file, one `ADDRESS LABEL` per line
+    pub fn symbols(&self) -> String {
+        let mut by_addr: Vec<(&usize, &String)> = self
+            .labels
+            .iter()
+            .map(|(name, addr)| (addr, name))
+            .collect();
+        by_addr.sort();
+        let mut text = String::new();
+        for (addr, name) in by_addr {
+            writeln!(text, "0x{:03x} {}", addr, name).unwrap();
+        }
+        text
+    }
+}
+
+enum Operand<'a> {
+    Reg(u8),
+    I,
+    Key,
+    DelayTimer,
+    SoundTimer,
+    Sprite(u8),
+    Value(&'a str),
+}
+
+struct Statement<'a> {
+    line: usize,
+    tokens: Vec<&'a str>,
+}
+
+impl Statement<'_> {
+    fn size(&self) -> usize {
+        match self.tokens[0].to_lowercase().as_str() {
+            "db" => self.tokens.len() - 1,
+            "dw" => (self.tokens.len() - 1) * 2,
+            _ => 2,
+        }
+    }
+}
+
+/// Assembles `source` into a program loaded at `start`
+pub fn assemble(source: &str, start: usize) -> anyhow::Result<Program> {
+    let mut labels = BTreeMap::new();
+    let mut statements = vec![];
+    let mut addr = start;
+    for (n, raw) in source.lines().enumerate() {
+        let line = n + 1;
+        let text = raw.split(';').next().unwrap_or_default();
+        let text = strip_listing(text.trim());
+        let mut tokens = tokenize(text);
+        while let Some(label) = tokens.first().and_then(|t| t.strip_suffix(':')) {
+            if !is_label(label) {
+                anyhow::bail!("line {}: invalid label {}", line, label);
+            }
+            if labels.insert(label.to_string(), addr).is_some() {
+                anyhow::bail!("line {}: label {} defined twice", line, label);
+            }
+            tokens.remove(0);
+        }
+        if tokens.is_empty() {
+            continue;
+        }
+        let statement = Statement { line, tokens };
+        addr += statement.size();
+        statements.push(statement);
+    }
+    if addr > MEMORY_SIZE {
+        anyhow::bail!(
+            "program is {} bytes, more than the {} that fit in memory",
+            addr - start,
+            MEMORY_SIZE - start
+        );
+    }
+
+    let mut bytes = Vec::with_capacity(addr - start);
+    for statement in &statements {
+        let err = |e: anyhow::Error| anyhow::anyhow!("line {}: {}", statement.line, e);
+        let args = &statement.tokens[1..];
+        match statement.tokens[0].to_lowercase().as_str() {
+            "db" => {
+                for arg in args {
+                    bytes.push(value(arg, &labels, 0xFF).map_err(err)? as u8);
+                }
+            }
+            "dw" => {
+                for arg in args {
+                    let word = value(arg, &labels, 0xFFFF).map_err(err)?;
+                    bytes.extend(word.to_be_bytes());
+                }
+            }
+            _ => {
+                let opcode = encode(&statement.tokens, &labels).map_err(err)?;
+                bytes.extend(opcode.to_be_bytes());
+            }
+        }
+    }
+    Ok(Program { bytes, labels })
+}
+
+/// Splits a line at commas and whitespace
+fn tokenize(text: &str) -> Vec<&str> {
+    text.split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|t| !t.is_empty())
+        .collect()
+}
+
+/// The source line of `opcode`, with its targets named from `symbols`. Opcodes
+/// whose mnemonic would assemble to another encoding, such as `5XYN` with N
+/// other than 0, come out as `unk(#NNNN)`.
+pub fn source(opcode: u16, symbols: &SymbolMap) -> String {
+    let operation = opcode::parse_opcode(opcode);
+    let plain = operation.to_string();
+    match encode(&tokenize(&plain), &BTreeMap::new()) {
+        Ok(encoded) if encoded == opcode => operation.labeled(symbols).to_string(),
+        _ => format!("unk(#{:0>4x})", opcode),
+    }
+}
+
+/// Drops the `ADDRESS: [OPCODE]` prefix of a disassembly listing line
+fn strip_listing(line: &str) -> &str {
+    let Some((addr, rest)) = line.split_once(": [") else {
+        return line;
+    };
+    if !addr.chars().all(|c| c.is_ascii_hexdigit()) {
+        return line;
+    }
+    match rest.split_once(']') {
+        Some((_, rest)) => rest.trim(),
+        None => line,
+    }
+}
+
+fn is_label(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+}
+
+/// The argument of `name(arg)`, matching `name` case-insensitively
+fn call<'a>(token: &'a str, name: &str) -> Option<&'a str> {
+    let open = token.find('(')?;
+    let inner = token[open + 1..].strip_suffix(')')?;
+    token[..open].eq_ignore_ascii_case(name).then_some(inner)
+}
+
+fn register(token: &str) -> Option<u8> {
+    let digit = token.strip_prefix(['v', 'V'])?;
+    match u8::from_str_radix(digit, 16) {
+        Ok(x) if digit.len() == 1 => Some(x),
+        _ => None,
+    }
+}
+
+fn operand(token: &str) -> Operand<'_> {
+    if let Some(x) = register(token) {
+        return Operand::Reg(x);
+    }
+    if let Some(x) = call(token, "sprite").and_then(register) {
+        return Operand::Sprite(x);
+    }
+    match token.to_lowercase().as_str() {
+        "vi" | "i" => Operand::I,
+        "$key" => Operand::Key,
+        "$dtm" => Operand::DelayTimer,
+        "$stm" => Operand::SoundTimer,
+        _ => Operand::Value(token),
+    }
+}
+
+/// A label's address or a `#` or `0x` prefixed hex number no larger than `max`
+fn value(token: &str, labels: &BTreeMap<String, usize>, max: u16) -> anyhow::Result<u16> {
+    let number = || {
+        let digits = token
+            .strip_prefix('#')
+            .or_else(|| token.strip_prefix("0x"))?;
+        usize::from_str_radix(digits, 16).ok()
+    };
+    let v = labels
+        .get(token)
+        .copied()
+        .or_else(number)
+        .ok_or_else(|| anyhow::anyhow!("unknown label or number {}", token))?;
+    if v > max as usize {
+        anyhow::bail!("{} is {:#x}, larger than {:#x}", token, v, max);
+    }
+    Ok(v as u16)
+}
+
+fn encode(tokens: &[&str], labels: &BTreeMap<String, usize>) -> anyhow::Result<u16> {
+    use Operand::*;
+    let mnemonic = tokens[0].to_lowercase();
+    let args: Vec<Operand> = tokens[1..].iter().map(|t| operand(t)).collect();
+    let addr = |t: &str| value(t, labels, 0xFFF);
+    let byte = |t: &str| value(t, labels, 0xFF);
+    let xy = |base: u16, x: &u8, y: &u8| base | (*x as u16) << 8 | (*y as u16) << 4;
+    let xnn = |base: u16, x: &u8, t: &str| -> anyhow::Result<u16> {
+        Ok(base | (*x as u16) << 8 | byte(t)?)
+    };
+    let fx = |low: u16, x: &u8| 0xF000 | (*x as u16) << 8 | low;
+
+    if let Some(inner) = call(&mnemonic, "unk") {
+        if !args.is_empty() {
+            anyhow::bail!("unk takes no operands");
+        }
+        return value(inner, labels, 0xFFFF);
+    }
+    let opcode = match (mnemonic.as_str(), args.as_slice()) {
+        ("cls", []) => 0x00E0,
+        ("ret", []) => 0x00EE,
+        ("call_sys", [Value(a)]) => addr(a)?,
+        ("jmp", [Value(a)]) => match call(a, "vi") {
+            Some(inner) => 0xB000 | addr(inner)?,
+            None => 0x1000 | addr(a)?,
+        },
+        ("call", [Value(a)]) => 0x2000 | addr(a)?,
+        ("skp_eq", [Reg(x), Reg(y)]) => xy(0x5000, x, y),
+        ("skp_eq", [Reg(x), Key]) => 0xE09E | (*x as u16) << 8,
+        ("skp_eq", [Reg(x), Value(c)]) => xnn(0x3000, x, c)?,
+        ("skp_ne" | "skip_ne", [Reg(x), Reg(y)]) => xy(0x9000, x, y),
+        ("skp_ne" | "skip_ne", [Reg(x), Key]) => 0xE0A1 | (*x as u16) << 8,
+        ("skp_ne" | "skip_ne", [Reg(x), Value(c)]) => xnn(0x4000, x, c)?,
+        ("set", [Reg(x), Reg(y)]) => xy(0x8000, x, y),
+        ("set", [Reg(x), DelayTimer]) => fx(0x07, x),
+        ("set", [Reg(x), Key]) => fx(0x0A, x),
+        ("set", [Reg(x), Value(c)]) => xnn(0x6000, x, c)?,
+        ("set", [DelayTimer, Reg(x)]) => fx(0x15, x),
+        ("set", [SoundTimer, Reg(x)]) => fx(0x18, x),
+        ("set", [I, Sprite(x)]) => fx(0x29, x),
+        ("set", [I, Value(a)]) => 0xA000 | addr(a)?,
+        ("add", [Reg(x), Reg(y)]) => xy(0x8004, x, y),
+        ("add", [Reg(x), Value(c)]) => xnn(0x7000, x, c)?,
+        ("add", [I, Reg(x)]) => fx(0x1E, x),
+        ("or", [Reg(x), Reg(y)]) => xy(0x8001, x, y),
+        ("and", [Reg(x), Reg(y)]) => xy(0x8002, x, y),
+        ("xor", [Reg(x), Reg(y)]) => xy(0x8003, x, y),
+        ("sub", [Reg(x), Reg(y)]) => xy(0x8005, x, y),
+        ("rshf", [Reg(x), Reg(y)]) => xy(0x8006, x, y),
+        ("sub_neg", [Reg(x), Reg(y)]) => xy(0x8007, x, y),
+        ("lshf", [Reg(x), Reg(y)]) => xy(0x800E, x, y),
+        ("rand", [Reg(x), Value(c)]) => xnn(0xC000, x, c)?,
+        ("draw", [Reg(x), Reg(y), Value(n)]) => xy(0xD000, x, y) | value(n, labels, 0xF)?,
+        ("bcd", [Reg(x)]) => fx(0x33, x),
+        ("store", [Reg(x)]) => fx(0x55, x),
+        ("restore", [Reg(x)]) => fx(0x65, x),
+        _ => anyhow::bail!("invalid instruction {}", tokens.join(" ")),
+    };
+    Ok(opcode)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_opcode_survives_a_round_trip() {
+        let symbols = SymbolMap::default();
+        for opcode in 0..=0xFFFF {
+            let line = source(opcode, &symbols);
+            let program = assemble(&line, 0x200).unwrap();
+            assert_eq!(program.bytes, opcode.to_be_bytes(), "{}", line);
+        }
+        assert_eq!(source(0x5123, &symbols), "unk(#5123)");
+    }
+
+    #[test]
+    fn bare_words_are_labels_not_numbers() {
+        assert_eq!(assemble("jmp #dea", 0x200).unwrap().bytes, [0x1D, 0xEA]);
+        assert_eq!(assemble("jmp 0xdea", 0x200).unwrap().bytes, [0x1D, 0xEA]);
+        let err = assemble("jmp dead", 0x200).err().unwrap();
+        assert_eq!(err.to_string(), "line 1: unknown label or number dead");
+    }
+}
@@ -1,6 +1,15 @@
+use crate::asm;
+use crate::machine::MEMORY_SIZE;
 use crate::opcode;
+use crate::romfile;
+use crate::source_map::parse_address;
+use crate::symbols::SymbolMap;
+use clap::ValueEnum;
+use sha1::{Digest, Sha1};
 use std::fs;
+use std::ops::RangeInclusive;
 use std::path;
+use std::time::SystemTime;
 
 pub const CARTRIDGE_ADDRESS: usize = 0x200;
 
@@ -13,10 +22,79 @@ impl Assemable {
     fn new(opcode: u16, operation: opcode::Operation) -> Self {
         Assemable { opcode, operation }
     }
+
+    pub fn operation(&self) -> &opcode::Operation {
+        &self.operation
+    }
+}
+
+/// Reads a cartridge in any of the forms `romfile` detects
+pub fn load_cartridge(path: &path::Path) -> anyhow::Result<Vec<u8>> {
+    Ok(romfile::read(path)?.bytes)
+}
+
+/// Lowercase hex SHA-1 of the cartridge bytes, the key of the ROM database
+pub fn sha1_hex(cartridge: &[u8]) -> String {
+    format!("{:x}", Sha1::digest(cartridge))
 }
 
-pub fn load_cartridge(path: &path::PathBuf) -> anyhow::Result<Vec<u8>> {
-    Ok(fs::read(path)?)
+/// A cartridge file followed for changes by its modification time
+pub struct Watched {
+    path: path::PathBuf,
+    modified: Option<SystemTime>,
+    /// a newer time seen once, accepted when the next poll still sees it so a
+    /// half-written file is not loaded
+    pending: Option<SystemTime>,
+}
+
+impl Watched {
+    /// Follows the file of `path`, the archive for a ROM inside one
+    pub fn new(path: &path::Path) -> Self {
+        let (path, _) = romfile::split_member(path);
+        Watched {
+            modified: Self::modified(&path),
+            path,
+            pending: None,
+        }
+    }
+
+    fn modified(path: &path::Path) -> Option<SystemTime> {
+        fs::metadata(path).and_then(|m| m.modified()).ok()
+    }
+
+    pub fn path(&self) -> &path::Path {
+        &self.path
+    }
+
+    /// Whether the file changed since the last accepted change, call periodically
+    pub fn changed(&mut self) -> bool {
+        let current = Self::modified(&self.path);
+        if current.is_none() || current == self.modified {
+            self.pending = None;
+            return false;
+        }
+        if self.pending != current {
+            self.pending = current;
+            return false;
+        }
+        self.modified = current;
+        self.pending = None;
+        true
+    }
+}
+
+/// Parses an inclusive address range such as `0xE00-0xFFF` inside memory
+pub fn parse_range(s: &str) -> anyhow::Result<RangeInclusive<usize>> {
+    let (start, end) = s.split_once('-').unwrap_or((s, s));
+    match (parse_address(start.trim()), parse_address(end.trim())) {
+        (Some(start), Some(end)) if start <= end && end < MEMORY_SIZE => Ok(start..=end),
+        (Some(_), Some(end)) if end >= MEMORY_SIZE => anyhow::bail!(
+            "address range {} ends past memory, the last address is {:#05x}",
+            s,
+            MEMORY_SIZE - 1
+        ),
+        _ => anyhow::bail!("invalid address range {}, expected START-END", s),
+    }
 }
 
 pub fn disassemble_cartridge(cartridge: &[u8]) -> Vec<Assemable> {
@@ -33,10 +111,70 @@ pub fn disassemble_cartridge(cartridge: &[u8]) -> Vec<Assemable> {
         .collect()
 }
 
-pub fn debug_cartridge(cartridge: &[u8]) {
-    let mut addr = CARTRIDGE_ADDRESS;
-    for d in disassemble_cartridge(cartridge) {
-        println!("{:0>12x}: [{:0>4x}] {}", addr, d.opcode, d.operation);
+/// How `disasm` prints a cartridge
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum DisasmFormat {
+    /// address, opcode and mnemonic per line
+    #[default]
+    Listing,
+    /// source the `asm` command reads back, with labels for every jump and call target
+    Asm,
+}
+
+/// Prints the instructions of `cartridge`, loaded at `start`, whose address is in
+/// `range`
+pub fn debug_cartridge(
+    cartridge: &[u8],
+    start: usize,
+    symbols: &SymbolMap,
+    range: RangeInclusive<usize>,
+    format: DisasmFormat,
+) {
+    let disassembly = disassemble_cartridge(cartridge);
+    let symbols = match format {
+        DisasmFormat::Listing => symbols,
+        DisasmFormat::Asm => &with_target_labels(&disassembly, start, symbols),
+    };
+    let mut addr = start;
+    for d in disassembly {
+        if range.contains(&addr) {
+            if let Some(label) = symbols.label(addr) {
+                println!("{}:", label);
+            }
+            match format {
+                DisasmFormat::Listing => println!(
+                    "{:0>12x}: [{:0>4x}] {}",
+                    addr,
+                    d.opcode,
+                    d.operation.labeled(symbols)
+                ),
+                // a trailing odd byte came back padded with zero
+                DisasmFormat::Asm if addr + 1 == start + cartridge.len() => {
+                    println!("    db #{:x}", d.opcode >> 8)
+                }
+                DisasmFormat::Asm => println!("    {}", asm::source(d.opcode, symbols)),
+            }
+        }
         addr += 2;
     }
 }
+
+/// `symbols` plus an `L<addr>` label for each jump, call or `I` target inside the
+/// cartridge loaded at `start` that has none
+fn with_target_labels(disassembly: &[Assemable], start: usize, symbols: &SymbolMap) -> SymbolMap {
+    use opcode::Operation::*;
+    let end = start + disassembly.len() * 2;
+    let mut labeled = symbols.clone();
+    for d in disassembly {
+        let target = match d.operation {
+            JumpC(a) | CallC(a) | SetIC(a) | JumpV0C(a) if a as usize >= start => a as usize,
+            _ => continue,
+        };
+        // only instruction-aligned addresses get printed, so only those can be labelled
+        let aligned = (target - start).is_multiple_of(2);
+        if (start..end).contains(&target) && aligned && labeled.label(target).is_none() {
+            labeled.insert(&format!("L{:03x}", target), target);
+        }
+    }
+    labeled
+}
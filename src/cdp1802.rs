@@ -0,0 +1,388 @@
+//! The RCA CDP1802, the COSMAC VIP's CPU, for the machine code routines
+//! programs call with `0NNN`.
+//!
+//! The CPU has sixteen 16-bit registers, any of which can be the program
+//! counter (picked by P) or the data pointer (picked by X), an 8-bit
+//! accumulator D and a carry DF. Interrupts and DMA aren't emulated, as the
+//! VIP only used them to refresh the display and count down the timers.
+
+/// The hardware around the CPU
+pub trait Io {
+    /// `OUT n` (`6n`, 1-7) puts a byte on the bus
+    fn output(&mut self, port: u8, value: u8);
+    /// `INP n` (`6n`, 9-F as 1-7) reads a byte from the bus
+    fn input(&mut self, port: u8) -> u8;
+    /// The external flag lines EF1-EF4 the `B1`-`BN4` branches test
+    fn flag(&mut self, n: u8) -> bool;
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct Cdp1802 {
+    pub r: [u16; 16],
+    pub d: u8,
+    pub df: bool,
+    pub p: u8,
+    pub x: u8,
+    /// X and P saved by `MARK` and interrupts
+    pub t: u8,
+    pub ie: bool,
+    pub q: bool,
+}
+
+impl Cdp1802 {
+    /// Runs from R(P) until P becomes `until`, as after `SEP` to the register
+    /// the caller returns through, and returns the instructions executed
+    pub fn run(
+        &mut self,
+        memory: &mut [u8],
+        io: &mut impl Io,
+        until: u8,
+        limit: u64,
+    ) -> anyhow::Result<u64> {
+        let mut executed = 0;
+        while self.p != until {
+            if executed == limit {
+                anyhow::bail!(
+                    "machine code still running at {:#06x} after {} instructions",
+                    self.r[self.p as usize],
+                    limit
+                );
+            }
+            self.step(memory, io)?;
+            executed += 1;
+        }
+        Ok(executed)
+    }
+
+    fn fetch(&mut self, memory: &[u8]) -> anyhow::Result<u8> {
+        let pc = self.r[self.p as usize];
+        self.r[self.p as usize] = pc.wrapping_add(1);
+        read(memory, pc)
+    }
+
+    fn rx(&self) -> u16 {
+        self.r[self.x as usize]
+    }
+
+    fn add(&mut self, a: u8, b: u8, carry: bool) {
+        let sum = a as u16 + b as u16 + carry as u16;
+        self.d = sum as u8;
+        self.df = sum > 0xFF;
+    }
+
+    /// `a - b`, DF is set when there was no borrow
+    fn sub(&mut self, a: u8, b: u8, borrow: bool) {
+        let diff = a as i16 - b as i16 - borrow as i16;
+        self.d = diff as u8;
+        self.df = diff >= 0;
+    }
+
+    fn short_branch(&mut self, memory: &[u8], taken: bool) -> anyhow::Result<()> {
+        let target = self.fetch(memory)?;
+        if taken {
+            let pc = &mut self.r[self.p as usize];
+            *pc = (*pc & 0xFF00) | target as u16;
+        }
+        Ok(())
+    }
+
+    fn long_branch(&mut self, memory: &[u8], taken: bool) -> anyhow::Result<()> {
+        let high = self.fetch(memory)?;
+        let low = self.fetch(memory)?;
+        if taken {
+            self.r[self.p as usize] = u16::from_be_bytes([high, low]);
+        }
+        Ok(())
+    }
+
+    fn long_skip(&mut self, taken: bool) {
+        if taken {
+            let pc = &mut self.r[self.p as usize];
+            *pc = pc.wrapping_add(2);
+        }
+    }
+
+    pub fn step(&mut self, memory: &mut [u8], io: &mut impl Io) -> anyhow::Result<()> {
+        let opcode = self.fetch(memory)?;
+        let n = opcode & 0xF;
+        let rn = n as usize;
+        match opcode >> 4 {
+            0x0 if n == 0 => {} // IDL waits for DMA or an interrupt
+            0x0 => self.d = read(memory, self.r[rn])?,
+            0x1 => self.r[rn] = self.r[rn].wrapping_add(1),
+            0x2 => self.r[rn] = self.r[rn].wrapping_sub(1),
+            0x3 => {
+                let flag = match n & 7 {
+                    0 => true,
+                    1 => self.q,
+                    2 => self.d == 0,
+                    3 => self.df,
+                    ef => io.flag(ef - 3),
+                };
+                // 30-37 branch when the condition holds, 38-3F when it doesn't,
+                // 38 being SKP
+                self.short_branch(memory, flag != (n >= 8))?;
+            }
+            0x4 => {
+                self.d = read(memory, self.r[rn])?;
+                self.r[rn] = self.r[rn].wrapping_add(1);
+            }
+            0x5 => write(memory, self.r[rn], self.d)?,
+            0x6 => match n {
+                0 => self.r[self.x as usize] = self.rx().wrapping_add(1),
+                1..=7 => {
+                    io.output(n, read(memory, self.rx())?);
+                    self.r[self.x as usize] = self.rx().wrapping_add(1);
+                }
+                8 => anyhow::bail!("68 isn't a CDP1802 instruction"),
+                _ => {
+                    self.d = io.input(n - 8);
+                    write(memory, self.rx(), self.d)?;
+                }
+            },
+            0x7 => match n {
+                // RET and DIS
+                0 | 1 => {
+                    let xp = read(memory, self.rx())?;
+                    self.r[self.x as usize] = self.rx().wrapping_add(1);
+                    (self.x, self.p) = (xp >> 4, xp & 0xF);
+                    self.ie = n == 0;
+                }
+                // LDXA
+                2 => {
+                    self.d = read(memory, self.rx())?;
+                    self.r[self.x as usize] = self.rx().wrapping_add(1);
+                }
+                // STXD
+                3 => {
+                    write(memory, self.rx(), self.d)?;
+                    self.r[self.x as usize] = self.rx().wrapping_sub(1);
+                }
+                // ADC, SDB, SMB and their immediate forms
+                4 | 5 | 7 | 0xC | 0xD | 0xF => {
+                    let m = if n < 8 {
+                        read(memory, self.rx())?
+                    } else {
+                        self.fetch(memory)?
+                    };
+                    match n & 7 {
+                        4 => self.add(m, self.d, self.df),
+                        5 => self.sub(m, self.d, !self.df),
+                        _ => self.sub(self.d, m, !self.df),
+                    }
+                }
+                // SHRC
+                6 => {
+                    let carry = self.d & 1 != 0;
+                    self.d = self.d >> 1 | (self.df as u8) << 7;
+                    self.df = carry;
+                }
+                // SAV
+                8 => write(memory, self.rx(), self.t)?,
+                // MARK
+                9 => {
+                    self.t = self.x << 4 | self.p;
+                    write(memory, self.r[2], self.t)?;
+                    self.x = self.p;
+                    self.r[2] = self.r[2].wrapping_sub(1);
+                }
+                0xA => self.q = false,
+                0xB => self.q = true,
+                // SHLC
+                _ => {
+                    let carry = self.d & 0x80 != 0;
+                    self.d = self.d << 1 | self.df as u8;
+                    self.df = carry;
+                }
+            },
+            0x8 => self.d = self.r[rn] as u8,
+            0x9 => self.d = (self.r[rn] >> 8) as u8,
+            0xA => self.r[rn] = (self.r[rn] & 0xFF00) | self.d as u16,
+            0xB => self.r[rn] = (self.r[rn] & 0x00FF) | (self.d as u16) << 8,
+            0xC => {
+                let flag = match n & 3 {
+                    0 => true,
+                    1 => self.q,
+                    2 => self.d == 0,
+                    _ => self.df,
+                };
+                match n {
+                    // LBR, LBQ, LBZ, LBDF
+                    0..=3 => self.long_branch(memory, flag)?,
+                    // NOP
+                    4 => {}
+                    // LSNQ, LSNZ, LSNF
+                    5..=7 => self.long_skip(!flag),
+                    // LSKP, LBNQ, LBNZ, LBNF
+                    8 => self.long_skip(true),
+                    9..=0xB => self.long_branch(memory, !flag)?,
+                    // LSIE
+                    0xC => self.long_skip(self.ie),
+                    // LSQ, LSZ, LSDF
+                    _ => self.long_skip(flag),
+                }
+            }
+            0xD => self.p = n,
+            0xE => self.x = n,
+            _ => {
+                let m = match n {
+                    6 | 0xE => 0,
+                    _ if n < 8 => read(memory, self.rx())?,
+                    _ => self.fetch(memory)?,
+                };
+                match n & 7 {
+                    0 => self.d = m,
+                    1 => self.d |= m,
+                    2 => self.d &= m,
+                    3 => self.d ^= m,
+                    4 => self.add(m, self.d, false),
+                    5 => self.sub(m, self.d, false),
+                    6 if n == 6 => {
+                        self.df = self.d & 1 != 0;
+                        self.d >>= 1;
+                    }
+                    6 => {
+                        self.df = self.d & 0x80 != 0;
+                        self.d <<= 1;
+                    }
+                    _ => self.sub(self.d, m, false),
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Addresses below 0x8000 wrap around the RAM, as on a VIP with less than 32 KB.
+/// The monitor ROM above them isn't available.
+fn address(memory: &[u8], addr: u16) -> anyhow::Result<usize> {
+    if addr >= 0x8000 {
+        anyhow::bail!(
+            "machine code reached {:#06x} in the VIP's monitor ROM, which isn't emulated",
+            addr
+        );
+    }
+    Ok(addr as usize % memory.len())
+}
+
+fn read(memory: &[u8], addr: u16) -> anyhow::Result<u8> {
+    Ok(memory[address(memory, addr)?])
+}
+
+fn write(memory: &mut [u8], addr: u16, value: u8) -> anyhow::Result<()> {
+    memory[address(memory, addr)?] = value;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct NoIo;
+
+    impl Io for NoIo {
+        fn output(&mut self, _port: u8, _value: u8) {}
+
+        fn input(&mut self, _port: u8) -> u8 {
+            0
+        }
+
+        fn flag(&mut self, _n: u8) -> bool {
+            false
+        }
+    }
+
+    /// A CPU about to run `program` from 0x000 with R0 as the program counter
+    fn load(program: &[u8]) -> (Cdp1802, Vec<u8>) {
+        let mut memory = vec![0; 0x200];
+        memory[..program.len()].copy_from_slice(program);
+        (Cdp1802::default(), memory)
+    }
+
+    fn steps(cpu: &mut Cdp1802, memory: &mut [u8], n: usize) {
+        for _ in 0..n {
+            cpu.step(memory, &mut NoIo).unwrap();
+        }
+    }
+
+    #[test]
+    fn skips_jump_over_the_next_bytes() {
+        // SKP over one byte, then LSKP over two
+        let (mut cpu, mut memory) = load(&[0x38, 0xFF, 0xC8, 0xFF, 0xFF]);
+        steps(&mut cpu, &mut memory, 2);
+        assert_eq!(cpu.r[0], 0x005);
+
+        // LSZ skips while D is 0, LSNZ doesn't
+        let (mut cpu, mut memory) = load(&[0xCE, 0xFF, 0xFF, 0xC6]);
+        steps(&mut cpu, &mut memory, 2);
+        assert_eq!(cpu.r[0], 0x004);
+    }
+
+    #[test]
+    fn branches_go_to_their_target() {
+        // LBR to 0x123, where LBZ is taken and LBNZ isn't
+        let (mut cpu, mut memory) = load(&[0xC0, 0x01, 0x23]);
+        memory[0x123..0x126].copy_from_slice(&[0xC2, 0x01, 0x80]);
+        memory[0x180..0x183].copy_from_slice(&[0xCA, 0x00, 0x00]);
+        steps(&mut cpu, &mut memory, 3);
+        assert_eq!(cpu.r[0], 0x183);
+
+        // a short branch whose target byte ends a page lands in the next page
+        let (mut cpu, mut memory) = load(&[]);
+        cpu.r[0] = 0x1FE;
+        memory[0x1FE] = 0x30;
+        memory[0x1FF] = 0x40;
+        steps(&mut cpu, &mut memory, 1);
+        assert_eq!(cpu.r[0], 0x240);
+    }
+
+    #[test]
+    fn subtractions_borrow_through_df() {
+        // LDI 3, SMI 5 borrows, SMBI 0 borrows again
+        let (mut cpu, mut memory) = load(&[0xF8, 0x03, 0xFF, 0x05, 0x7F, 0x00]);
+        steps(&mut cpu, &mut memory, 2);
+        assert_eq!((cpu.d, cpu.df), (0xFE, false));
+        steps(&mut cpu, &mut memory, 1);
+        assert_eq!((cpu.d, cpu.df), (0xFD, true));
+
+        // SD and SDB subtract D from M(R(X)), here 0x10 at 0x100
+        let (mut cpu, mut memory) = load(&[0xF8, 0x11, 0xF5, 0x75]);
+        memory[0x100] = 0x10;
+        cpu.r[1] = 0x100;
+        cpu.x = 1;
+        steps(&mut cpu, &mut memory, 2);
+        assert_eq!((cpu.d, cpu.df), (0xFF, false));
+        steps(&mut cpu, &mut memory, 1);
+        assert_eq!((cpu.d, cpu.df), (0x10, false));
+
+        // SM and SMB subtract M(R(X)) from D
+        let (mut cpu, mut memory) = load(&[0xF8, 0x10, 0xF7, 0x77]);
+        memory[0x100] = 0x10;
+        cpu.r[1] = 0x100;
+        cpu.x = 1;
+        steps(&mut cpu, &mut memory, 2);
+        assert_eq!((cpu.d, cpu.df), (0x00, true));
+        steps(&mut cpu, &mut memory, 1);
+        assert_eq!((cpu.d, cpu.df), (0xF0, false));
+    }
+
+    #[test]
+    fn ret_returns_to_where_mark_saved() {
+        // with X=1, P=0: MARK, then SEP R3 to a routine that returns with RET
+        let (mut cpu, mut memory) = load(&[0x79, 0xD3, 0xFF]);
+        memory[0x150] = 0x70;
+        cpu.x = 1;
+        cpu.r[2] = 0x1F0;
+        cpu.r[3] = 0x150;
+        steps(&mut cpu, &mut memory, 2);
+        assert_eq!((cpu.t, memory[0x1F0], cpu.r[2]), (0x10, 0x10, 0x1EF));
+        assert_eq!((cpu.x, cpu.p), (0, 3));
+
+        // RET reads through R(X), which MARK made R0, so point it at the save
+        cpu.x = 2;
+        cpu.r[2] = 0x1F0;
+        steps(&mut cpu, &mut memory, 1);
+        assert_eq!((cpu.x, cpu.p, cpu.r[2], cpu.ie), (1, 0, 0x1F1, true));
+        assert_eq!(cpu.r[0], 0x002);
+    }
+}
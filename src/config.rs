@@ -0,0 +1,256 @@
+//! Settings read from TOML files, so a ROM runs right without flags.
+//!
+//! The global file is `bchip8/config.toml` in the user's config directory, and
+//! a sidecar next to a ROM (`game.toml` for `game.ch8`) overrides it for that
+//! ROM. Settings from the ROM database sit between the two, and flags given on
+//! the command line win over all of them:
+//!
+//! ```toml
+//! cycle_micro = 500
+//! platform = "schip"
+//! render = "braille"
+//! theme = "amber"
+//! palette = ["#000000", "#ffcc00"]
+//! keymap = "keys.toml"
+//! font = "dream6800"
+//!
+//! [quirks]
+//! shift_vy = true
+//! ```
+//! Relative paths are resolved against the directory of the file. There are no
+//! audio settings, as nothing plays the sound timer's tone yet.
+
+use crate::filter::FilterMode;
+use crate::font::{Font, FontSet};
+use crate::graphics::GraphicsMode;
+use crate::keymap::Layout;
+use crate::memory_map::MemoryMap;
+use crate::quirks::{Platform, Quirks};
+use crate::render::RenderMode;
+use crate::romfile;
+use crate::theme::{self, Rgb, ThemeName};
+use crate::vip::{self, Vip};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    pub cycle_micro: Option<u64>,
+    pub platform: Option<Platform>,
+    #[serde(default)]
+    pub quirks: BTreeMap<String, bool>,
+    pub font: Option<FontSet>,
+    /// a font of sixteen digits, used instead of `font`, and replaced by a
+    /// `font` set in a later layer
+    pub font_file: Option<PathBuf>,
+    pub machine_code: Option<bool>,
+    /// an image of the COSMAC VIP's interpreter, which implies `machine_code`
+    /// unless that is turned off
+    pub vip_interpreter: Option<PathBuf>,
+    pub layout: Option<Layout>,
+    pub keymap: Option<PathBuf>,
+    pub render: Option<RenderMode>,
+    pub graphics: Option<GraphicsMode>,
+    pub filter: Option<FilterMode>,
+    pub theme: Option<ThemeName>,
+    #[serde(default, deserialize_with = "color")]
+    pub fg: Option<Rgb>,
+    #[serde(default, deserialize_with = "color")]
+    pub bg: Option<Rgb>,
+    #[serde(default, deserialize_with = "colors")]
+    pub palette: Vec<Rgb>,
+    pub hud: Option<bool>,
+}
+
+fn color<'de, D: serde::Deserializer<'de>>(d: D) -> Result<Option<Rgb>, D::Error> {
+    let s = String::deserialize(d)?;
+    theme::parse_color(&s)
+        .map(Some)
+        .map_err(serde::de::Error::custom)
+}
+
+fn colors<'de, D: serde::Deserializer<'de>>(d: D) -> Result<Vec<Rgb>, D::Error> {
+    let colors = Vec::<String>::deserialize(d)?;
+    colors
+        .iter()
+        .map(|s| theme::parse_color(s).map_err(serde::de::Error::custom))
+        .collect()
+}
+
+/// The cycle time that runs `instructions_per_frame` in a 60 Hz frame
+pub fn cycle_micro(instructions_per_frame: u64) -> Option<u64> {
+    (instructions_per_frame > 0).then(|| 1_000_000 / (instructions_per_frame * 60))
+}
+
+/// Where the global config file lives
+pub fn global_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("bchip8").join("config.toml"))
+}
+
+/// The sidecar file of a ROM, `game.toml` for `game.ch8`, and for a ROM inside
+/// an archive `game.toml` next to the archive
+pub fn sidecar_path(rom: &Path) -> PathBuf {
+    match romfile::split_member(rom) {
+        (archive, Some(member)) => archive
+            .with_file_name(Path::new(&member).file_name().unwrap_or_default())
+            .with_extension("toml"),
+        (rom, None) => rom.with_extension("toml"),
+    }
+}
+
+impl Config {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let text = fs::read_to_string(path)?;
+        let mut config: Config =
+            toml::from_str(&text).map_err(|e| anyhow::anyhow!("{}: {}", path.display(), e))?;
+        for name in config.quirks.keys() {
+            Quirks::default()
+                .set(name, true)
+                .map_err(|e| anyhow::anyhow!("{}: {}", path.display(), e))?;
+        }
+        let base = path.parent().unwrap_or(Path::new("."));
+        config.keymap = config.keymap.map(|keymap| base.join(keymap));
+        config.font_file = config.font_file.map(|font| base.join(font));
+        config.vip_interpreter = config.vip_interpreter.map(|image| base.join(image));
+        Ok(config)
+    }
+
+    /// Loads `path` if it exists, an empty config otherwise
+    pub fn load_optional(path: &Path) -> anyhow::Result<Self> {
+        if path.is_file() {
+            Self::load(path)
+        } else {
+            Ok(Config::default())
+        }
+    }
+
+    /// The global config, or `path` in its place, then `known` (what the ROM
+    /// database recommends) and the sidecar of `rom`, each overriding the last
+    pub fn for_rom(path: Option<&Path>, rom: &Path, known: Config) -> anyhow::Result<Self> {
+        let global = match path {
+            Some(path) => Config::load(path)?,
+            None => match global_path() {
+                Some(path) => Config::load_optional(&path)?,
+                None => Config::default(),
+            },
+        };
+        Ok(global
+            .merge(known)
+            .merge(Config::load_optional(&sidecar_path(rom))?))
+    }
+
+    /// `self` with every setting `other` has replaced
+    pub fn merge(self, other: Config) -> Config {
+        let mut quirks = self.quirks;
+        quirks.extend(other.quirks);
+        // a font set and a font file are one setting, whichever a layer names
+        let (font, font_file) = if other.font.is_some() || other.font_file.is_some() {
+            (other.font, other.font_file)
+        } else {
+            (self.font, self.font_file)
+        };
+        Config {
+            cycle_micro: other.cycle_micro.or(self.cycle_micro),
+            platform: other.platform.or(self.platform),
+            quirks,
+            font,
+            font_file,
+            machine_code: other.machine_code.or(self.machine_code),
+            vip_interpreter: other.vip_interpreter.or(self.vip_interpreter),
+            layout: other.layout.or(self.layout),
+            keymap: other.keymap.or(self.keymap),
+            render: other.render.or(self.render),
+            graphics: other.graphics.or(self.graphics),
+            filter: other.filter.or(self.filter),
+            theme: other.theme.or(self.theme),
+            fg: other.fg.or(self.fg),
+            bg: other.bg.or(self.bg),
+            palette: if other.palette.is_empty() {
+                self.palette
+            } else {
+                other.palette
+            },
+            hud: other.hud.or(self.hud),
+        }
+    }
+
+    /// Where the platform loads programs and what it keeps for itself
+    pub fn memory_map(&self) -> anyhow::Result<MemoryMap> {
+        Ok(MemoryMap::new(self.platform.unwrap_or_default()).with_font(&self.font()?))
+    }
+
+    /// The font file, else the font set, else the one of the platform
+    pub fn font(&self) -> anyhow::Result<Font> {
+        match &self.font_file {
+            Some(path) => Font::load(path),
+            None => Ok(Font::builtin(self.font.unwrap_or_else(|| {
+                FontSet::for_platform(self.platform.unwrap_or_default())
+            }))),
+        }
+    }
+
+    /// What runs `0NNN` machine code calls, none when they are skipped. Turning
+    /// `machine_code` off wins over an interpreter image named below it.
+    pub fn vip(&self) -> anyhow::Result<Option<Vip>> {
+        let image = match &self.vip_interpreter {
+            _ if self.machine_code == Some(false) => return Ok(None),
+            Some(path) => {
+                fs::read(path).map_err(|e| anyhow::anyhow!("{}: {}", path.display(), e))?
+            }
+            None if self.machine_code == Some(true) => vip::stand_in(),
+            None => return Ok(None),
+        };
+        Vip::new(image).map(Some)
+    }
+
+    /// The platform's quirks with the individual overrides applied
+    pub fn quirks(&self) -> anyhow::Result<Quirks> {
+        let mut quirks = self.platform.unwrap_or_default().quirks();
+        for (name, value) in &self.quirks {
+            quirks.set(name, *value)?;
+        }
+        Ok(quirks)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_later_font_replaces_an_earlier_font_file() {
+        let file = Config {
+            font_file: Some(PathBuf::from("digits.bin")),
+            ..Default::default()
+        };
+        let set = Config {
+            font: Some(FontSet::Dream6800),
+            ..Default::default()
+        };
+        let merged = file.clone().merge(set.clone());
+        assert_eq!(merged.font, Some(FontSet::Dream6800));
+        assert_eq!(merged.font_file, None);
+        assert_eq!(merged.font().unwrap(), Font::builtin(FontSet::Dream6800));
+
+        let merged = set.merge(file).merge(Config::default());
+        assert_eq!(merged.font, None);
+        assert_eq!(merged.font_file, Some(PathBuf::from("digits.bin")));
+    }
+
+    #[test]
+    fn machine_code_off_wins_over_an_interpreter_image() {
+        let image = Config {
+            vip_interpreter: Some(PathBuf::from("no-such-interpreter.bin")),
+            ..Default::default()
+        };
+        let off = Config {
+            machine_code: Some(false),
+            ..Default::default()
+        };
+        assert!(image.clone().vip().is_err());
+        assert!(image.merge(off).vip().unwrap().is_none());
+    }
+}
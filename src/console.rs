@@ -1,30 +1,51 @@
+use crate::filter::Intensity;
+use crate::graphics::{self, Bitmap, Protocol};
+use crate::heatmap::{self, Heatmap};
+use crate::hud::{self, Hud, HudView};
+use crate::keymap::Keymap;
+use crate::render::RenderMode;
+use crate::theme::{self, Theme};
+use clap::ValueEnum;
 use crossterm::{
+    cursor::MoveTo,
     event::{
         self, Event, KeyCode, KeyEventKind, KeyboardEnhancementFlags, PopKeyboardEnhancementFlags,
         PushKeyboardEnhancementFlags,
     },
-    execute,
+    execute, queue, terminal,
 };
-use ratatui::style;
+use ratatui::style::{self, Stylize};
 use ratatui::{
     DefaultTerminal,
     buffer::Buffer,
-    layout::{Position, Rect},
+    layout::{Constraint, Layout, Position, Rect},
     widgets::{Block, Widget},
 };
-use std::io;
+use std::io::{self, Write};
 use std::time::{Duration, Instant};
 
 pub const WIDTH: usize = 64;
 pub const HEIGHT: usize = 32;
 
-#[derive(Debug)]
+/// Shades of a faded pixel in bitmap output
+const BITMAP_LEVELS: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Key {
     Quit,
+    Pause,
+    Reset,
+    HardReset,
+    Hud,
+    Heatmap,
+    Screenshot,
+    Record,
+    SaveState,
+    LoadState,
     Num(u8),
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Eq)]
 pub enum KeyEvent {
     Pressed(Key),
     Released(Key),
@@ -32,31 +53,40 @@ pub enum KeyEvent {
 
 #[derive(Debug)]
 pub struct Screen<'a> {
-    display_buffer: &'a [[bool; 64]; 32],
+    intensity: &'a Intensity,
+    render_mode: RenderMode,
+    theme: Theme,
 }
 
 impl<'a> Widget for Screen<'a> {
     fn render(self, area: Rect, buf: &mut Buffer) {
-        let block = Block::default();
+        let block = Block::default().bg(self.theme.color(theme::BACKGROUND));
         block.render(area, buf);
 
-        // Calculate scaling factors if needed
-        let pixel_width = area.width / 64;
-        let pixel_height = area.height / 32;
-
-        for y in 0..32 {
-            for x in 0..64 {
-                if self.display_buffer[y][x] {
-                    for py in 0..pixel_height {
-                        for px in 0..pixel_width {
-                            let rx = area.x + (x as u16) * pixel_width + px;
-                            let ry = area.y + (y as u16) * pixel_height + py;
-                            buf.cell_mut(Position::new(rx, ry))
-                                .unwrap()
-                                .set_fg(style::Color::LightGreen)
-                                .set_symbol("█");
-                        }
-                    }
+        let layout = self
+            .render_mode
+            .layout(WIDTH, HEIGHT, area.width, area.height);
+        let width = layout.width.min(area.width);
+        let height = layout.height.min(area.height);
+        let left = area.x + (area.width - width) / 2;
+        let top = area.y + (area.height - height) / 2;
+        let value = |x: usize, y: usize| {
+            if x < WIDTH && y < HEIGHT {
+                self.intensity[y][x]
+            } else {
+                0
+            }
+        };
+        let pixel = |x: usize, y: usize| value(x, y) > 0;
+
+        for row in 0..height {
+            for col in 0..width {
+                if let Some(glyph) = layout.glyph(col, row, pixel) {
+                    let intensity = layout.cell_max(col, row, value);
+                    buf.cell_mut(Position::new(left + col, top + row))
+                        .unwrap()
+                        .set_fg(self.theme.intensity_color(intensity))
+                        .set_char(glyph);
                 }
             }
         }
@@ -64,13 +94,160 @@ impl<'a> Widget for Screen<'a> {
 }
 
 impl<'a> Screen<'a> {
-    fn new(display_buffer: &'a [[bool; WIDTH]; HEIGHT]) -> Self {
-        Screen { display_buffer }
+    fn new(intensity: &'a Intensity, render_mode: RenderMode, theme: Theme) -> Self {
+        Screen {
+            intensity,
+            render_mode,
+            theme,
+        }
     }
 }
 
+/// Memory access map, one half-block per byte, 64 bytes per row
+pub struct HeatmapView<'a> {
+    heatmap: &'a Heatmap,
+}
+
+impl<'a> Widget for HeatmapView<'a> {
+    fn render(self, area: Rect, buf: &mut Buffer) {
+        let block = Block::default();
+        block.render(area, buf);
+
+        let max = (0..crate::machine::MEMORY_SIZE)
+            .map(|addr| self.heatmap.get(addr).total())
+            .max()
+            .unwrap_or(0);
+        let color = |addr: usize| {
+            let access = self.heatmap.get(addr);
+            let shade = Heatmap::shade(access.total(), max);
+            let [r, g, b] = access
+                .region()
+                .rgb()
+                .map(|c| (c as u32 * shade / 255) as u8);
+            style::Color::Rgb(r, g, b)
+        };
+
+        let rows = (heatmap::IMAGE_HEIGHT / 2) as u16;
+        for y in 0..rows.min(area.height) {
+            for x in 0..(heatmap::IMAGE_WIDTH as u16).min(area.width) {
+                let upper = (y as usize * 2) * heatmap::IMAGE_WIDTH + x as usize;
+                let lower = upper + heatmap::IMAGE_WIDTH;
+                buf.cell_mut(Position::new(area.x + x, area.y + y))
+                    .unwrap()
+                    .set_fg(color(upper))
+                    .set_bg(color(lower))
+                    .set_symbol("▀");
+            }
+        }
+    }
+}
+
+impl<'a> HeatmapView<'a> {
+    fn new(heatmap: &'a Heatmap) -> Self {
+        HeatmapView { heatmap }
+    }
+}
+
+/// Where a bitmap goes on screen, in cells, and its integer pixel scale
+struct Placement {
+    left: u16,
+    top: u16,
+    cols: u16,
+    rows: u16,
+    scale: usize,
+}
+
 pub struct Console {
     terminal: DefaultTerminal,
+    /// the terminal reports key releases
+    release_events: bool,
+    holds: KeyHolds,
+    render_mode: RenderMode,
+    theme: Theme,
+    keymap: Keymap,
+    graphics: Option<Protocol>,
+    /// a bitmap may be covering the text cells
+    image_shown: bool,
+}
+
+/// How the end of a key press is detected
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum KeyRelease {
+    /// Use release events when the terminal supports keyboard enhancement
+    #[default]
+    Auto,
+    /// Trust the terminal to send release events
+    Events,
+    /// Release keys once they are no longer held or auto-repeated
+    Timeout,
+}
+
+/// Once auto-repeat has started a key counts as released after this long without a repeat
+const REPEAT_GAP: Duration = Duration::from_millis(100);
+
+/// A keypad key pressed without a release event yet
+#[derive(Debug, Clone, Copy)]
+struct HeldKey {
+    last: Instant,
+    repeating: bool,
+}
+
+/// The keypad keys being held, for releasing them when the terminal doesn't
+/// say so itself
+#[derive(Debug)]
+struct KeyHolds {
+    synthesize_release: bool,
+    /// how long a press without auto-repeat is held
+    hold: Duration,
+    held: [Option<HeldKey>; 16],
+}
+
+impl KeyHolds {
+    fn new(synthesize_release: bool, hold: Duration) -> Self {
+        KeyHolds {
+            synthesize_release,
+            hold,
+            held: [None; 16],
+        }
+    }
+
+    /// A press or auto-repeat of `k` at `now`
+    fn press(&mut self, k: Key, now: Instant) {
+        if let (Key::Num(n), true) = (k, self.synthesize_release) {
+            let held = &mut self.held[n as usize];
+            *held = Some(HeldKey {
+                last: now,
+                repeating: held.is_some(),
+            });
+        }
+    }
+
+    /// A release event from the terminal
+    fn release(&mut self, k: Key) {
+        if let Key::Num(n) = k {
+            self.held[n as usize] = None;
+        }
+    }
+
+    /// Releases keys that stopped auto-repeating or were only tapped by `now`
+    fn release_expired(&mut self, now: Instant, keys: &mut Vec<KeyEvent>) {
+        if !self.synthesize_release {
+            return;
+        }
+        for (n, held) in self.held.iter_mut().enumerate() {
+            if let Some(h) = held {
+                let limit = if h.repeating {
+                    REPEAT_GAP.min(self.hold)
+                } else {
+                    self.hold
+                };
+                if now.saturating_duration_since(h.last) >= limit {
+                    *held = None;
+                    keys.push(KeyEvent::Released(Key::Num(n as u8)));
+                }
+            }
+        }
+    }
 }
 
 pub fn init() -> anyhow::Result<Console> {
@@ -84,31 +261,205 @@ pub fn init() -> anyhow::Result<Console> {
         io::stdout(),
         PushKeyboardEnhancementFlags(KeyboardEnhancementFlags::REPORT_EVENT_TYPES)
     )?;
+    let release_events = terminal::supports_keyboard_enhancement().unwrap_or(false);
+    log::info!("(Console) keyboard enhancement {}", release_events);
 
     unsafe { IS_INIT = true };
 
-    Ok(Console::new(terminal))
+    Ok(Console::new(terminal, release_events))
 }
 
 impl Console {
-    fn new(terminal: DefaultTerminal) -> Self {
-        Console { terminal }
+    fn new(terminal: DefaultTerminal, release_events: bool) -> Self {
+        Console {
+            terminal,
+            release_events,
+            holds: KeyHolds::new(!release_events, Duration::from_millis(700)),
+            render_mode: RenderMode::default(),
+            theme: Theme::default(),
+            keymap: Keymap::default(),
+            graphics: None,
+            image_shown: false,
+        }
+    }
+
+    pub fn set_render_mode(&mut self, render_mode: RenderMode) {
+        self.render_mode = render_mode;
+    }
+
+    pub fn set_theme(&mut self, theme: Theme) {
+        self.theme = theme;
+    }
+
+    pub fn set_key_release(&mut self, mode: KeyRelease, hold: Duration) {
+        let synthesize_release = match mode {
+            KeyRelease::Auto => !self.release_events,
+            KeyRelease::Events => false,
+            KeyRelease::Timeout => true,
+        };
+        self.holds = KeyHolds::new(synthesize_release, hold);
+    }
+
+    pub fn set_keymap(&mut self, keymap: Keymap) {
+        self.keymap = keymap;
+    }
+
+    pub fn set_graphics(&mut self, graphics: Option<Protocol>) {
+        self.graphics = graphics;
     }
 
     pub fn restore(&mut self) {
+        if let Err(e) = self.clear_image() {
+            log::error!("err in clearing image {}", e);
+        }
         if let Err(e) = execute!(io::stdout(), PopKeyboardEnhancementFlags) {
             log::error!("err in popping keyboard enhancement flags {}", e);
         }
         ratatui::restore();
     }
 
-    pub fn draw(&mut self, display_buffer: &[[bool; WIDTH]; HEIGHT]) -> anyhow::Result<()> {
+    /// Hotkeys listed in the status bar
+    fn hints(&self) -> String {
+        [
+            (Key::Hud, "hud"),
+            (Key::Pause, "pause"),
+            (Key::Reset, "reset"),
+            (Key::Heatmap, "heatmap"),
+            (Key::Record, "record"),
+            (Key::Screenshot, "screenshot"),
+            (Key::SaveState, "save"),
+            (Key::LoadState, "load"),
+        ]
+        .iter()
+        .filter_map(|(key, name)| Some(format!("{} {}", self.keymap.describe(*key)?, name)))
+        .collect::<Vec<_>>()
+        .join("  ")
+    }
+
+    /// Splits off the bottom rows for the status bar when it is shown
+    fn split(area: Rect, hud: bool) -> (Rect, Option<Rect>) {
+        if !hud || area.height <= hud::HEIGHT {
+            return (area, None);
+        }
+        let [screen, status] =
+            Layout::vertical([Constraint::Min(0), Constraint::Length(hud::HEIGHT)]).areas(area);
+        (screen, Some(status))
+    }
+
+    pub fn draw(&mut self, intensity: &Intensity, hud: Option<&Hud>) -> anyhow::Result<()> {
+        if let Some(protocol) = self.graphics {
+            let (area, _) = Self::split(self.terminal.get_frame().area(), hud.is_some());
+            if let Some(placement) = Self::placement(protocol, area) {
+                return self.draw_bitmap(protocol, placement, intensity, hud);
+            }
+        }
+        self.clear_image()?;
+        let render_mode = self.render_mode;
+        let theme = self.theme;
+        let hints = self.hints();
+        match self.terminal.draw(|frame| {
+            let (area, status) = Self::split(frame.area(), hud.is_some());
+            frame.render_widget(Screen::new(intensity, render_mode, theme), area);
+            if let (Some(hud), Some(status)) = (hud, status) {
+                frame.render_widget(HudView::new(hud, theme, &hints), status);
+            }
+        }) {
+            Ok(_) => Ok(()),
+            Err(e) => anyhow::bail!("failed to render screen {}", e),
+        }
+    }
+
+    /// Fits the display into `area` using the terminal's cell size in pixels,
+    /// `None` when the terminal does not report it
+    fn placement(protocol: Protocol, area: Rect) -> Option<Placement> {
+        let size = terminal::window_size().ok()?;
+        if size.width == 0 || size.height == 0 || size.columns == 0 || size.rows == 0 {
+            return None;
+        }
+        let cell_width = (size.width / size.columns).max(1) as usize;
+        let cell_height = (size.height / size.rows).max(1) as usize;
+        // a sixel touching the last row scrolls the screen
+        let max_rows = match protocol {
+            Protocol::Kitty => area.height,
+            Protocol::Sixel => area.height.saturating_sub(1),
+        } as usize;
+        let scale = (area.width as usize * cell_width / WIDTH).min(max_rows * cell_height / HEIGHT);
+        if scale == 0 {
+            return None;
+        }
+        let cols = (WIDTH * scale).div_ceil(cell_width) as u16;
+        let rows = (HEIGHT * scale).div_ceil(cell_height) as u16;
+        Some(Placement {
+            left: area.x + area.width.saturating_sub(cols) / 2,
+            top: area.y + (max_rows as u16).saturating_sub(rows) / 2,
+            cols,
+            rows,
+            scale,
+        })
+    }
+
+    /// Blanks the cells and writes the display as an image on top of them
+    fn draw_bitmap(
+        &mut self,
+        protocol: Protocol,
+        placement: Placement,
+        intensity: &Intensity,
+        hud: Option<&Hud>,
+    ) -> anyhow::Result<()> {
+        let theme = self.theme;
+        let hints = self.hints();
+        if let Err(e) = self.terminal.draw(|frame| {
+            let (area, status) = Self::split(frame.area(), hud.is_some());
+            frame.render_widget(Block::default().bg(theme.color(theme::BACKGROUND)), area);
+            if let (Some(hud), Some(status)) = (hud, status) {
+                frame.render_widget(HudView::new(hud, theme, &hints), status);
+            }
+        }) {
+            anyhow::bail!("failed to render screen {}", e);
+        }
+        let max = BITMAP_LEVELS - 1;
+        let palette = (0..BITMAP_LEVELS)
+            .map(|l| self.theme.blend((l * u8::MAX as usize / max) as u8))
+            .collect();
+        let bitmap = Bitmap::from_pixels(WIDTH, HEIGHT, placement.scale, palette, |x, y| {
+            ((intensity[y][x] as usize * max).div_ceil(u8::MAX as usize)) as u8
+        });
+        let sequence = match protocol {
+            Protocol::Kitty => graphics::encode_kitty(&bitmap, placement.cols, placement.rows),
+            Protocol::Sixel => graphics::encode_sixel(&bitmap),
+        };
+        let mut stdout = io::stdout().lock();
+        queue!(stdout, MoveTo(placement.left, placement.top))?;
+        stdout.write_all(sequence.as_bytes())?;
+        stdout.flush()?;
+        self.image_shown = true;
+        Ok(())
+    }
+
+    /// Removes a previously drawn image so text cells show again
+    fn clear_image(&mut self) -> anyhow::Result<()> {
+        if !self.image_shown {
+            return Ok(());
+        }
+        if self.graphics == Some(Protocol::Kitty) {
+            let mut stdout = io::stdout().lock();
+            stdout.write_all(graphics::delete_kitty().as_bytes())?;
+            stdout.flush()?;
+        }
+        // sixel pixels stay until the cells beneath are written again
+        self.terminal.clear()?;
+        self.image_shown = false;
+        Ok(())
+    }
+
+    pub fn draw_heatmap(&mut self, heatmap: &Heatmap) -> anyhow::Result<()> {
+        self.clear_image()?;
         match self
             .terminal
-            .draw(|frame| frame.render_widget(Screen::new(display_buffer), frame.area()))
+            .draw(|frame| frame.render_widget(HeatmapView::new(heatmap), frame.area()))
         {
             Ok(_) => Ok(()),
-            Err(e) => anyhow::bail!("failed to render screen {}", e),
+            Err(e) => anyhow::bail!("failed to render heatmap {}", e),
         }
     }
 
@@ -116,46 +467,85 @@ impl Console {
         let start = Instant::now();
         let no_wait = Duration::from_secs(0);
         let mut keys = vec![];
-        while start.elapsed() < timeout {
-            if event::poll(no_wait)? {
-                match event::read()? {
-                    Event::Key(key) => match self.handle_key_code(key.code) {
-                        Some(k) => match key.kind {
-                            KeyEventKind::Press => keys.push(KeyEvent::Pressed(k)),
-                            KeyEventKind::Repeat => keys.push(KeyEvent::Pressed(k)),
-                            KeyEventKind::Release => keys.push(KeyEvent::Released(k)),
-                        },
-                        None => continue,
-                    },
-                    _ => continue,
+        while start.elapsed() < timeout && event::poll(no_wait)? {
+            let Event::Key(key) = event::read()? else {
+                continue;
+            };
+            let Some(k) = self.handle_key_code(key.code) else {
+                continue;
+            };
+            match key.kind {
+                KeyEventKind::Press | KeyEventKind::Repeat => {
+                    self.holds.press(k, Instant::now());
+                    keys.push(KeyEvent::Pressed(k));
+                }
+                KeyEventKind::Release => {
+                    self.holds.release(k);
+                    keys.push(KeyEvent::Released(k));
                 }
-            } else {
-                return Ok(keys);
             }
         }
+        self.holds.release_expired(Instant::now(), &mut keys);
         Ok(keys)
     }
 
     fn handle_key_code(&self, key_code: KeyCode) -> Option<Key> {
-        match key_code {
-            KeyCode::Esc => Some(Key::Quit),
-            KeyCode::Char('1') => Some(Key::Num(1)),
-            KeyCode::Char('2') => Some(Key::Num(2)),
-            KeyCode::Char('3') => Some(Key::Num(3)),
-            KeyCode::Char('4') => Some(Key::Num(0xc)),
-            KeyCode::Char('q') => Some(Key::Num(4)),
-            KeyCode::Char('w') => Some(Key::Num(5)),
-            KeyCode::Char('e') => Some(Key::Num(6)),
-            KeyCode::Char('r') => Some(Key::Num(0xd)),
-            KeyCode::Char('a') => Some(Key::Num(7)),
-            KeyCode::Char('s') => Some(Key::Num(8)),
-            KeyCode::Char('d') => Some(Key::Num(9)),
-            KeyCode::Char('f') => Some(Key::Num(0xe)),
-            KeyCode::Char('z') => Some(Key::Num(0xa)),
-            KeyCode::Char('x') => Some(Key::Num(0)),
-            KeyCode::Char('c') => Some(Key::Num(0xb)),
-            KeyCode::Char('v') => Some(Key::Num(0xf)),
-            _ => None,
-        }
+        self.keymap.lookup(key_code)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOLD: Duration = Duration::from_millis(700);
+
+    fn expired(holds: &mut KeyHolds, now: Instant) -> Vec<KeyEvent> {
+        let mut keys = vec![];
+        holds.release_expired(now, &mut keys);
+        keys
+    }
+
+    #[test]
+    fn a_tap_is_held_for_the_hold_time() {
+        let start = Instant::now();
+        let mut holds = KeyHolds::new(true, HOLD);
+        holds.press(Key::Num(5), start);
+        assert_eq!(expired(&mut holds, start + HOLD / 2), []);
+        assert_eq!(
+            expired(&mut holds, start + HOLD),
+            [KeyEvent::Released(Key::Num(5))]
+        );
+        assert_eq!(expired(&mut holds, start + HOLD * 2), []);
+    }
+
+    #[test]
+    fn auto_repeat_releases_soon_after_the_last_repeat() {
+        let start = Instant::now();
+        let mut holds = KeyHolds::new(true, HOLD);
+        holds.press(Key::Num(5), start);
+        let repeat = start + Duration::from_millis(500);
+        holds.press(Key::Num(5), repeat);
+        // still held past the tap time while repeats keep coming
+        holds.press(Key::Num(5), start + HOLD);
+        assert_eq!(expired(&mut holds, start + HOLD + REPEAT_GAP / 2), []);
+        assert_eq!(
+            expired(&mut holds, start + HOLD + REPEAT_GAP),
+            [KeyEvent::Released(Key::Num(5))]
+        );
+    }
+
+    #[test]
+    fn release_events_end_a_hold() {
+        let start = Instant::now();
+        let mut holds = KeyHolds::new(true, HOLD);
+        holds.press(Key::Num(5), start);
+        holds.release(Key::Num(5));
+        assert_eq!(expired(&mut holds, start + HOLD), []);
+
+        // without synthesized releases nothing expires
+        let mut holds = KeyHolds::new(false, HOLD);
+        holds.press(Key::Num(5), start);
+        assert_eq!(expired(&mut holds, start + HOLD), []);
     }
 }
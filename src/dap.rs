@@ -0,0 +1,787 @@
+use crate::debugger::{Debugger, StopReason};
+use crate::machine::{MEMORY_SIZE, Machine, REGISTER_COUNT};
+use crate::opcode;
+use crate::source_map::{self, SourceMap};
+use base64::Engine;
+use log::{info, warn};
+use rand::Rng;
+use serde::Deserialize;
+use serde_json::{Value, json};
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{Ipv4Addr, TcpListener};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::mpsc;
+use std::thread;
+
+const THREAD_ID: i64 = 1;
+const SCOPE_REGISTERS: i64 = 1;
+const SCOPE_TIMERS: i64 = 2;
+const SCOPE_STACK: i64 = 3;
+
+#[derive(Debug, Clone, Copy)]
+pub enum Endpoint {
+    Stdio,
+    Tcp(u16),
+}
+
+impl FromStr for Endpoint {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "stdio" => Ok(Endpoint::Stdio),
+            port => port
+                .parse()
+                .map(Endpoint::Tcp)
+                .map_err(|_| format!("expected `stdio` or a port, got {}", port)),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct Request {
+    seq: i64,
+    command: String,
+    #[serde(default)]
+    arguments: Value,
+}
+
+/// A message from the client, as the reader thread passes it on
+enum Incoming {
+    Request(Request),
+    /// a message that isn't a valid request, with what could be read of it
+    Malformed(Request, String),
+}
+
+impl Incoming {
+    fn parse(body: &[u8]) -> Self {
+        let value: Value = match serde_json::from_slice(body) {
+            Ok(value) => value,
+            Err(e) => return Incoming::Malformed(Request::salvage(&Value::Null), e.to_string()),
+        };
+        match serde_json::from_value(value.clone()) {
+            Ok(req) => Incoming::Request(req),
+            Err(e) => Incoming::Malformed(Request::salvage(&value), e.to_string()),
+        }
+    }
+}
+
+impl Request {
+    /// The seq and command of a malformed request, so the error response
+    /// can still name them
+    fn salvage(value: &Value) -> Self {
+        Request {
+            seq: value["seq"].as_i64().unwrap_or(0),
+            command: value["command"].as_str().unwrap_or_default().to_string(),
+            arguments: Value::Null,
+        }
+    }
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct LaunchArgs {
+    pub program: Option<PathBuf>,
+    pub source_map: Option<PathBuf>,
+    pub symbols: Option<PathBuf>,
+    #[serde(default)]
+    pub stop_on_entry: bool,
+}
+
+/// Debug Adapter Protocol server, speaking over stdio or a local TCP connection
+pub struct DapServer {
+    incoming: mpsc::Receiver<anyhow::Result<Incoming>>,
+    writer: Box<dyn Write + Send>,
+    seq: i64,
+    source_map: SourceMap,
+    source_breakpoints: HashMap<PathBuf, Vec<usize>>,
+    instruction_breakpoints: Vec<usize>,
+    function_breakpoints: Vec<usize>,
+    /// breakpoint used to implement `next` and `stepOut`
+    step_breakpoint: Option<usize>,
+    pending_launch: Option<i64>,
+    stop_on_entry: bool,
+    attached: bool,
+}
+
+impl DapServer {
+    pub fn connect(endpoint: Endpoint) -> anyhow::Result<Self> {
+        let (reader, writer): (Box<dyn Read + Send>, Box<dyn Write + Send>) = match endpoint {
+            Endpoint::Stdio => (Box::new(io::stdin()), Box::new(io::stdout())),
+            Endpoint::Tcp(port) => {
+                let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port))?;
+                eprintln!(
+                    "waiting for debug adapter client on {}",
+                    listener.local_addr()?
+                );
+                let (stream, peer) = listener.accept()?;
+                info!("(Dap) connected from {}", peer);
+                stream.set_nodelay(true)?;
+                (Box::new(stream.try_clone()?), Box::new(stream))
+            }
+        };
+        let (tx, incoming) = mpsc::channel();
+        thread::spawn(move || {
+            let mut reader = BufReader::new(reader);
+            loop {
+                match read_message(&mut reader) {
+                    Ok(Some(body)) => {
+                        if tx.send(Ok(Incoming::parse(&body))).is_err() {
+                            break;
+                        }
+                    }
+                    Ok(None) => break,
+                    Err(e) => {
+                        let _ = tx.send(Err(e));
+                        break;
+                    }
+                }
+            }
+        });
+        Ok(DapServer {
+            incoming,
+            writer,
+            seq: 0,
+            source_map: SourceMap::default(),
+            source_breakpoints: HashMap::new(),
+            instruction_breakpoints: Vec::new(),
+            function_breakpoints: Vec::new(),
+            step_breakpoint: None,
+            pending_launch: None,
+            stop_on_entry: false,
+            attached: true,
+        })
+    }
+
+    /// Answers `initialize` and blocks until the client asks to launch a program
+    pub fn wait_for_launch(&mut self) -> anyhow::Result<LaunchArgs> {
+        loop {
+            let req = match self.incoming.recv() {
+                Ok(incoming) => match incoming? {
+                    Incoming::Request(req) => req,
+                    Incoming::Malformed(req, e) => {
+                        self.reject(&req, e)?;
+                        continue;
+                    }
+                },
+                Err(_) => anyhow::bail!("debug adapter client disconnected before launch"),
+            };
+            match req.command.as_str() {
+                "initialize" => {
+                    let body = json!({
+                        "supportsConfigurationDoneRequest": true,
+                        "supportsInstructionBreakpoints": true,
+                        "supportsFunctionBreakpoints": true,
+                        "supportsReadMemoryRequest": true,
+                        "supportsDisassembleRequest": true,
+                        "supportsSetVariable": true,
+                        "supportsTerminateRequest": true,
+                    });
+                    self.respond(&req, Ok(body))?;
+                }
+                "launch" => {
+                    let args: LaunchArgs = match serde_json::from_value(req.arguments.clone()) {
+                        Ok(args) => args,
+                        Err(e) => {
+                            self.reject(&req, e.to_string())?;
+                            continue;
+                        }
+                    };
+                    if let Some(path) = &args.source_map {
+                        match SourceMap::load(path) {
+                            Ok(source_map) => self.source_map = source_map,
+                            Err(e) => {
+                                self.reject(&req, e.to_string())?;
+                                continue;
+                            }
+                        }
+                    }
+                    self.stop_on_entry = args.stop_on_entry;
+                    self.pending_launch = Some(req.seq);
+                    return Ok(args);
+                }
+                "disconnect" => anyhow::bail!("debug adapter client disconnected before launch"),
+                _ => self.respond(&req, Err("not launched".into()))?,
+            }
+        }
+    }
+
+    fn send(&mut self, mut msg: Value) -> anyhow::Result<()> {
+        self.seq += 1;
+        msg["seq"] = json!(self.seq);
+        let body = serde_json::to_vec(&msg)?;
+        write!(self.writer, "Content-Length: {}\r\n\r\n", body.len())?;
+        self.writer.write_all(&body)?;
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    fn respond(&mut self, req: &Request, body: Result<Value, String>) -> anyhow::Result<()> {
+        let mut msg = json!({
+            "type": "response",
+            "request_seq": req.seq,
+            "command": req.command,
+            "success": body.is_ok(),
+        });
+        match body {
+            Ok(body) => msg["body"] = body,
+            Err(message) => msg["message"] = json!(message),
+        }
+        self.send(msg)
+    }
+
+    /// Answers a request that couldn't be handled and carries on
+    fn reject(&mut self, req: &Request, error: String) -> anyhow::Result<()> {
+        warn!("(Dap) {} failed: {}", req.command, error);
+        self.respond(req, Err(error))
+    }
+
+    fn event(&mut self, event: &str, body: Value) -> anyhow::Result<()> {
+        self.send(json!({"type": "event", "event": event, "body": body}))
+    }
+
+    fn stopped(&mut self, reason: &str) -> anyhow::Result<()> {
+        self.event(
+            "stopped",
+            json!({"reason": reason, "threadId": THREAD_ID, "allThreadsStopped": true}),
+        )
+    }
+
+    fn sync_breakpoints<R: Rng>(&self, machine: &mut Machine<R>) {
+        machine.clear_breakpoints();
+        let addrs = self
+            .source_breakpoints
+            .values()
+            .flatten()
+            .chain(self.instruction_breakpoints.iter())
+            .chain(self.function_breakpoints.iter())
+            .chain(self.step_breakpoint.iter());
+        for addr in addrs {
+            machine.add_breakpoint(*addr);
+        }
+    }
+
+    fn source_of(&self, addr: usize) -> (Value, u32) {
+        match self.source_map.lookup(addr) {
+            Some(s) => {
+                let name = s.file.file_name().map(|n| n.to_string_lossy().into_owned());
+                (json!({"name": name, "path": s.file}), s.line)
+            }
+            None => (Value::Null, 0),
+        }
+    }
+
+    fn handle<R: Rng>(
+        &mut self,
+        machine: &mut Machine<R>,
+        req: &Request,
+    ) -> anyhow::Result<Option<Result<Value, String>>> {
+        let args = &req.arguments;
+        let body = match req.command.as_str() {
+            "setBreakpoints" => {
+                let path = PathBuf::from(args["source"]["path"].as_str().unwrap_or_default());
+                let mut addrs = Vec::new();
+                let mut result = Vec::new();
+                for bp in args["breakpoints"].as_array().into_iter().flatten() {
+                    let line = bp["line"].as_u64().unwrap_or(0) as u32;
+                    match self.source_map.address_of(&path, line) {
+                        Some(addr) => {
+                            addrs.push(addr);
+                            let line = self.source_map.lookup(addr).map_or(line, |s| s.line);
+                            result.push(json!({
+                                "verified": true,
+                                "line": line,
+                                "instructionReference": format!("0x{:x}", addr),
+                            }));
+                        }
+                        None => result.push(json!({
+                            "verified": false,
+                            "line": line,
+                            "message": "no code at this line",
+                        })),
+                    }
+                }
+                self.source_breakpoints.insert(path, addrs);
+                self.sync_breakpoints(machine);
+                json!({"breakpoints": result})
+            }
+            "setInstructionBreakpoints" => {
+                self.instruction_breakpoints.clear();
+                let mut result = Vec::new();
+                for bp in args["breakpoints"].as_array().into_iter().flatten() {
+                    let reference = bp["instructionReference"].as_str().unwrap_or_default();
+                    let offset = bp["offset"].as_i64().unwrap_or(0);
+                    let addr = source_map::parse_address(reference)
+                        .and_then(|addr| (addr as i64).checked_add(offset))
+                        .and_then(|addr| usize::try_from(addr).ok())
+                        .filter(|addr| *addr < MEMORY_SIZE);
+                    match addr {
+                        Some(addr) => {
+                            self.instruction_breakpoints.push(addr);
+                            result.push(json!({
+                                "verified": true,
+                                "instructionReference": format!("0x{:x}", addr),
+                            }));
+                        }
+                        None => result.push(json!({"verified": false})),
+                    }
+                }
+                self.sync_breakpoints(machine);
+                json!({"breakpoints": result})
+            }
+            "setFunctionBreakpoints" => {
+                self.function_breakpoints.clear();
+                let mut result = Vec::new();
+                for bp in args["breakpoints"].as_array().into_iter().flatten() {
+                    let name = bp["name"].as_str().unwrap_or_default();
+                    match machine.symbols().resolve(name) {
+                        Some(addr) => {
+                            self.function_breakpoints.push(addr);
+                            result.push(json!({
+                                "verified": true,
+                                "instructionReference": format!("0x{:x}", addr),
+                            }));
+                        }
+                        None => result.push(json!({
+                            "verified": false,
+                            "message": format!("unknown label {}", name),
+                        })),
+                    }
+                }
+                self.sync_breakpoints(machine);
+                json!({"breakpoints": result})
+            }
+            "configurationDone" => {
+                if self.stop_on_entry {
+                    self.respond(req, Ok(Value::Null))?;
+                    self.stopped("entry")?;
+                    return Ok(None);
+                }
+                machine.resume();
+                Value::Null
+            }
+            "threads" => json!({"threads": [{"id": THREAD_ID, "name": "chip8"}]}),
+            "stackTrace" => {
+                let mut addrs = vec![machine.pc()];
+                addrs.extend(
+                    machine
+                        .stack()
+                        .iter()
+                        .rev()
+                        .map(|ret| ret.saturating_sub(2)),
+                );
+                let frames: Vec<Value> = addrs
+                    .into_iter()
+                    .enumerate()
+                    .map(|(id, addr)| {
+                        let (source, line) = self.source_of(addr);
+                        json!({
+                            "id": id,
+                            "name": machine.symbols().describe(addr),
+                            "source": source,
+                            "line": line,
+                            "column": if line > 0 { 1 } else { 0 },
+                            "instructionPointerReference": format!("0x{:x}", addr),
+                        })
+                    })
+                    .collect();
+                json!({"stackFrames": frames, "totalFrames": frames.len()})
+            }
+            "scopes" => json!({"scopes": [
+                {"name": "Registers", "variablesReference": SCOPE_REGISTERS, "expensive": false},
+                {"name": "Timers", "variablesReference": SCOPE_TIMERS, "expensive": false},
+                {"name": "Stack", "variablesReference": SCOPE_STACK, "expensive": false},
+            ]}),
+            "variables" => {
+                let reference = args["variablesReference"].as_i64().unwrap_or(0);
+                json!({"variables": variables(machine, reference)})
+            }
+            "setVariable" => {
+                let name = args["name"].as_str().unwrap_or_default();
+                let value = args["value"].as_str().unwrap_or_default();
+                let Some(value) = parse_number(value) else {
+                    return Ok(Some(Err(format!("invalid value {}", value))));
+                };
+                if let Err(e) = set_variable(machine, name, value) {
+                    return Ok(Some(Err(e.to_string())));
+                }
+                json!({"value": format!("{:#x}", value)})
+            }
+            "continue" => {
+                machine.resume();
+                json!({"allThreadsContinued": true})
+            }
+            "next" => {
+                let pc = machine.pc();
+                let opcode = machine.peek(pc).zip(machine.peek(pc + 1));
+                let is_call = opcode.is_some_and(|(hi, lo)| {
+                    matches!(
+                        opcode::parse_opcode(u16::from_be_bytes([hi, lo])),
+                        opcode::Operation::CallC(_)
+                    )
+                });
+                if is_call {
+                    self.run_to(machine, pc + 2);
+                } else {
+                    self.step_in(machine, req)?;
+                    return Ok(None);
+                }
+                Value::Null
+            }
+            "stepIn" => {
+                self.step_in(machine, req)?;
+                return Ok(None);
+            }
+            "stepOut" => match machine.stack().last() {
+                Some(ret) => {
+                    self.run_to(machine, *ret);
+                    Value::Null
+                }
+                None => {
+                    self.step_in(machine, req)?;
+                    return Ok(None);
+                }
+            },
+            "pause" => {
+                self.respond(req, Ok(Value::Null))?;
+                if !machine.is_paused() {
+                    machine.stop(StopReason::Interrupt)?;
+                }
+                return Ok(None);
+            }
+            "readMemory" => {
+                let reference = args["memoryReference"].as_str().unwrap_or_default();
+                let Some(base) = source_map::parse_address(reference) else {
+                    return Ok(Some(Err(format!("invalid memory reference {}", reference))));
+                };
+                let offset = args["offset"].as_i64().unwrap_or(0);
+                let addr = (base as i64).saturating_add(offset).max(0) as usize;
+                let count = args["count"].as_u64().unwrap_or(0) as usize;
+                let data: Vec<u8> = (addr..addr.saturating_add(count))
+                    .map_while(|a| machine.peek(a))
+                    .collect();
+                json!({
+                    "address": format!("0x{:x}", addr),
+                    "unreadableBytes": count - data.len(),
+                    "data": base64::engine::general_purpose::STANDARD.encode(&data),
+                })
+            }
+            "disassemble" => {
+                let reference = args["memoryReference"].as_str().unwrap_or_default();
+                let Some(base) = source_map::parse_address(reference) else {
+                    return Ok(Some(Err(format!("invalid memory reference {}", reference))));
+                };
+                let start = (base as i64)
+                    .saturating_add(args["offset"].as_i64().unwrap_or(0))
+                    .saturating_add(
+                        args["instructionOffset"]
+                            .as_i64()
+                            .unwrap_or(0)
+                            .saturating_mul(2),
+                    );
+                let count = args["instructionCount"]
+                    .as_i64()
+                    .unwrap_or(0)
+                    .clamp(0, (MEMORY_SIZE / 2) as i64);
+                let instructions: Vec<Value> = (0..count)
+                    .map(|n| start.saturating_add(n * 2))
+                    .map(|addr| {
+                        let bytes = usize::try_from(addr)
+                            .ok()
+                            .and_then(|a| machine.peek(a).zip(machine.peek(a + 1)));
+                        match bytes {
+                            Some((hi, lo)) => {
+                                let (source, line) = self.source_of(addr as usize);
+                                json!({
+                                    "address": format!("0x{:x}", addr),
+                                    "instructionBytes": format!("{:02x} {:02x}", hi, lo),
+                                    "instruction": opcode::parse_opcode(u16::from_be_bytes([hi, lo]))
+                                        .labeled(machine.symbols())
+                                        .to_string(),
+                                    "symbol": machine.symbols().label(addr as usize),
+                                    "location": source,
+                                    "line": line,
+                                })
+                            }
+                            None => json!({
+                                "address": format!("0x{:x}", addr),
+                                "instruction": "??",
+                                "presentationHint": "invalid",
+                            }),
+                        }
+                    })
+                    .collect();
+                json!({"instructions": instructions})
+            }
+            "disconnect" | "terminate" => {
+                self.respond(req, Ok(Value::Null))?;
+                machine.quit();
+                if req.command == "disconnect" {
+                    self.attached = false;
+                }
+                return Ok(None);
+            }
+            other => return Ok(Some(Err(format!("unsupported request {}", other)))),
+        };
+        Ok(Some(Ok(body)))
+    }
+
+    fn step_in<R: Rng>(&mut self, machine: &mut Machine<R>, req: &Request) -> anyhow::Result<()> {
+        machine.step_once()?;
+        self.respond(req, Ok(Value::Null))?;
+        self.stopped("step")
+    }
+
+    fn run_to<R: Rng>(&mut self, machine: &mut Machine<R>, addr: usize) {
+        self.step_breakpoint = Some(addr);
+        self.sync_breakpoints(machine);
+        machine.resume();
+    }
+}
+
+impl<R> Debugger<R> for DapServer
+where
+    R: Rng,
+{
+    fn poll(&mut self, machine: &mut Machine<R>) -> anyhow::Result<()> {
+        if let Some(seq) = self.pending_launch.take() {
+            let launch = Request {
+                seq,
+                command: "launch".into(),
+                arguments: Value::Null,
+            };
+            self.respond(&launch, Ok(Value::Null))?;
+            self.event("initialized", Value::Null)?;
+        }
+        loop {
+            let req = match self.incoming.try_recv() {
+                Ok(incoming) => match incoming? {
+                    Incoming::Request(req) => req,
+                    Incoming::Malformed(req, e) => {
+                        self.reject(&req, e)?;
+                        continue;
+                    }
+                },
+                Err(mpsc::TryRecvError::Empty) => return Ok(()),
+                Err(mpsc::TryRecvError::Disconnected) => {
+                    info!("(Dap) client disconnected");
+                    machine.quit();
+                    self.attached = false;
+                    return Ok(());
+                }
+            };
+            match self.handle(machine, &req) {
+                Ok(Some(Ok(body))) => self.respond(&req, Ok(body))?,
+                Ok(Some(Err(e))) => self.reject(&req, e)?,
+                Ok(None) => {}
+                Err(e) => self.reject(&req, e.to_string())?,
+            }
+        }
+    }
+
+    fn on_stop(&mut self, machine: &mut Machine<R>, reason: StopReason) -> anyhow::Result<()> {
+        match reason {
+            StopReason::Exited => {
+                self.event("exited", json!({"exitCode": 0}))?;
+                self.event("terminated", Value::Null)
+            }
+            StopReason::Interrupt => self.stopped("pause"),
+            StopReason::Breakpoint => {
+                let stepped = self.step_breakpoint.take() == Some(machine.pc());
+                self.sync_breakpoints(machine);
+                self.stopped(if stepped { "step" } else { "breakpoint" })
+            }
+        }
+    }
+
+    fn attached(&self) -> bool {
+        self.attached
+    }
+}
+
+/// Reads one `Content-Length` framed message, `None` at end of stream
+/// The body of the next message, or `None` once the client closed the stream
+fn read_message(reader: &mut impl BufRead) -> anyhow::Result<Option<Vec<u8>>> {
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim();
+        if header.is_empty() {
+            break;
+        }
+        if let Some(len) = header.strip_prefix("Content-Length:") {
+            length = Some(len.trim().parse::<usize>()?);
+        }
+    }
+    let Some(length) = length else {
+        anyhow::bail!("missing Content-Length header");
+    };
+    let mut body = vec![0u8; length];
+    reader.read_exact(&mut body)?;
+    Ok(Some(body))
+}
+
+fn variable(name: &str, value: String) -> Value {
+    json!({"name": name, "value": value, "variablesReference": 0})
+}
+
+fn variables<R: Rng>(machine: &Machine<R>, reference: i64) -> Vec<Value> {
+    match reference {
+        SCOPE_REGISTERS => {
+            let mut vars: Vec<Value> = (0..REGISTER_COUNT as u8)
+                .map(|r| {
+                    let val = machine.get_register(r).unwrap_or(0);
+                    variable(&format!("v{:x}", r), format!("{:#04x}", val))
+                })
+                .collect();
+            let i = format!("{:#05x}", machine.get_register_i());
+            let mut var_i = variable("i", i.clone());
+            var_i["memoryReference"] = json!(i);
+            vars.push(var_i);
+            vars.push(variable("pc", format!("{:#05x}", machine.pc())));
+            vars
+        }
+        SCOPE_TIMERS => vec![
+            variable("dt", format!("{}", machine.delay_timer())),
+            variable("st", format!("{}", machine.sound_timer())),
+        ],
+        SCOPE_STACK => {
+            let mut vars = vec![variable("sp", format!("{}", machine.stack().len()))];
+            vars.extend(
+                machine
+                    .stack()
+                    .iter()
+                    .enumerate()
+                    .map(|(n, ret)| variable(&format!("#{}", n), format!("{:#05x}", ret))),
+            );
+            vars
+        }
+        _ => Vec::new(),
+    }
+}
+
+fn set_variable<R: Rng>(machine: &mut Machine<R>, name: &str, value: u16) -> anyhow::Result<()> {
+    match name {
+        "i" => machine.set_register_i(value)?,
+        "pc" => machine.set_pc(value as usize)?,
+        "dt" => machine.set_delay_timer(value as u8),
+        "st" => machine.set_sound_timer(value as u8),
+        _ => match name.strip_prefix('v').map(|r| u8::from_str_radix(r, 16)) {
+            Some(Ok(reg)) => machine.set_register(reg, value as u8)?,
+            _ => anyhow::bail!("{} can not be modified", name),
+        },
+    }
+    Ok(())
+}
+
+fn parse_number(s: &str) -> Option<u16> {
+    match s.strip_prefix("0x") {
+        Some(hex) => u16::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::CARTRIDGE_ADDRESS;
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    #[derive(Clone, Default)]
+    struct Output(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Output {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Output {
+        /// The messages written so far, with their headers stripped
+        fn messages(&self) -> Vec<Value> {
+            let out = self.0.lock().unwrap();
+            let mut reader = &out[..];
+            let mut messages = Vec::new();
+            while let Some(body) = read_message(&mut reader).unwrap() {
+                messages.push(serde_json::from_slice(&body).unwrap());
+            }
+            messages
+        }
+    }
+
+    fn server(incoming: mpsc::Receiver<anyhow::Result<Incoming>>, output: Output) -> DapServer {
+        DapServer {
+            incoming,
+            writer: Box::new(output),
+            seq: 0,
+            source_map: SourceMap::default(),
+            source_breakpoints: HashMap::new(),
+            instruction_breakpoints: Vec::new(),
+            function_breakpoints: Vec::new(),
+            step_breakpoint: None,
+            pending_launch: None,
+            stop_on_entry: false,
+            attached: true,
+        }
+    }
+
+    #[test]
+    fn bad_requests_are_answered_without_ending_the_session() {
+        let (tx, rx) = mpsc::channel();
+        let output = Output::default();
+        let mut dap = server(rx, output.clone());
+        let mut machine = Machine::headless(StdRng::seed_from_u64(0), Duration::ZERO);
+        // a return with nothing on the stack
+        machine
+            .load_cartridge(CARTRIDGE_ADDRESS, &[0x00, 0xEE])
+            .unwrap();
+        machine.set_pc(CARTRIDGE_ADDRESS).unwrap();
+
+        let request = |body: Value| Ok(Incoming::parse(body.to_string().as_bytes()));
+        tx.send(request(json!({"seq": 1, "command": 5}))).unwrap();
+        tx.send(Ok(Incoming::parse(b"{not json"))).unwrap();
+        tx.send(request(json!({"seq": 3, "command": "stepIn"})))
+            .unwrap();
+        tx.send(request(json!({
+            "seq": 4,
+            "command": "disassemble",
+            "arguments": {
+                "memoryReference": "0x200",
+                "instructionOffset": i64::MAX,
+                "instructionCount": i64::MAX,
+            },
+        })))
+        .unwrap();
+        Debugger::poll(&mut dap, &mut machine).unwrap();
+
+        let messages = output.messages();
+        let answers: Vec<_> = messages
+            .iter()
+            .map(|m| (m["request_seq"].as_i64(), m["success"].as_bool()))
+            .collect();
+        assert_eq!(
+            answers,
+            [
+                (Some(1), Some(false)),
+                (Some(0), Some(false)),
+                (Some(3), Some(false)),
+                (Some(4), Some(true)),
+            ]
+        );
+        let instructions = messages[3]["body"]["instructions"].as_array().unwrap();
+        assert_eq!(instructions.len(), MEMORY_SIZE / 2);
+        assert!(dap.attached);
+    }
+}
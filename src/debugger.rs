@@ -0,0 +1,23 @@
+use crate::machine::Machine;
+use rand::Rng;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    Breakpoint,
+    Interrupt,
+    Exited,
+}
+
+/// A debugging front-end attached to the machine.
+/// It is polled once per iteration of the boot loop and notified whenever execution stops.
+pub trait Debugger<R>
+where
+    R: Rng,
+{
+    fn poll(&mut self, machine: &mut Machine<R>) -> anyhow::Result<()>;
+
+    fn on_stop(&mut self, machine: &mut Machine<R>, reason: StopReason) -> anyhow::Result<()>;
+
+    /// Once this returns false the debugger is dropped and the machine runs freely
+    fn attached(&self) -> bool;
+}
@@ -0,0 +1,141 @@
+//! Assertions checked by the `test` command once a headless run ends.
+
+use crate::machine::{DISPLAY_HEIGHT, DISPLAY_WIDTH, Machine};
+use crate::source_map::parse_address;
+use rand::Rng;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone)]
+pub enum Expectation {
+    /// a label or address
+    Pc(String),
+    Register(u8, u8),
+    I(u16),
+    Memory(usize, u8),
+    /// a plain PBM image of the display, such as a `.pbm` screenshot
+    Screen(PathBuf),
+}
+
+impl fmt::Display for Expectation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Expectation::Pc(pc) => write!(f, "pc = {}", pc),
+            Expectation::Register(x, v) => write!(f, "v{:x} = {:#04x}", x, v),
+            Expectation::I(v) => write!(f, "I = {:#05x}", v),
+            Expectation::Memory(addr, v) => write!(f, "[{:#05x}] = {:#04x}", addr, v),
+            Expectation::Screen(path) => write!(f, "screen = {}", path.display()),
+        }
+    }
+}
+
+/// Parses `VX=NN` or `I=NNN`, values in hex
+pub fn parse_register(s: &str) -> anyhow::Result<Expectation> {
+    let err = || anyhow::anyhow!("invalid register check {}, expected VX=NN or I=NNN", s);
+    let (reg, value) = s.split_once('=').ok_or_else(err)?;
+    let value = parse_address(value.trim()).ok_or_else(err)?;
+    let reg = reg.trim().to_lowercase();
+    if reg == "i" || reg == "vi" {
+        return u16::try_from(value).map(Expectation::I).map_err(|_| err());
+    }
+    let x = reg
+        .strip_prefix('v')
+        .filter(|x| x.len() == 1)
+        .and_then(|x| u8::from_str_radix(x, 16).ok())
+        .ok_or_else(err)?;
+    let value = u8::try_from(value).map_err(|_| err())?;
+    Ok(Expectation::Register(x, value))
+}
+
+/// Parses `ADDR=NN`, both in hex
+pub fn parse_memory(s: &str) -> anyhow::Result<Expectation> {
+    let err = || anyhow::anyhow!("invalid memory check {}, expected ADDR=NN", s);
+    let (addr, value) = s.split_once('=').ok_or_else(err)?;
+    let addr = parse_address(addr.trim()).ok_or_else(err)?;
+    let value = parse_address(value.trim())
+        .and_then(|v| u8::try_from(v).ok())
+        .ok_or_else(err)?;
+    Ok(Expectation::Memory(addr, value))
+}
+
+impl Expectation {
+    /// `Err` describes what was found instead
+    pub fn check<R: Rng>(&self, machine: &Machine<R>) -> anyhow::Result<Result<(), String>> {
+        let found = match self {
+            Expectation::Pc(pc) => {
+                let Some(addr) = machine.symbols().resolve(pc) else {
+                    anyhow::bail!("unknown label {}, addresses take a 0x prefix", pc);
+                };
+                (machine.pc() != addr).then(|| machine.symbols().describe(machine.pc()))
+            }
+            Expectation::Register(x, v) => {
+                let found = machine.get_register(*x)?;
+                (found != *v).then(|| format!("{:#04x}", found))
+            }
+            Expectation::I(v) => {
+                let found = machine.get_register_i();
+                (found != *v).then(|| format!("{:#05x}", found))
+            }
+            Expectation::Memory(addr, v) => match machine.peek(*addr) {
+                Some(found) if found == *v => None,
+                Some(found) => Some(format!("{:#04x}", found)),
+                None => anyhow::bail!("address {:#x} is outside memory", addr),
+            },
+            Expectation::Screen(path) => {
+                let expected = read_pbm(path)?;
+                let differ = expected
+                    .iter()
+                    .zip(machine.display_buffer())
+                    .flat_map(|(e, f)| e.iter().zip(f))
+                    .filter(|(e, f)| e != f)
+                    .count();
+                (differ > 0).then(|| format!("{} pixels differ", differ))
+            }
+        };
+        Ok(match found {
+            Some(found) => Err(found),
+            None => Ok(()),
+        })
+    }
+}
+
+/// Reads a plain (P1) PBM of the display at any integer scale
+fn read_pbm(path: &Path) -> anyhow::Result<[[bool; DISPLAY_WIDTH]; DISPLAY_HEIGHT]> {
+    let text = fs::read_to_string(path)?;
+    let mut tokens = text
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or_default())
+        .flat_map(|line| line.split_whitespace());
+    let err = || anyhow::anyhow!("{} is not a plain PBM image", path.display());
+    if tokens.next() != Some("P1") {
+        return Err(err());
+    }
+    let mut size = || tokens.next().and_then(|t| t.parse::<usize>().ok());
+    let (Some(width), Some(height)) = (size(), size()) else {
+        return Err(err());
+    };
+    let scale = width / DISPLAY_WIDTH;
+    if scale == 0 || width != DISPLAY_WIDTH * scale || height != DISPLAY_HEIGHT * scale {
+        anyhow::bail!(
+            "{} is {}x{}, not a multiple of the {}x{} display",
+            path.display(),
+            width,
+            height,
+            DISPLAY_WIDTH,
+            DISPLAY_HEIGHT
+        );
+    }
+    // pixels may be run together without whitespace
+    let pixels: Vec<bool> = tokens.flat_map(|t| t.chars()).map(|c| c == '1').collect();
+    if pixels.len() != width * height {
+        return Err(err());
+    }
+    let mut display = [[false; DISPLAY_WIDTH]; DISPLAY_HEIGHT];
+    for (y, row) in display.iter_mut().enumerate() {
+        for (x, pixel) in row.iter_mut().enumerate() {
+            *pixel = pixels[y * scale * width + x * scale];
+        }
+    }
+    Ok(display)
+}
@@ -0,0 +1,102 @@
+use crate::machine::{DISPLAY_HEIGHT, DISPLAY_WIDTH};
+use clap::ValueEnum;
+use serde::Deserialize;
+
+/// Brightness of each display pixel, 0 is dark and 255 fully lit
+pub type Intensity = [[u8; DISPLAY_WIDTH]; DISPLAY_HEIGHT];
+
+/// Below this a fading pixel goes dark
+const DECAY_CUTOFF: u8 = 16;
+
+/// Anti-flicker filters for sprites erased and redrawn with XOR
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum FilterMode {
+    /// Draw every change as soon as it happens
+    #[default]
+    Off,
+    /// Draw at most once per 60 Hz frame
+    Frame,
+    /// Draw once per frame with the previous frame ORed in
+    Blend,
+    /// Draw once per frame with unlit pixels fading out like phosphor
+    Decay,
+}
+
+pub struct Filter {
+    mode: FilterMode,
+    previous: [[bool; DISPLAY_WIDTH]; DISPLAY_HEIGHT],
+    previous_dirty: bool,
+    levels: Intensity,
+}
+
+impl Filter {
+    pub fn new(mode: FilterMode) -> Self {
+        Filter {
+            mode,
+            previous: [[false; DISPLAY_WIDTH]; DISPLAY_HEIGHT],
+            previous_dirty: false,
+            levels: [[0; DISPLAY_WIDTH]; DISPLAY_HEIGHT],
+        }
+    }
+
+    /// Whether changes are drawn as they happen rather than once per frame
+    pub fn immediate(&self) -> bool {
+        self.mode == FilterMode::Off
+    }
+
+    /// Advances the filter at the end of a 60 Hz frame, returns whether the
+    /// screen needs drawing
+    pub fn end_frame(
+        &mut self,
+        display_buffer: &[[bool; DISPLAY_WIDTH]; DISPLAY_HEIGHT],
+        dirty: bool,
+    ) -> bool {
+        match self.mode {
+            FilterMode::Off | FilterMode::Frame => dirty,
+            FilterMode::Blend => {
+                // the previous frame is the one just drawn, drop it on the next draw
+                let redraw = dirty || self.previous_dirty;
+                self.previous_dirty = dirty;
+                redraw
+            }
+            FilterMode::Decay => {
+                let mut fading = false;
+                for (levels, row) in self.levels.iter_mut().zip(display_buffer) {
+                    for (level, lit) in levels.iter_mut().zip(row) {
+                        let next = match (*lit, *level / 2) {
+                            (true, _) => u8::MAX,
+                            (false, l) if l < DECAY_CUTOFF => 0,
+                            (false, l) => l,
+                        };
+                        fading |= next != *level;
+                        *level = next;
+                    }
+                }
+                fading
+            }
+        }
+    }
+
+    /// The intensities to draw, call after [`Filter::end_frame`] asked for a redraw
+    pub fn intensity(
+        &mut self,
+        display_buffer: &[[bool; DISPLAY_WIDTH]; DISPLAY_HEIGHT],
+    ) -> Intensity {
+        let lit = |on: bool| if on { u8::MAX } else { 0 };
+        let mut out = [[0; DISPLAY_WIDTH]; DISPLAY_HEIGHT];
+        for (y, row) in out.iter_mut().enumerate() {
+            for (x, v) in row.iter_mut().enumerate() {
+                *v = match self.mode {
+                    FilterMode::Off | FilterMode::Frame => lit(display_buffer[y][x]),
+                    FilterMode::Blend => lit(display_buffer[y][x] || self.previous[y][x]),
+                    FilterMode::Decay => self.levels[y][x],
+                };
+            }
+        }
+        if self.mode == FilterMode::Blend {
+            self.previous = *display_buffer;
+        }
+        out
+    }
+}
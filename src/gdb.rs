@@ -0,0 +1,512 @@
+use crate::debugger::{Debugger, StopReason};
+use crate::machine::{MEMORY_SIZE, Machine, REGISTER_COUNT};
+use log::{info, warn};
+use rand::Rng;
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, TcpListener, TcpStream};
+
+/// Register numbering exposed to gdb: v0-vf, then i, pc, sp, delay and sound timers
+const REG_I: usize = REGISTER_COUNT;
+const REG_PC: usize = REGISTER_COUNT + 1;
+const REG_SP: usize = REGISTER_COUNT + 2;
+const REG_DT: usize = REGISTER_COUNT + 3;
+const REG_ST: usize = REGISTER_COUNT + 4;
+const REG_COUNT: usize = REGISTER_COUNT + 5;
+
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.bchip8.chip8">
+    <reg name="v0" bitsize="8" regnum="0"/>
+    <reg name="v1" bitsize="8"/>
+    <reg name="v2" bitsize="8"/>
+    <reg name="v3" bitsize="8"/>
+    <reg name="v4" bitsize="8"/>
+    <reg name="v5" bitsize="8"/>
+    <reg name="v6" bitsize="8"/>
+    <reg name="v7" bitsize="8"/>
+    <reg name="v8" bitsize="8"/>
+    <reg name="v9" bitsize="8"/>
+    <reg name="va" bitsize="8"/>
+    <reg name="vb" bitsize="8"/>
+    <reg name="vc" bitsize="8"/>
+    <reg name="vd" bitsize="8"/>
+    <reg name="ve" bitsize="8"/>
+    <reg name="vf" bitsize="8"/>
+    <reg name="i" bitsize="16" type="data_ptr"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+    <reg name="sp" bitsize="8"/>
+    <reg name="dt" bitsize="8"/>
+    <reg name="st" bitsize="8"/>
+  </feature>
+</target>"#;
+
+/// GDB remote serial protocol stub over a local TCP connection
+pub struct GdbStub {
+    stream: TcpStream,
+    buf: Vec<u8>,
+    /// a `c` or `s` is outstanding and gdb waits for a stop reply
+    waiting_stop: bool,
+    attached: bool,
+}
+
+impl GdbStub {
+    /// Listens on the loopback interface and blocks until gdb connects
+    pub fn listen(port: u16) -> anyhow::Result<Self> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port))?;
+        eprintln!("waiting for gdb on {}", listener.local_addr()?);
+        Self::accept(&listener)
+    }
+
+    fn accept(listener: &TcpListener) -> anyhow::Result<Self> {
+        let (stream, peer) = listener.accept()?;
+        info!("(Gdb) connected from {}", peer);
+        stream.set_nonblocking(true)?;
+        stream.set_nodelay(true)?;
+        Ok(GdbStub {
+            stream,
+            buf: Vec::new(),
+            waiting_stop: false,
+            attached: true,
+        })
+    }
+
+    fn fill_buf(&mut self) -> anyhow::Result<()> {
+        let mut chunk = [0u8; 1024];
+        loop {
+            match self.stream.read(&mut chunk) {
+                Ok(0) => {
+                    info!("(Gdb) connection closed");
+                    self.attached = false;
+                    return Ok(());
+                }
+                Ok(n) => self.buf.extend_from_slice(&chunk[..n]),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    /// Takes the next complete item off the input buffer
+    fn next_packet(&mut self) -> Option<Incoming> {
+        loop {
+            let first = *self.buf.first()?;
+            match first {
+                b'+' | b'-' => {
+                    self.buf.remove(0);
+                }
+                0x03 => {
+                    self.buf.remove(0);
+                    return Some(Incoming::Interrupt);
+                }
+                b'$' => {
+                    let end = self.buf.iter().position(|&b| b == b'#')?;
+                    if self.buf.len() < end + 3 {
+                        return None;
+                    }
+                    let body: Vec<u8> = self.buf[1..end].to_vec();
+                    let checksum = std::str::from_utf8(&self.buf[end + 1..end + 3])
+                        .ok()
+                        .and_then(|c| u8::from_str_radix(c, 16).ok());
+                    self.buf.drain(..end + 3);
+                    if checksum != Some(Self::checksum(&body)) {
+                        warn!("(Gdb) bad checksum on {}", String::from_utf8_lossy(&body));
+                        return Some(Incoming::Corrupted);
+                    }
+                    return Some(Incoming::Packet(body));
+                }
+                _ => {
+                    self.buf.remove(0);
+                }
+            }
+        }
+    }
+
+    fn checksum(data: &[u8]) -> u8 {
+        data.iter().fold(0u8, |acc, b| acc.wrapping_add(*b))
+    }
+
+    fn send_raw(&mut self, data: &[u8]) -> anyhow::Result<()> {
+        self.stream.set_nonblocking(false)?;
+        let res = self.stream.write_all(data);
+        self.stream.set_nonblocking(true)?;
+        Ok(res?)
+    }
+
+    fn send(&mut self, body: &str) -> anyhow::Result<()> {
+        let packet = format!("${}#{:02x}", body, Self::checksum(body.as_bytes()));
+        self.send_raw(packet.as_bytes())
+    }
+
+    fn send_stop(&mut self, signal: u8) -> anyhow::Result<()> {
+        self.waiting_stop = false;
+        self.send(&format!("S{:02x}", signal))
+    }
+
+    fn read_register<R: Rng>(machine: &Machine<R>, reg: usize) -> Option<Vec<u8>> {
+        Some(match reg {
+            0..REGISTER_COUNT => vec![machine.get_register(reg as u8).ok()?],
+            REG_I => machine.get_register_i().to_le_bytes().to_vec(),
+            REG_PC => (machine.pc() as u16).to_le_bytes().to_vec(),
+            REG_SP => vec![machine.stack().len() as u8],
+            REG_DT => vec![machine.delay_timer()],
+            REG_ST => vec![machine.sound_timer()],
+            _ => return None,
+        })
+    }
+
+    fn write_register<R: Rng>(
+        machine: &mut Machine<R>,
+        reg: usize,
+        val: &[u8],
+    ) -> anyhow::Result<()> {
+        let word = || -> anyhow::Result<u16> {
+            match val {
+                [lo, hi] => Ok(u16::from_le_bytes([*lo, *hi])),
+                _ => anyhow::bail!("expected 2 bytes for register {}", reg),
+            }
+        };
+        let byte = || -> anyhow::Result<u8> {
+            match val {
+                [b] => Ok(*b),
+                _ => anyhow::bail!("expected 1 byte for register {}", reg),
+            }
+        };
+        match reg {
+            0..REGISTER_COUNT => machine.set_register(reg as u8, byte()?)?,
+            REG_I => machine.set_register_i(word()?)?,
+            REG_PC => machine.set_pc(word()? as usize)?,
+            REG_SP => machine.truncate_stack(byte()? as usize)?,
+            REG_DT => machine.set_delay_timer(byte()?),
+            REG_ST => machine.set_sound_timer(byte()?),
+            _ => anyhow::bail!("invalid register {}", reg),
+        }
+        Ok(())
+    }
+
+    fn register_width(reg: usize) -> usize {
+        match reg {
+            REG_I | REG_PC => 2,
+            _ => 1,
+        }
+    }
+
+    fn handle<R: Rng>(&mut self, machine: &mut Machine<R>, packet: &str) -> anyhow::Result<()> {
+        let (cmd, args) = packet.split_at(packet.chars().next().map_or(0, |c| c.len_utf8()));
+        match cmd {
+            "?" => self.send(&format!("S{:02x}", SIGTRAP)),
+            "g" => {
+                let mut out = String::new();
+                for reg in 0..REG_COUNT {
+                    out.push_str(&to_hex(&Self::read_register(machine, reg).unwrap()));
+                }
+                self.send(&out)
+            }
+            "G" => {
+                let data = from_hex(args)?;
+                let mut offset = 0;
+                for reg in 0..REG_COUNT {
+                    let width = Self::register_width(reg);
+                    if offset + width > data.len() {
+                        break;
+                    }
+                    Self::write_register(machine, reg, &data[offset..offset + width])?;
+                    offset += width;
+                }
+                self.send("OK")
+            }
+            "p" => {
+                let reg = usize::from_str_radix(args, 16)?;
+                match Self::read_register(machine, reg) {
+                    Some(v) => self.send(&to_hex(&v)),
+                    None => self.send("E01"),
+                }
+            }
+            "P" => {
+                let (reg, val) = args.split_once('=').unwrap_or((args, ""));
+                let reg = usize::from_str_radix(reg, 16)?;
+                match Self::write_register(machine, reg, &from_hex(val)?) {
+                    Ok(_) => self.send("OK"),
+                    Err(_) => self.send("E01"),
+                }
+            }
+            "m" => {
+                let (addr, len) = parse_addr_len(args)?;
+                let data: Vec<u8> = (addr..addr.saturating_add(len))
+                    .map_while(|a| machine.peek(a))
+                    .collect();
+                if data.is_empty() && len > 0 {
+                    self.send("E01")
+                } else {
+                    self.send(&to_hex(&data))
+                }
+            }
+            "M" => {
+                let (range, data) = args.split_once(':').unwrap_or((args, ""));
+                let (addr, len) = parse_addr_len(range)?;
+                let data = from_hex(data)?;
+                if addr.checked_add(len).is_none_or(|end| end > MEMORY_SIZE) || data.len() != len {
+                    return self.send("E01");
+                }
+                for (i, b) in data.into_iter().enumerate() {
+                    machine.poke(addr + i, b)?;
+                }
+                self.send("OK")
+            }
+            "Z" | "z" => {
+                let mut parts = args.split(',');
+                let kind = parts.next().unwrap_or("");
+                let addr = usize::from_str_radix(parts.next().unwrap_or(""), 16)?;
+                if kind != "0" && kind != "1" {
+                    return self.send("");
+                }
+                if cmd == "Z" {
+                    machine.add_breakpoint(addr);
+                } else {
+                    machine.remove_breakpoint(addr);
+                }
+                self.send("OK")
+            }
+            "c" => {
+                if !args.is_empty() {
+                    machine.set_pc(usize::from_str_radix(args, 16)?)?;
+                }
+                self.resume(machine);
+                Ok(())
+            }
+            "s" => {
+                if !args.is_empty() {
+                    machine.set_pc(usize::from_str_radix(args, 16)?)?;
+                }
+                self.single_step(machine)
+            }
+            "v" => self.handle_v(machine, args),
+            "q" => self.handle_query(args),
+            "H" | "T" => self.send("OK"),
+            "k" => {
+                machine.quit();
+                self.attached = false;
+                Ok(())
+            }
+            "D" => {
+                self.send("OK")?;
+                self.attached = false;
+                Ok(())
+            }
+            _ => self.send(""),
+        }
+    }
+
+    fn handle_v<R: Rng>(&mut self, machine: &mut Machine<R>, args: &str) -> anyhow::Result<()> {
+        if args == "Cont?" {
+            return self.send("vCont;c;s");
+        }
+        match args.strip_prefix("Cont;") {
+            Some(action) if action.starts_with('c') => {
+                self.resume(machine);
+                Ok(())
+            }
+            Some(action) if action.starts_with('s') => self.single_step(machine),
+            _ => self.send(""),
+        }
+    }
+
+    fn handle_query(&mut self, args: &str) -> anyhow::Result<()> {
+        if args.starts_with("Supported") {
+            return self.send("PacketSize=1000;qXfer:features:read+");
+        }
+        if let Some(range) = args.strip_prefix("Xfer:features:read:target.xml:") {
+            let (offset, len) = parse_addr_len(range)?;
+            let xml = TARGET_XML.as_bytes();
+            let start = offset.min(xml.len());
+            let end = offset.saturating_add(len).min(xml.len());
+            let prefix = if end < xml.len() { "m" } else { "l" };
+            let chunk = String::from_utf8_lossy(&xml[start..end]);
+            return self.send(&format!("{}{}", prefix, chunk));
+        }
+        match args {
+            "Attached" => self.send("1"),
+            "C" => self.send("QC1"),
+            "fThreadInfo" => self.send("m1"),
+            "sThreadInfo" => self.send("l"),
+            "Offsets" => self.send("Text=0;Data=0;Bss=0"),
+            _ => self.send(""),
+        }
+    }
+
+    fn resume<R: Rng>(&mut self, machine: &mut Machine<R>) {
+        self.waiting_stop = true;
+        machine.resume();
+    }
+
+    fn single_step<R: Rng>(&mut self, machine: &mut Machine<R>) -> anyhow::Result<()> {
+        machine.step_once()?;
+        self.send_stop(SIGTRAP)
+    }
+}
+
+enum Incoming {
+    Packet(Vec<u8>),
+    Interrupt,
+    Corrupted,
+}
+
+impl<R> Debugger<R> for GdbStub
+where
+    R: Rng,
+{
+    fn poll(&mut self, machine: &mut Machine<R>) -> anyhow::Result<()> {
+        self.fill_buf()?;
+        while let Some(incoming) = self.next_packet() {
+            match incoming {
+                Incoming::Interrupt => {
+                    if !machine.is_paused() {
+                        machine.stop(StopReason::Interrupt)?;
+                    }
+                }
+                Incoming::Corrupted => self.send_raw(b"-")?,
+                Incoming::Packet(body) => {
+                    self.send_raw(b"+")?;
+                    let packet = String::from_utf8_lossy(&body).into_owned();
+                    if let Err(e) = self.handle(machine, &packet) {
+                        warn!("(Gdb) failed to handle {}: {}", packet, e);
+                        self.send("E01")?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn on_stop(&mut self, _machine: &mut Machine<R>, reason: StopReason) -> anyhow::Result<()> {
+        match reason {
+            StopReason::Exited => self.send("W00"),
+            StopReason::Interrupt => self.send_stop(SIGINT),
+            StopReason::Breakpoint => {
+                if self.waiting_stop {
+                    self.send_stop(SIGTRAP)
+                } else {
+                    Ok(())
+                }
+            }
+        }
+    }
+
+    fn attached(&self) -> bool {
+        self.attached
+    }
+}
+
+fn to_hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(s: &str) -> anyhow::Result<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        anyhow::bail!("odd length hex string");
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| Ok(u8::from_str_radix(&s[i..i + 2], 16)?))
+        .collect()
+}
+
+fn parse_addr_len(s: &str) -> anyhow::Result<(usize, usize)> {
+    let (addr, len) = s
+        .split_once(',')
+        .ok_or_else(|| anyhow::anyhow!("expected addr,len"))?;
+    Ok((
+        usize::from_str_radix(addr, 16)?,
+        usize::from_str_radix(len, 16)?,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::font;
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+    use std::thread;
+    use std::time::Duration;
+
+    /// v0 := 5, v0 += 1, then a jump to itself
+    const ROM: [u8; 6] = [0x60, 0x05, 0x70, 0x01, 0x12, 0x04];
+
+    struct Client(TcpStream);
+
+    impl Client {
+        /// Sends a packet and returns the reply's body
+        fn ask(&mut self, body: &str) -> String {
+            let packet = format!("${}#{:02x}", body, GdbStub::checksum(body.as_bytes()));
+            self.0.write_all(packet.as_bytes()).unwrap();
+            let mut reply = vec![];
+            let mut byte = [0u8];
+            while !reply.ends_with(b"#") {
+                self.0.read_exact(&mut byte).unwrap();
+                if reply.is_empty() && byte[0] != b'$' {
+                    continue;
+                }
+                reply.push(byte[0]);
+            }
+            let mut checksum = [0u8; 2];
+            self.0.read_exact(&mut checksum).unwrap();
+            self.0.write_all(b"+").unwrap();
+            String::from_utf8(reply[1..reply.len() - 1].to_vec()).unwrap()
+        }
+    }
+
+    #[test]
+    fn serves_gdb_over_loopback() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = thread::spawn(move || {
+            let mut gdb = Client(TcpStream::connect(addr).unwrap());
+            let regs = gdb.ask("g");
+            // v0-vf, then i and pc as little-endian words
+            assert_eq!(&regs[32..40], "00000002");
+            assert_eq!(gdb.ask("m200,6"), "600570011204");
+            assert_eq!(gdb.ask("M300,2:abcd"), "OK");
+            assert_eq!(gdb.ask("m300,2"), "abcd");
+            assert_eq!(gdb.ask("mffffffffffffffff,2"), "E01");
+            assert_eq!(gdb.ask("Mffffffffffffffff,2:abcd"), "E01");
+            assert_eq!(
+                gdb.ask("qXfer:features:read:target.xml:ffffffffffffffff,1"),
+                "l"
+            );
+
+            // a breakpoint at the entry point stops the first continue
+            assert_eq!(gdb.ask("Z0,200,2"), "OK");
+            assert_eq!(gdb.ask("c"), "S05");
+            assert_eq!(gdb.ask("p11"), "0002");
+            assert_eq!(gdb.ask("z0,200,2"), "OK");
+
+            assert_eq!(gdb.ask("s"), "S05");
+            assert_eq!(gdb.ask("p11"), "0202");
+            assert_eq!(gdb.ask("p0"), "05");
+
+            assert_eq!(gdb.ask("Z0,204,2"), "OK");
+            assert_eq!(gdb.ask("c"), "S05");
+            assert_eq!(gdb.ask("p11"), "0402");
+            assert_eq!(gdb.ask("p0"), "06");
+
+            // sp can only drop return addresses, not invent them
+            assert_eq!(gdb.ask("P12=05"), "E01");
+            gdb.0.write_all(b"$k#6b").unwrap();
+        });
+
+        let stub = GdbStub::accept(&listener).unwrap();
+        let mut machine = Machine::headless(StdRng::seed_from_u64(0), Duration::ZERO);
+        machine.set_instructions_per_frame(16);
+        machine
+            .load_font(font::FONT_ADDRESS, font::Font::default())
+            .unwrap();
+        machine.load_cartridge(0x200, &ROM).unwrap();
+        machine.attach_debugger(Box::new(stub));
+        machine.boot().unwrap();
+        client.join().unwrap();
+    }
+}
@@ -0,0 +1,354 @@
+use base64::Engine;
+use clap::ValueEnum;
+use flate2::Compression;
+use flate2::write::ZlibEncoder;
+use serde::Deserialize;
+use std::env;
+use std::fmt::Write as _;
+use std::io::Write;
+use std::time::Duration;
+
+/// Terminal image protocols used to draw the display as a bitmap
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    Kitty,
+    Sixel,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum GraphicsMode {
+    /// Use an image protocol when the terminal is known to support one
+    #[default]
+    Auto,
+    Kitty,
+    Sixel,
+    /// Always draw with text cells
+    Off,
+}
+
+impl GraphicsMode {
+    pub fn resolve(&self) -> Option<Protocol> {
+        match self {
+            GraphicsMode::Auto => detect(),
+            GraphicsMode::Kitty => Some(Protocol::Kitty),
+            GraphicsMode::Sixel => Some(Protocol::Sixel),
+            GraphicsMode::Off => None,
+        }
+    }
+}
+
+/// How long the terminal gets to answer the graphics query
+const QUERY_TIMEOUT: Duration = Duration::from_millis(200);
+/// A Kitty graphics query for a 1x1 image followed by DA1. Terminals answer in
+/// order, so the DA1 reply ends the wait whether or not Kitty was answered.
+const QUERY: &[u8] = b"\x1b_Gi=31,s=1,v=1,a=q,t=d,f=24;AAAA\x1b\\\x1b[c";
+
+/// Asks the terminal for its image protocol, falling back to the environment it
+/// exports when it doesn't answer. The terminal must be in raw mode.
+pub fn detect() -> Option<Protocol> {
+    let var = |name: &str| env::var(name).unwrap_or_default().to_lowercase();
+    if env::var_os("TMUX").is_some() || var("TERM").starts_with("screen") {
+        return None;
+    }
+    match query() {
+        Some(reply) => parse_reply(&reply),
+        None => from_env(),
+    }
+}
+
+#[cfg(unix)]
+fn query() -> Option<Vec<u8>> {
+    use std::fs::OpenOptions;
+    use std::io::Read;
+    use std::os::fd::AsRawFd;
+    use std::time::Instant;
+
+    let mut tty = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/tty")
+        .ok()?;
+    tty.write_all(QUERY).ok()?;
+    tty.flush().ok()?;
+    let deadline = Instant::now() + QUERY_TIMEOUT;
+    let mut reply = Vec::new();
+    while da1(&reply).is_none() {
+        let left = deadline.checked_duration_since(Instant::now())?;
+        let mut fd = libc::pollfd {
+            fd: tty.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        if unsafe { libc::poll(&mut fd, 1, left.as_millis() as libc::c_int) } <= 0 {
+            return None;
+        }
+        let mut buf = [0; 256];
+        let n = tty.read(&mut buf).ok()?;
+        if n == 0 {
+            return None;
+        }
+        reply.extend_from_slice(&buf[..n]);
+    }
+    Some(reply)
+}
+
+#[cfg(not(unix))]
+fn query() -> Option<Vec<u8>> {
+    None
+}
+
+/// The parameters of the DA1 reply `ESC [ ? params c` in `reply`
+fn da1(reply: &[u8]) -> Option<&[u8]> {
+    let start = reply.windows(3).position(|w| w == b"\x1b[?")? + 3;
+    let len = reply[start..].iter().position(|b| *b == b'c')?;
+    Some(&reply[start..start + len])
+}
+
+/// Kitty when the terminal accepted the graphics query, sixel when DA1 lists
+/// attribute 4
+fn parse_reply(reply: &[u8]) -> Option<Protocol> {
+    if reply.windows(9).any(|w| w == b"_Gi=31;OK") {
+        return Some(Protocol::Kitty);
+    }
+    da1(reply)?
+        .split(|b| *b == b';')
+        .any(|p| p == b"4")
+        .then_some(Protocol::Sixel)
+}
+
+/// Guesses the image protocol from the environment the terminal exports
+fn from_env() -> Option<Protocol> {
+    let var = |name: &str| env::var(name).unwrap_or_default().to_lowercase();
+    if env::var_os("KITTY_WINDOW_ID").is_some() || var("TERM").contains("kitty") {
+        return Some(Protocol::Kitty);
+    }
+    match var("TERM_PROGRAM").as_str() {
+        "ghostty" | "wezterm" => return Some(Protocol::Kitty),
+        "mlterm" | "contour" => return Some(Protocol::Sixel),
+        _ => {}
+    }
+    let term = var("TERM");
+    if term.starts_with("foot") || term.contains("sixel") || term.starts_with("mlterm") {
+        return Some(Protocol::Sixel);
+    }
+    None
+}
+
+/// An indexed image, `pixels` holds palette indices row by row
+pub struct Bitmap {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<u8>,
+    pub palette: Vec<[u8; 3]>,
+}
+
+impl Bitmap {
+    /// Scales `width`x`height` display pixels up by `scale`
+    pub fn from_pixels(
+        width: usize,
+        height: usize,
+        scale: usize,
+        palette: Vec<[u8; 3]>,
+        index: impl Fn(usize, usize) -> u8,
+    ) -> Self {
+        let mut pixels = Vec::with_capacity(width * height * scale * scale);
+        for y in 0..height * scale {
+            for x in 0..width * scale {
+                pixels.push(index(x / scale, y / scale));
+            }
+        }
+        Bitmap {
+            width: width * scale,
+            height: height * scale,
+            pixels,
+            palette,
+        }
+    }
+
+    fn rgb(&self) -> Vec<u8> {
+        self.pixels
+            .iter()
+            .flat_map(|i| self.palette[*i as usize])
+            .collect()
+    }
+}
+
+/// Kitty graphics protocol transmit-and-display of zlib compressed RGB, replacing
+/// image 1 in place. `cols`x`rows` is the cell area the image is placed into.
+pub fn encode_kitty(bitmap: &Bitmap, cols: u16, rows: u16) -> String {
+    const CHUNK: usize = 4096;
+    let mut zlib = ZlibEncoder::new(Vec::new(), Compression::fast());
+    zlib.write_all(&bitmap.rgb()).unwrap();
+    let data = base64::engine::general_purpose::STANDARD.encode(zlib.finish().unwrap());
+    let chunks: Vec<&[u8]> = data.as_bytes().chunks(CHUNK).collect();
+    let mut out = String::new();
+    for (n, chunk) in chunks.iter().enumerate() {
+        let more = (n + 1 < chunks.len()) as u8;
+        let chunk = std::str::from_utf8(chunk).unwrap();
+        if n == 0 {
+            write!(
+                out,
+                "\x1b_Ga=T,f=24,o=z,s={},v={},c={},r={},i=1,p=1,q=2,C=1,m={};{}\x1b\\",
+                bitmap.width, bitmap.height, cols, rows, more, chunk
+            )
+            .unwrap();
+        } else {
+            write!(out, "\x1b_Gm={};{}\x1b\\", more, chunk).unwrap();
+        }
+    }
+    out
+}
+
+/// Removes the image drawn by [`encode_kitty`]
+pub fn delete_kitty() -> &'static str {
+    "\x1b_Ga=d,d=I,i=1,q=2\x1b\\"
+}
+
+/// A DEC sixel image with run length encoding, every pixel is painted
+pub fn encode_sixel(bitmap: &Bitmap) -> String {
+    let mut out = String::new();
+    write!(out, "\x1bP0;1;0q\"1;1;{};{}", bitmap.width, bitmap.height).unwrap();
+    for (i, [r, g, b]) in bitmap.palette.iter().enumerate() {
+        let pct = |c: &u8| *c as u32 * 100 / 255;
+        write!(out, "#{};2;{};{};{}", i, pct(r), pct(g), pct(b)).unwrap();
+    }
+    for band in (0..bitmap.height).step_by(6) {
+        for color in 0..bitmap.palette.len() as u8 {
+            let sixels: Vec<u8> = (0..bitmap.width)
+                .map(|x| {
+                    (0..6)
+                        .filter(|dy| {
+                            let y = band + dy;
+                            y < bitmap.height && bitmap.pixels[y * bitmap.width + x] == color
+                        })
+                        .fold(0u8, |bits, dy| bits | 1 << dy)
+                })
+                .collect();
+            if sixels.iter().all(|s| *s == 0) {
+                continue;
+            }
+            write!(out, "#{}", color).unwrap();
+            let mut x = 0;
+            while x < sixels.len() {
+                let run = sixels[x..].iter().take_while(|s| **s == sixels[x]).count();
+                let ch = (0x3f + sixels[x]) as char;
+                if run > 3 {
+                    write!(out, "!{}{}", run, ch).unwrap();
+                } else {
+                    (0..run).for_each(|_| out.push(ch));
+                }
+                x += run;
+            }
+            out.push('$');
+        }
+        out.push('-');
+    }
+    out.push_str("\x1b\\");
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::ZlibDecoder;
+    use std::io::Read;
+
+    fn bitmap(width: usize, height: usize, pixels: Vec<u8>, palette: Vec<[u8; 3]>) -> Bitmap {
+        Bitmap {
+            width,
+            height,
+            pixels,
+            palette,
+        }
+    }
+
+    #[test]
+    fn sixel_paints_each_colour_with_runs() {
+        let image = bitmap(
+            6,
+            1,
+            vec![0, 1, 1, 1, 1, 1],
+            vec![[0, 0, 0], [255, 255, 255]],
+        );
+        assert_eq!(
+            encode_sixel(&image),
+            "\x1bP0;1;0q\"1;1;6;1#0;2;0;0;0#1;2;100;100;100#0@!5?$#1?!5@$-\x1b\\"
+        );
+    }
+
+    #[test]
+    fn sixel_bands_are_six_rows() {
+        let image = bitmap(1, 7, vec![1; 7], vec![[0, 0, 0], [255, 0, 0]]);
+        assert_eq!(
+            encode_sixel(&image),
+            "\x1bP0;1;0q\"1;1;1;7#0;2;0;0;0#1;2;100;0;0#1~$-#1@$-\x1b\\"
+        );
+    }
+
+    /// Splits kitty output into its escapes' keys and payloads
+    fn kitty_chunks(out: &str) -> Vec<(&str, &str)> {
+        out.split_terminator("\x1b\\")
+            .map(|escape| {
+                let escape = escape.strip_prefix("\x1b_G").unwrap();
+                escape.split_once(';').unwrap()
+            })
+            .collect()
+    }
+
+    fn inflate(payload: &str) -> Vec<u8> {
+        let zlib = base64::engine::general_purpose::STANDARD
+            .decode(payload)
+            .unwrap();
+        let mut rgb = Vec::new();
+        ZlibDecoder::new(&zlib[..]).read_to_end(&mut rgb).unwrap();
+        rgb
+    }
+
+    #[test]
+    fn kitty_sends_compressed_rgb() {
+        let image = bitmap(2, 1, vec![0, 1], vec![[1, 2, 3], [4, 5, 6]]);
+        let out = encode_kitty(&image, 10, 5);
+        let chunks = kitty_chunks(&out);
+        assert_eq!(chunks.len(), 1);
+        let (keys, payload) = chunks[0];
+        assert_eq!(keys, "a=T,f=24,o=z,s=2,v=1,c=10,r=5,i=1,p=1,q=2,C=1,m=0");
+        assert_eq!(inflate(payload), [1, 2, 3, 4, 5, 6]);
+    }
+
+    #[test]
+    fn kitty_splits_large_images_into_chunks() {
+        let palette: Vec<[u8; 3]> = (0..=255)
+            .map(|n: u8| [n, n ^ 0x5a, n.wrapping_mul(7)])
+            .collect();
+        let mut seed = 1u32;
+        let pixels = (0..128 * 128)
+            .map(|_| {
+                seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                (seed >> 16) as u8
+            })
+            .collect();
+        let image = bitmap(128, 128, pixels, palette);
+        let out = encode_kitty(&image, 10, 5);
+        let chunks = kitty_chunks(&out);
+        assert!(chunks.len() > 2);
+        assert!(chunks[0].0.ends_with(",m=1"));
+        for (keys, payload) in &chunks[1..chunks.len() - 1] {
+            assert_eq!(*keys, "m=1");
+            assert_eq!(payload.len(), 4096);
+        }
+        assert_eq!(chunks.last().unwrap().0, "m=0");
+        let payload: String = chunks.iter().map(|(_, p)| *p).collect();
+        assert_eq!(inflate(&payload), image.rgb());
+    }
+
+    #[test]
+    fn replies_pick_the_protocol() {
+        let kitty = b"\x1b_Gi=31;OK\x1b\\\x1b[?62;22c";
+        assert_eq!(parse_reply(kitty), Some(Protocol::Kitty));
+        assert_eq!(parse_reply(b"\x1b[?62;4;22c"), Some(Protocol::Sixel));
+        assert_eq!(parse_reply(b"\x1b[?1;2c"), None);
+        assert_eq!(parse_reply(b"\x1b[?64;44c"), None);
+        assert_eq!(da1(b"\x1b[?62;4"), None);
+    }
+}
@@ -0,0 +1,194 @@
+use crate::machine::MEMORY_SIZE;
+use std::fs;
+use std::io::{self, Write};
+use std::ops::Range;
+use std::path;
+
+/// Width of the exported heatmap image, one pixel per byte of memory
+pub const IMAGE_WIDTH: usize = 64;
+pub const IMAGE_HEIGHT: usize = MEMORY_SIZE / IMAGE_WIDTH;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Region {
+    Unused,
+    Code,
+    Data,
+    SelfModified,
+}
+
+impl Region {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Region::Unused => "unused",
+            Region::Code => "code",
+            Region::Data => "data",
+            Region::SelfModified => "self_modified",
+        }
+    }
+
+    pub fn rgb(&self) -> [u8; 3] {
+        match self {
+            Region::Unused => [0x20, 0x20, 0x20],
+            Region::Code => [0x40, 0x80, 0xff],
+            Region::Data => [0xff, 0xd0, 0x40],
+            Region::SelfModified => [0xff, 0x40, 0x40],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Access {
+    pub reads: u32,
+    pub writes: u32,
+    pub execs: u32,
+    /// pc of the instruction which wrote this byte and has not been executed since
+    pub pending_write: Option<u16>,
+}
+
+impl Access {
+    pub fn region(&self) -> Region {
+        if self.execs > 0 && self.writes > 0 {
+            Region::SelfModified
+        } else if self.execs > 0 {
+            Region::Code
+        } else if self.reads > 0 || self.writes > 0 {
+            Region::Data
+        } else {
+            Region::Unused
+        }
+    }
+
+    pub fn total(&self) -> u32 {
+        self.reads
+            .saturating_add(self.writes)
+            .saturating_add(self.execs)
+    }
+}
+
+pub struct Heatmap {
+    access: Box<[Access; MEMORY_SIZE]>,
+}
+
+impl Heatmap {
+    pub fn new() -> Self {
+        Heatmap {
+            access: Box::new([Access::default(); MEMORY_SIZE]),
+        }
+    }
+
+    pub fn get(&self, addr: usize) -> Access {
+        self.access[addr]
+    }
+
+    pub fn record_read(&mut self, addr: usize) {
+        let a = &mut self.access[addr];
+        a.reads = a.reads.saturating_add(1);
+    }
+
+    pub fn record_write(&mut self, addr: usize, pc: usize) {
+        let a = &mut self.access[addr];
+        a.writes = a.writes.saturating_add(1);
+        a.pending_write = Some(pc as u16);
+    }
+
+    /// Records an execution and returns the pc of the instruction that wrote
+    /// this byte, if it was modified since it was last executed
+    pub fn record_exec(&mut self, addr: usize) -> Option<u16> {
+        let a = &mut self.access[addr];
+        a.execs = a.execs.saturating_add(1);
+        a.pending_write.take()
+    }
+
+    /// Records the execution of the instruction in `bytes` and returns the pc
+    /// of the last instruction that wrote any of them since they were last
+    /// executed, so a modified instruction is reported once
+    pub fn record_instruction(&mut self, bytes: Range<usize>) -> Option<u16> {
+        bytes.fold(None, |writer, addr| self.record_exec(addr).or(writer))
+    }
+
+    pub fn export(&self, path: &path::Path) -> anyhow::Result<()> {
+        let mut file = io::BufWriter::new(fs::File::create(path)?);
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("csv") => self.write_csv(&mut file)?,
+            _ => self.write_ppm(&mut file)?,
+        }
+        file.flush()?;
+        Ok(())
+    }
+
+    fn write_csv(&self, w: &mut impl Write) -> io::Result<()> {
+        writeln!(w, "address,reads,writes,executions,region")?;
+        for (addr, a) in self.access.iter().enumerate() {
+            writeln!(
+                w,
+                "{:#05x},{},{},{},{}",
+                addr,
+                a.reads,
+                a.writes,
+                a.execs,
+                a.region().name()
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Binary PPM, one pixel per byte, coloured by region and shaded by access count
+    fn write_ppm(&self, w: &mut impl Write) -> io::Result<()> {
+        write!(w, "P6\n{} {}\n255\n", IMAGE_WIDTH, IMAGE_HEIGHT)?;
+        let max = self.access.iter().map(|a| a.total()).max().unwrap_or(0);
+        for a in self.access.iter() {
+            let shade = Self::shade(a.total(), max);
+            let rgb = a.region().rgb().map(|c| (c as u32 * shade / 255) as u8);
+            w.write_all(&rgb)?;
+        }
+        Ok(())
+    }
+
+    /// Logarithmic brightness in 64..=255 so rarely touched bytes stay visible
+    pub fn shade(count: u32, max: u32) -> u32 {
+        if count == 0 || max == 0 {
+            return 255;
+        }
+        let ratio = (count as f64).ln_1p() / (max as f64).ln_1p();
+        64 + (ratio * 191.0) as u32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accesses_are_counted_by_region() {
+        let mut heatmap = Heatmap::new();
+        heatmap.record_read(0x300);
+        heatmap.record_write(0x301, 0x200);
+        heatmap.record_exec(0x200);
+        heatmap.record_exec(0x200);
+
+        assert_eq!(heatmap.get(0x300).reads, 1);
+        assert_eq!(heatmap.get(0x300).region(), Region::Data);
+        assert_eq!(heatmap.get(0x301).writes, 1);
+        assert_eq!(heatmap.get(0x301).region(), Region::Data);
+        assert_eq!(heatmap.get(0x200).execs, 2);
+        assert_eq!(heatmap.get(0x200).region(), Region::Code);
+        assert_eq!(heatmap.get(0x400).region(), Region::Unused);
+    }
+
+    #[test]
+    fn a_modified_instruction_is_reported_once() {
+        let mut heatmap = Heatmap::new();
+        assert_eq!(heatmap.record_instruction(0x200..0x202), None);
+
+        // both bytes written by the instruction at 0x210
+        heatmap.record_write(0x200, 0x210);
+        heatmap.record_write(0x201, 0x210);
+        assert_eq!(heatmap.record_instruction(0x200..0x202), Some(0x210));
+        assert_eq!(heatmap.get(0x200).region(), Region::SelfModified);
+        // executing it again without a new write reports nothing
+        assert_eq!(heatmap.record_instruction(0x200..0x202), None);
+
+        heatmap.record_write(0x201, 0x214);
+        assert_eq!(heatmap.record_instruction(0x200..0x202), Some(0x214));
+    }
+}
@@ -0,0 +1,139 @@
+use crate::theme::{self, Theme};
+use ratatui::{
+    buffer::Buffer,
+    layout::{Constraint, Layout, Rect},
+    style::{Style, Stylize},
+    text::{Line, Span},
+    widgets::{Paragraph, Widget},
+};
+use std::time::{Duration, Instant};
+
+/// Rows taken by the status bar, one per keypad row
+pub const HEIGHT: u16 = 4;
+
+const KEYPAD: [[u8; 4]; 4] = [
+    [0x1, 0x2, 0x3, 0xc],
+    [0x4, 0x5, 0x6, 0xd],
+    [0x7, 0x8, 0x9, 0xe],
+    [0xa, 0x0, 0xb, 0xf],
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RunState {
+    Running,
+    /// stopped by a debugger
+    Paused,
+    /// blocked in `GetKey`
+    WaitingForKey,
+}
+
+/// Machine status shown below the display
+#[derive(Debug, Clone)]
+pub struct Hud {
+    pub rom: String,
+    /// platform the quirks match, or `custom`
+    pub quirks: String,
+    pub state: RunState,
+    pub ips: f64,
+    pub fps: f64,
+    pub delay_timer: u8,
+    pub sound_timer: u8,
+    pub keys: [bool; 16],
+    /// what the ROM's keys do, from the ROM database
+    pub controls: String,
+}
+
+/// Events per second, averaged over about a second
+pub struct Meter {
+    count: u64,
+    since: Instant,
+    rate: f64,
+}
+
+impl Default for Meter {
+    fn default() -> Self {
+        Meter {
+            count: 0,
+            since: Instant::now(),
+            rate: 0.0,
+        }
+    }
+}
+
+impl Meter {
+    pub fn add(&mut self, n: u64) {
+        self.count += n;
+        let elapsed = self.since.elapsed();
+        if elapsed >= Duration::from_secs(1) {
+            self.rate = self.count as f64 / elapsed.as_secs_f64();
+            self.count = 0;
+            self.since = Instant::now();
+        }
+    }
+
+    pub fn rate(&self) -> f64 {
+        self.rate
+    }
+}
+
+pub struct HudView<'a> {
+    hud: &'a Hud,
+    theme: Theme,
+    hints: &'a str,
+}
+
+impl<'a> HudView<'a> {
+    pub fn new(hud: &'a Hud, theme: Theme, hints: &'a str) -> Self {
+        HudView { hud, theme, hints }
+    }
+}
+
+impl<'a> Widget for HudView<'a> {
+    fn render(self, area: Rect, buf: &mut Buffer) {
+        let style = Style::default()
+            .fg(self.theme.color(theme::PLANE_1))
+            .bg(self.theme.color(theme::BACKGROUND));
+        let [status, keypad] =
+            Layout::horizontal([Constraint::Min(0), Constraint::Length(8)]).areas(area);
+
+        let hud = self.hud;
+        let state = match hud.state {
+            RunState::Running => Span::raw("running"),
+            RunState::Paused => Span::raw("paused").bold(),
+            RunState::WaitingForKey => Span::raw("waiting for key").bold().reversed(),
+        };
+        let lines = vec![
+            Line::from(vec![
+                Span::raw(format!("{}  {}  ", hud.rom, hud.quirks)),
+                state,
+            ]),
+            Line::raw(format!("{:.0} ips  {:.0} fps", hud.ips, hud.fps)),
+            Line::raw(format!(
+                "DT {:02X}  ST {:02X}  {}",
+                hud.delay_timer, hud.sound_timer, hud.controls
+            )),
+            Line::raw(self.hints).dim(),
+        ];
+        Paragraph::new(lines).style(style).render(status, buf);
+
+        let rows: Vec<Line> = KEYPAD
+            .iter()
+            .map(|row| {
+                Line::from(
+                    row.iter()
+                        .flat_map(|k| {
+                            let key = Span::raw(format!("{:X}", k));
+                            let key = if hud.keys[*k as usize] {
+                                key.reversed()
+                            } else {
+                                key
+                            };
+                            [Span::raw(" "), key]
+                        })
+                        .collect::<Vec<_>>(),
+                )
+            })
+            .collect();
+        Paragraph::new(rows).style(style).render(keypad, buf);
+    }
+}
@@ -0,0 +1,101 @@
+//! ROM facts printed by the `info` command.
+
+use crate::analysis::Analysis;
+use crate::cartridge;
+use crate::memory_map::MemoryMap;
+use crate::quirks::Platform;
+use crate::romdb;
+use std::collections::HashMap;
+use std::fmt;
+
+pub struct Info {
+    pub size: usize,
+    pub sha1: String,
+    pub platform: Platform,
+    /// the ROM database entry, when the hash is known
+    pub known: Option<romdb::Match>,
+    pub analysis: Analysis,
+    /// the platform's program area, and whether the ROM fits
+    pub program: std::ops::Range<usize>,
+    pub fit: Result<Vec<String>, String>,
+    /// opcode patterns and how often they occur, most frequent first
+    pub opcodes: Vec<(&'static str, usize)>,
+}
+
+impl Info {
+    pub fn new(rom: &[u8], db: &romdb::RomDb) -> Self {
+        let mut counts: HashMap<&'static str, usize> = HashMap::new();
+        for d in cartridge::disassemble_cartridge(rom) {
+            *counts.entry(d.operation().pattern()).or_default() += 1;
+        }
+        let mut opcodes: Vec<_> = counts.into_iter().collect();
+        opcodes.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+        let sha1 = cartridge::sha1_hex(rom);
+        let known = db.lookup(&sha1);
+        let listed = known.as_ref().and_then(|k| k.platform);
+        let analysis = Analysis::new(
+            rom,
+            MemoryMap::new(listed.unwrap_or_default()).program.start,
+        );
+        let platform = listed.unwrap_or(analysis.platform);
+        let map = MemoryMap::new(platform);
+        Info {
+            size: rom.len(),
+            platform,
+            fit: map
+                .check_load(map.program.start, rom.len())
+                .map_err(|e| e.to_string()),
+            program: map.program,
+            sha1,
+            known,
+            analysis,
+            opcodes,
+        }
+    }
+}
+
+impl fmt::Display for Info {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let start = self.program.start;
+        let end = start + self.size.max(1) - 1;
+        writeln!(
+            f,
+            "size      {} bytes ({:#05x}-{:#05x})",
+            self.size, start, end
+        )?;
+        writeln!(f, "sha1      {}", self.sha1)?;
+        match &self.known {
+            Some(known) => {
+                writeln!(f, "title     {}", known.name())?;
+                writeln!(f, "platform  {} (from the ROM database)", self.platform)?;
+                if !known.keys.is_empty() {
+                    writeln!(f, "keys      {}", known.key_hints())?;
+                }
+            }
+            None => writeln!(f, "platform  {} (guessed from the code)", self.platform)?,
+        }
+        match &self.fit {
+            Ok(warnings) => {
+                let free = self.program.len() - self.size;
+                writeln!(f, "memory    fits, {} bytes to spare", free)?;
+                for warning in warnings {
+                    writeln!(f, "  warning: {}", warning)?;
+                }
+            }
+            Err(e) => writeln!(f, "memory    too large: {}", e)?,
+        }
+        write!(f, "{}", self.analysis)?;
+        writeln!(f, "opcodes   (every word, data included)")?;
+        let total: usize = self.opcodes.iter().map(|(_, n)| n).sum();
+        for (pattern, n) in &self.opcodes {
+            writeln!(
+                f,
+                "  {}  {:>5}  {:>5.1}%",
+                pattern,
+                n,
+                *n as f64 * 100.0 / total as f64
+            )?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,330 @@
+//! Keyboard layouts for the keypad and emulator hotkeys.
+//!
+//! A keymap file is TOML. `preset` picks the base layout, `[keys]` rebinds keypad
+//! keys and `[hotkeys]` rebinds emulator actions, each to one key or a list of
+//! keys. `[rom."name.ch8"]` tables override any of these for one ROM:
+//!
+//! ```toml
+//! preset = "azerty"
+//!
+//! [keys]
+//! 5 = ["z", "up"]
+//!
+//! [hotkeys]
+//! quit = "f10"
+//!
+//! [rom."tetris.ch8"]
+//! keys = { 4 = "left", 6 = "right" }
+//! ```
+
+use crate::console::Key;
+use clap::ValueEnum;
+use crossterm::event::KeyCode;
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::Path;
+
+/// Keypad keys in the order of the original 4x4 pad, row by row
+const PAD: [u8; 16] = [
+    0x1, 0x2, 0x3, 0xc, 0x4, 0x5, 0x6, 0xd, 0x7, 0x8, 0x9, 0xe, 0xa, 0x0, 0xb, 0xf,
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Layout {
+    /// 1234 / QWER / ASDF / ZXCV
+    #[default]
+    Qwerty,
+    /// the same keys by position on AZERTY, digits also work unshifted
+    Azerty,
+    Dvorak,
+    Colemak,
+    /// 789/ 456* 123- 0.Enter+ on the numeric keypad
+    Numpad,
+}
+
+impl Layout {
+    /// Rows of the pad in [`PAD`] order, alternatives for one key joined by `|`
+    fn rows(&self) -> [&'static str; 4] {
+        match self {
+            Layout::Qwerty => ["1 2 3 4", "q w e r", "a s d f", "z x c v"],
+            Layout::Azerty => ["1|& 2|é 3|\" 4|'", "a z e r", "q s d f", "w x c v"],
+            Layout::Dvorak => ["1 2 3 4", "' , . p", "a o e u", "; q j k"],
+            Layout::Colemak => ["1 2 3 4", "q w f p", "a r s t", "z x c v"],
+            Layout::Numpad => ["7 8 9 /", "4 5 6 *", "1 2 3 -", "0 . enter +"],
+        }
+    }
+}
+
+/// Hotkey names and their defaults
+const HOTKEYS: [(&str, Key, &str); 10] = [
+    ("quit", Key::Quit, "esc"),
+    ("pause", Key::Pause, "f5"),
+    ("reset", Key::Reset, "f6"),
+    ("hard_reset", Key::HardReset, "f7"),
+    ("hud", Key::Hud, "f1"),
+    ("heatmap", Key::Heatmap, "f2"),
+    ("record", Key::Record, "f9"),
+    ("screenshot", Key::Screenshot, "f12"),
+    ("save_state", Key::SaveState, "f3"),
+    ("load_state", Key::LoadState, "f4"),
+];
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum OneOrMany {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl OneOrMany {
+    fn names(&self) -> Vec<&str> {
+        match self {
+            OneOrMany::One(name) => vec![name],
+            OneOrMany::Many(names) => names.iter().map(|n| n.as_str()).collect(),
+        }
+    }
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct Bindings {
+    preset: Option<Layout>,
+    #[serde(default)]
+    keys: BTreeMap<String, OneOrMany>,
+    #[serde(default)]
+    hotkeys: BTreeMap<String, OneOrMany>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct KeymapFile {
+    preset: Option<Layout>,
+    #[serde(default)]
+    keys: BTreeMap<String, OneOrMany>,
+    #[serde(default)]
+    hotkeys: BTreeMap<String, OneOrMany>,
+    #[serde(default)]
+    rom: BTreeMap<String, Bindings>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Keymap {
+    keys: HashMap<KeyCode, Key>,
+    hotkeys: Vec<(Key, Vec<KeyCode>)>,
+}
+
+impl Default for Keymap {
+    fn default() -> Self {
+        Keymap::preset(Layout::default())
+    }
+}
+
+impl Keymap {
+    pub fn preset(layout: Layout) -> Self {
+        Keymap::from_bindings(layout, &[]).unwrap()
+    }
+
+    /// Loads a keymap file, applying the overrides for `rom` when it has any.
+    /// `layout` is used unless the file names a preset.
+    pub fn load(path: &Path, layout: Layout, rom: &str) -> anyhow::Result<Self> {
+        let text = fs::read_to_string(path)?;
+        let mut file: KeymapFile =
+            toml::from_str(&text).map_err(|e| anyhow::anyhow!("{}: {}", path.display(), e))?;
+        let top = Bindings {
+            preset: file.preset,
+            keys: file.keys,
+            hotkeys: file.hotkeys,
+        };
+        let mut bindings = vec![&top];
+        let rom = file.rom.remove(rom);
+        bindings.extend(rom.as_ref());
+        let layout = bindings
+            .iter()
+            .rev()
+            .find_map(|b| b.preset)
+            .unwrap_or(layout);
+        Keymap::from_bindings(layout, &bindings)
+            .map_err(|e| anyhow::anyhow!("{}: {}", path.display(), e))
+    }
+
+    /// Starts from `layout` and the default hotkeys, later bindings replace the keys
+    /// of whatever they name
+    fn from_bindings(layout: Layout, bindings: &[&Bindings]) -> anyhow::Result<Self> {
+        let mut pad: BTreeMap<u8, Vec<KeyCode>> = BTreeMap::new();
+        let names = layout.rows().into_iter().flat_map(|row| row.split(' '));
+        for (k, names) in PAD.iter().zip(names) {
+            let codes = names.split('|').map(parse_key);
+            pad.insert(*k, codes.collect::<Result<_, _>>()?);
+        }
+        let mut hotkeys: Vec<(Key, Vec<KeyCode>)> = HOTKEYS
+            .iter()
+            .map(|(_, key, default)| Ok((*key, vec![parse_key(default)?])))
+            .collect::<anyhow::Result<_>>()?;
+
+        for b in bindings {
+            for (k, names) in &b.keys {
+                let k = u8::from_str_radix(k, 16)
+                    .ok()
+                    .filter(|k| *k < 16)
+                    .ok_or_else(|| anyhow::anyhow!("unknown keypad key {}", k))?;
+                let codes: Vec<KeyCode> = names
+                    .names()
+                    .into_iter()
+                    .map(parse_key)
+                    .collect::<Result<_, _>>()?;
+                // a rebound key leaves whatever keypad key it was on before
+                for other in pad.values_mut() {
+                    other.retain(|code| !codes.contains(code));
+                }
+                pad.insert(k, codes);
+            }
+            for (name, names) in &b.hotkeys {
+                let Some((_, key, _)) = HOTKEYS.iter().find(|(n, _, _)| n == name) else {
+                    let known: Vec<&str> = HOTKEYS.iter().map(|(n, _, _)| *n).collect();
+                    anyhow::bail!(
+                        "unknown hotkey {}, expected one of {}",
+                        name,
+                        known.join(", ")
+                    );
+                };
+                let codes = names.names().into_iter().map(parse_key);
+                let codes = codes.collect::<Result<_, _>>()?;
+                hotkeys.iter_mut().find(|(k, _)| k == key).unwrap().1 = codes;
+            }
+        }
+
+        let mut keys = HashMap::new();
+        for (k, codes) in &pad {
+            for code in codes {
+                keys.insert(*code, Key::Num(*k));
+            }
+        }
+        // hotkeys win over keypad keys bound to the same key
+        for (key, codes) in &hotkeys {
+            for code in codes {
+                keys.insert(*code, *key);
+            }
+        }
+        Ok(Keymap { keys, hotkeys })
+    }
+
+    pub fn lookup(&self, code: KeyCode) -> Option<Key> {
+        let code = match code {
+            KeyCode::Char(c) => KeyCode::Char(c.to_lowercase().next().unwrap_or(c)),
+            code => code,
+        };
+        self.keys.get(&code).copied()
+    }
+
+    /// Name of the first key bound to hotkey `key`
+    pub fn describe(&self, key: Key) -> Option<String> {
+        let (_, codes) = self.hotkeys.iter().find(|(k, _)| *k == key)?;
+        codes.first().map(|code| match code {
+            KeyCode::Char(c) => c.to_string(),
+            code => code.to_string(),
+        })
+    }
+}
+
+/// Parses a key name: a single character, `f1`-`f12`, or a named key such as
+/// `esc`, `enter`, `space`, `tab`, `backspace`, `up` or `pageup`
+pub fn parse_key(name: &str) -> anyhow::Result<KeyCode> {
+    let mut chars = name.chars();
+    if let (Some(c), None) = (chars.next(), chars.next()) {
+        return Ok(KeyCode::Char(c.to_lowercase().next().unwrap_or(c)));
+    }
+    let lower = name.to_lowercase();
+    if let Some(n) = lower.strip_prefix('f').and_then(|n| n.parse::<u8>().ok()) {
+        return Ok(KeyCode::F(n));
+    }
+    Ok(match lower.as_str() {
+        "esc" | "escape" => KeyCode::Esc,
+        "enter" | "return" => KeyCode::Enter,
+        "space" => KeyCode::Char(' '),
+        "tab" => KeyCode::Tab,
+        "backspace" => KeyCode::Backspace,
+        "delete" | "del" => KeyCode::Delete,
+        "insert" | "ins" => KeyCode::Insert,
+        "home" => KeyCode::Home,
+        "end" => KeyCode::End,
+        "pageup" => KeyCode::PageUp,
+        "pagedown" => KeyCode::PageDown,
+        "up" => KeyCode::Up,
+        "down" => KeyCode::Down,
+        "left" => KeyCode::Left,
+        "right" => KeyCode::Right,
+        _ => anyhow::bail!("unknown key {}", name),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(toml: &str, rom: &str) -> Keymap {
+        let path =
+            std::env::temp_dir().join(format!("bchip8-keymap-{}-{}.toml", std::process::id(), rom));
+        fs::write(&path, toml).unwrap();
+        let keymap = Keymap::load(&path, Layout::Qwerty, rom);
+        fs::remove_file(&path).unwrap();
+        keymap.unwrap()
+    }
+
+    fn char(c: char) -> KeyCode {
+        KeyCode::Char(c)
+    }
+
+    #[test]
+    fn presets_follow_the_pad_layout() {
+        let qwerty = Keymap::preset(Layout::Qwerty);
+        assert_eq!(qwerty.lookup(char('1')), Some(Key::Num(0x1)));
+        assert_eq!(qwerty.lookup(char('W')), Some(Key::Num(0x5)));
+        assert_eq!(qwerty.lookup(char('v')), Some(Key::Num(0xf)));
+        assert_eq!(qwerty.lookup(KeyCode::Esc), Some(Key::Quit));
+
+        let azerty = Keymap::preset(Layout::Azerty);
+        assert_eq!(azerty.lookup(char('z')), Some(Key::Num(0x5)));
+        assert_eq!(azerty.lookup(char('é')), Some(Key::Num(0x2)));
+
+        let numpad = Keymap::preset(Layout::Numpad);
+        assert_eq!(numpad.lookup(KeyCode::Enter), Some(Key::Num(0xb)));
+    }
+
+    #[test]
+    fn a_key_can_have_several_bindings() {
+        let keymap = load(
+            "[keys]\n5 = [\"w\", \"up\"]\n[hotkeys]\nquit = [\"f10\", \"q\"]\n",
+            "a",
+        );
+        assert_eq!(keymap.lookup(char('w')), Some(Key::Num(0x5)));
+        assert_eq!(keymap.lookup(KeyCode::Up), Some(Key::Num(0x5)));
+        assert_eq!(keymap.lookup(KeyCode::F(10)), Some(Key::Quit));
+        assert_eq!(keymap.lookup(char('q')), Some(Key::Quit));
+        assert_eq!(keymap.lookup(KeyCode::Esc), None);
+        assert_eq!(keymap.describe(Key::Quit).as_deref(), Some("F10"));
+    }
+
+    #[test]
+    fn rom_tables_override_the_top_level() {
+        let toml = "[keys]\n4 = \"left\"\n[rom.\"tetris.ch8\"]\npreset = \"numpad\"\nkeys = { 4 = \"a\" }\n";
+        let other = load(toml, "other.ch8");
+        assert_eq!(other.lookup(KeyCode::Left), Some(Key::Num(0x4)));
+        assert_eq!(other.lookup(char('q')), None);
+
+        let tetris = load(toml, "tetris.ch8");
+        assert_eq!(tetris.lookup(char('a')), Some(Key::Num(0x4)));
+        assert_eq!(tetris.lookup(KeyCode::Left), None);
+        assert_eq!(tetris.lookup(char('8')), Some(Key::Num(0x2)));
+    }
+
+    #[test]
+    fn a_rebound_key_leaves_its_preset_key() {
+        let keymap = load("[keys]\n4 = \"w\"\n", "b");
+        assert_eq!(keymap.lookup(char('w')), Some(Key::Num(0x4)));
+        assert_eq!(keymap.lookup(char('q')), None);
+        // key 5 loses its only key rather than taking w back
+        assert!(!keymap.keys.values().any(|k| *k == Key::Num(0x5)));
+    }
+}
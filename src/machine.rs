@@ -1,12 +1,36 @@
+use std::collections::BTreeSet;
+use std::mem;
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
 use std::thread;
 use std::time::Duration;
 use std::time::Instant;
 
+use crate::cartridge::{self, Watched};
 use crate::console;
 use crate::console::Console;
 use crate::console::Key;
 use crate::console::KeyEvent;
+use crate::console::KeyRelease;
+use crate::debugger::{Debugger, StopReason};
+use crate::filter::{Filter, FilterMode};
+use crate::font::Font;
+use crate::graphics::GraphicsMode;
+use crate::heatmap::Heatmap;
+use crate::hud::{Hud, Meter, RunState};
+use crate::keymap::Keymap;
+use crate::memory_map::MemoryMap;
+use crate::movie::Movie;
 use crate::opcode;
+use crate::patch::{self, Patch};
+use crate::quirks::{Platform, Quirks};
+use crate::recorder::Recorder;
+use crate::render::RenderMode;
+use crate::screenshot;
+use crate::script::{self, ScriptHost};
+use crate::symbols::SymbolMap;
+use crate::theme::Theme;
+use crate::vip::{self, Vip};
 use log::{Level, info, log_enabled, trace, warn};
 use rand::Rng;
 
@@ -15,6 +39,8 @@ pub const REGISTER_COUNT: usize = 0x10;
 pub const DISPLAY_WIDTH: usize = 64;
 pub const DISPLAY_HEIGHT: usize = 32;
 pub const TICK_RATE: Duration = Duration::from_millis(16);
+/// Frames between checks of a watched cartridge file
+const WATCH_FRAMES: u128 = 30;
 
 const SPRITE_MASK: [u8; 8] = [
     1 << 7,
@@ -34,17 +60,66 @@ enum GetKeyState {
     Released(u8),
 }
 
+/// The program's state kept by the save state hotkey
+struct SavedState {
+    memory: Vec<u8>,
+    registers: [u8; REGISTER_COUNT],
+    register_i: u16,
+    pc: usize,
+    stack: Vec<usize>,
+    delay_timer: u8,
+    sound_timer: u8,
+    display_buffer: [[bool; DISPLAY_WIDTH]; DISPLAY_HEIGHT],
+}
+
 pub struct Machine<R>
 where
     R: Rng,
 {
     running: bool,
+    paused: bool,
+    debugger: Option<Box<dyn Debugger<R>>>,
+    breakpoints: BTreeSet<usize>,
+    /// stopped at the breakpoint at pc, so resuming runs its instruction
+    at_breakpoint: bool,
+    symbols: SymbolMap,
+    script: Option<ScriptHost>,
+    theme: Theme,
+    screenshot: screenshot::Settings,
+    recorder: Option<Recorder>,
+    movie: Option<Movie>,
+    frame_limit: Option<u128>,
     cycle: Duration,
+    /// run this many instructions per frame as fast as possible instead of in real time
+    instructions_per_frame: Option<u64>,
+    frame_instructions: u64,
+    instructions: u64,
+    /// the platform named in the config, which the HUD shows
+    platform: Platform,
+    quirks: Quirks,
     display_buffer: [[bool; DISPLAY_WIDTH]; DISPLAY_HEIGHT],
-    console: Console,
+    console: Option<Console>,
     cartridge_address: usize,
     font_address: usize,
+    cartridge: Vec<u8>,
+    font: Font,
+    memory_map: MemoryMap,
+    /// runs `0NNN` machine code calls when set
+    vip: Option<Vip>,
+    cartridge_path: Option<PathBuf>,
+    patches: Vec<Patch>,
+    watch: Option<Watched>,
+    preserve: Vec<RangeInclusive<usize>>,
     display_buffer_dirty: bool,
+    filter: Filter,
+    show_hud: bool,
+    rom_name: String,
+    controls: String,
+    ips: Meter,
+    fps: Meter,
+    show_heatmap: bool,
+    heatmap: Heatmap,
+    saved_state: Option<SavedState>,
     key_state: [bool; 16],
     get_key_state: GetKeyState,
     rng: R,
@@ -54,7 +129,8 @@ where
     register_pool: [u8; REGISTER_COUNT],
     delay_timer: u8,
     sound_timer: u8,
-    memory: [u8; MEMORY_SIZE],
+    /// always MEMORY_SIZE bytes, a Vec so a script can borrow it without a copy
+    memory: Vec<u8>,
     stack: Vec<usize>,
     pc: usize,
 }
@@ -65,14 +141,56 @@ where
 {
     pub fn new(rng: R, cycle: Duration) -> anyhow::Result<Self> {
         let console = console::init()?;
-        Ok(Machine {
+        Ok(Self::with_console(rng, cycle, Some(console)))
+    }
+
+    /// A machine without a terminal, input only arrives through a debugger
+    pub fn headless(rng: R, cycle: Duration) -> Self {
+        Self::with_console(rng, cycle, None)
+    }
+
+    fn with_console(rng: R, cycle: Duration, console: Option<Console>) -> Self {
+        Machine {
             running: false,
+            paused: false,
+            debugger: None,
+            breakpoints: BTreeSet::new(),
+            at_breakpoint: false,
+            symbols: SymbolMap::default(),
+            script: None,
+            theme: Theme::default(),
+            screenshot: screenshot::Settings::default(),
+            recorder: None,
+            movie: None,
+            frame_limit: None,
             cycle,
+            instructions_per_frame: None,
+            frame_instructions: 0,
+            instructions: 0,
+            platform: Platform::default(),
+            quirks: Quirks::default(),
             display_buffer: [[false; DISPLAY_WIDTH]; DISPLAY_HEIGHT],
             console,
             cartridge_address: 0x0,
             font_address: 0x0,
+            cartridge: vec![],
+            font: Font::default(),
+            memory_map: MemoryMap::default(),
+            vip: None,
+            cartridge_path: None,
+            patches: vec![],
+            watch: None,
+            preserve: vec![],
             display_buffer_dirty: false,
+            filter: Filter::new(FilterMode::Off),
+            show_hud: false,
+            rom_name: String::new(),
+            controls: String::new(),
+            ips: Meter::default(),
+            fps: Meter::default(),
+            show_heatmap: false,
+            heatmap: Heatmap::new(),
+            saved_state: None,
             key_state: [false; 16],
             get_key_state: GetKeyState::None,
             rng,
@@ -82,10 +200,10 @@ where
             register_pool: [0u8; REGISTER_COUNT],
             delay_timer: 0x0,
             sound_timer: 0x0,
-            memory: [0; MEMORY_SIZE],
+            memory: vec![0; MEMORY_SIZE],
             stack: Vec::new(),
             pc: 0x0,
-        })
+        }
     }
 
     pub fn boot(&mut self) -> anyhow::Result<()> {
@@ -94,14 +212,29 @@ where
         self.running = true;
         let mut cycle_at = Instant::now();
         while self.running {
+            self.poll_debugger()?;
+            let fast = self.instructions_per_frame.is_some();
+            let mut stepped = false;
             match self.get_key_state {
+                _ if self.paused && !fast => thread::sleep(self.cycle),
+                _ if self.paused => {}
+                GetKeyState::None | GetKeyState::Released(_)
+                    if self.breakpoints.contains(&self.pc) && !self.at_breakpoint =>
+                {
+                    self.at_breakpoint = true;
+                    self.stop(StopReason::Breakpoint)?;
+                }
                 GetKeyState::None | GetKeyState::Released(_) => {
                     let last_cycle_elapsed = cycle_at.elapsed();
-                    if last_cycle_elapsed <= self.cycle {
+                    if !fast && last_cycle_elapsed <= self.cycle {
                         thread::sleep(self.cycle - last_cycle_elapsed);
                     }
                     cycle_at = Instant::now();
                     self.step()?;
+                    stepped = true;
+                    self.instructions += 1;
+                    self.frame_instructions += 1;
+                    self.ips.add(1);
                 }
                 _ => {}
             };
@@ -109,7 +242,14 @@ where
             self.handle_key_events()?;
             self.display()?;
 
-            if self.tick_at.elapsed() >= TICK_RATE {
+            let frame_done = match self.instructions_per_frame {
+                // a machine that can't step still lets frames pass, so timers and
+                // movie input keep going
+                Some(n) => self.frame_instructions >= n || !stepped,
+                None => self.tick_at.elapsed() >= TICK_RATE,
+            };
+            if frame_done {
+                self.frame_instructions = 0;
                 self.tick()?;
             }
         }
@@ -119,7 +259,207 @@ where
     }
 
     fn on_halt(&mut self) {
-        self.console.restore();
+        if let Err(e) = self.stop(StopReason::Exited) {
+            log::error!("err in notifying debugger {}", e);
+        }
+        if let Err(e) = self.stop_recording() {
+            log::error!("err in finishing recording {}", e);
+        }
+        if let Some(console) = self.console.as_mut() {
+            console.restore();
+        }
+    }
+
+    /// Attaches a debugger, the machine stays paused until the debugger resumes it
+    pub fn attach_debugger(&mut self, debugger: Box<dyn Debugger<R>>) {
+        self.debugger = Some(debugger);
+        self.paused = true;
+    }
+
+    fn poll_debugger(&mut self) -> anyhow::Result<()> {
+        if let Some(mut debugger) = self.debugger.take() {
+            let res = debugger.poll(self);
+            self.reattach_debugger(debugger);
+            res?;
+        }
+        Ok(())
+    }
+
+    fn reattach_debugger(&mut self, debugger: Box<dyn Debugger<R>>) {
+        if debugger.attached() {
+            self.debugger = Some(debugger);
+        } else {
+            info!("debugger detached");
+            self.breakpoints.clear();
+            self.paused = false;
+        }
+    }
+
+    /// Pauses execution and tells the attached debugger why
+    pub fn stop(&mut self, reason: StopReason) -> anyhow::Result<()> {
+        self.paused = true;
+        if let Some(mut debugger) = self.debugger.take() {
+            let res = debugger.on_stop(self, reason);
+            self.reattach_debugger(debugger);
+            res?;
+        }
+        Ok(())
+    }
+
+    pub fn resume(&mut self) {
+        self.paused = false;
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    pub fn quit(&mut self) {
+        self.running = false;
+    }
+
+    /// Executes a single instruction regardless of the paused state
+    pub fn step_once(&mut self) -> anyhow::Result<()> {
+        self.step()
+    }
+
+    pub fn add_breakpoint(&mut self, addr: usize) {
+        self.breakpoints.insert(addr);
+    }
+
+    pub fn remove_breakpoint(&mut self, addr: usize) {
+        self.breakpoints.remove(&addr);
+    }
+
+    pub fn clear_breakpoints(&mut self) {
+        self.breakpoints.clear();
+    }
+
+    pub fn set_render_mode(&mut self, render_mode: RenderMode) {
+        if let Some(console) = self.console.as_mut() {
+            console.set_render_mode(render_mode);
+        }
+    }
+
+    pub fn set_hud(&mut self, show: bool, rom_name: &str) {
+        self.show_hud = show;
+        self.rom_name = rom_name.to_string();
+    }
+
+    /// What the ROM's keypad keys do, shown in the status bar
+    pub fn set_controls(&mut self, controls: String) {
+        self.controls = controls;
+    }
+
+    pub fn set_key_release(&mut self, mode: KeyRelease, hold: Duration) {
+        if let Some(console) = self.console.as_mut() {
+            console.set_key_release(mode, hold);
+        }
+    }
+
+    pub fn set_keymap(&mut self, keymap: Keymap) {
+        if let Some(console) = self.console.as_mut() {
+            console.set_keymap(keymap);
+        }
+    }
+
+    pub fn set_filter(&mut self, mode: FilterMode) {
+        self.filter = Filter::new(mode);
+    }
+
+    pub fn set_screenshot_settings(&mut self, settings: screenshot::Settings) {
+        self.screenshot = settings;
+    }
+
+    /// Saves the display at the configured scale, the format follows the extension
+    pub fn save_screenshot(&self, path: &Path) -> anyhow::Result<()> {
+        screenshot::save(
+            path,
+            &self.display_buffer,
+            self.screenshot.scale,
+            &self.theme,
+        )
+    }
+
+    /// Records the display once per frame until stopped or the machine halts
+    pub fn start_recording(&mut self, path: &Path) -> anyhow::Result<()> {
+        self.stop_recording()?;
+        let recorder = Recorder::start(path, self.screenshot.scale, self.theme)?;
+        info!("(Record) start {}", path.display());
+        self.recorder = Some(recorder);
+        Ok(())
+    }
+
+    pub fn stop_recording(&mut self) -> anyhow::Result<()> {
+        if let Some(recorder) = self.recorder.take() {
+            recorder.finish(self.tick_cnt)?;
+            info!("(Record) stop");
+        }
+        Ok(())
+    }
+
+    /// Feeds keypad input from a movie file
+    pub fn set_movie(&mut self, movie: Movie) {
+        self.movie = Some(movie);
+    }
+
+    /// Runs `n` instructions per 60 Hz frame without waiting for real time, for
+    /// tests and benchmarks
+    pub fn set_instructions_per_frame(&mut self, n: u64) {
+        self.instructions_per_frame = Some(n.max(1));
+    }
+
+    /// Runs with `quirks`, which start as those of `platform`
+    pub fn set_quirks(&mut self, platform: Platform, quirks: Quirks) {
+        self.platform = platform;
+        self.quirks = quirks;
+    }
+
+    /// Halts after `frames` 60 Hz frames
+    pub fn set_frame_limit(&mut self, frames: u128) {
+        self.frame_limit = Some(frames);
+    }
+
+    pub fn set_theme(&mut self, theme: Theme) {
+        self.theme = theme;
+        if let Some(console) = self.console.as_mut() {
+            console.set_theme(theme);
+        }
+    }
+
+    /// Picks the image protocol, which `auto` asks the terminal about
+    pub fn set_graphics(&mut self, graphics: GraphicsMode) {
+        if let Some(console) = self.console.as_mut() {
+            console.set_graphics(graphics.resolve());
+        }
+    }
+
+    pub fn set_symbols(&mut self, symbols: SymbolMap) {
+        self.symbols = symbols;
+    }
+
+    pub fn symbols(&self) -> &SymbolMap {
+        &self.symbols
+    }
+
+    /// Runs the script's top level and `init`, then keeps it for the hooks
+    pub fn attach_script(&mut self, mut script: ScriptHost) -> anyhow::Result<()> {
+        script.init(self)?;
+        self.script = Some(script);
+        Ok(())
+    }
+
+    fn call_script(&mut self, hook: &str, args: impl rhai::FuncArgs) -> anyhow::Result<()> {
+        if let Some(mut script) = self.script.take() {
+            let res = script.call(self, hook, args);
+            self.script = Some(script);
+            res?;
+        }
+        Ok(())
+    }
+
+    pub fn heatmap(&self) -> &Heatmap {
+        &self.heatmap
     }
 
     pub fn trace_machine(&self) -> anyhow::Result<()> {
@@ -134,6 +474,11 @@ where
         }
         buf.push('\n');
         buf.push_str(&format!("  <i> {:0>2x}\n", self.get_register_i()));
+        buf.push_str("  <s> ");
+        for ret in self.stack.iter().rev() {
+            buf.push_str(&format!("[{}] ", self.symbols.describe(*ret)));
+        }
+        buf.push('\n');
         buf.push_str("  <k> ");
         for k in 0..=0xF {
             let stat: u8 = if self.key_state[k] { 1 } else { 0 };
@@ -163,26 +508,183 @@ where
     }
 
     pub fn load(&mut self, address: usize, data: &[u8]) -> anyhow::Result<()> {
-        if address + data.len() >= MEMORY_SIZE {
-            anyhow::bail!("memory overflow");
+        if address + data.len() > MEMORY_SIZE {
+            anyhow::bail!(
+                "{} bytes at {:#05x} run past the end of memory",
+                data.len(),
+                address
+            );
         }
         let sl = &mut self.memory[address..address + data.len()];
         sl.copy_from_slice(data);
         Ok(())
     }
 
-    pub fn load_font(&mut self, address: usize, font: &[u8]) -> anyhow::Result<()> {
-        self.load(address, font)?;
-        self.font_address = address;
+    /// Loads the font at `address`, or with machine code enabled keeps it in the
+    /// VIP's digit ROM, clear of the interpreter image
+    pub fn load_font(&mut self, address: usize, font: Font) -> anyhow::Result<()> {
+        if self.vip.is_some() {
+            self.font_address = vip::DIGITS;
+        } else {
+            self.load(address, font.bytes())?;
+            self.font_address = address;
+        }
+        self.font = font;
         Ok(())
     }
 
+    /// Where `addr` falls in the digit ROM, which only exists with machine code
+    /// enabled
+    fn digit_rom(&self, addr: usize) -> Option<usize> {
+        self.vip.as_ref()?;
+        addr.checked_sub(vip::DIGITS)
+            .filter(|&offset| offset < self.font.bytes().len())
+    }
+
+    /// Loads the cartridge after checking it against the memory map, logging
+    /// the areas it overwrites
     pub fn load_cartridge(&mut self, address: usize, cart: &[u8]) -> anyhow::Result<()> {
+        for warning in self.memory_map.check_load(address, cart.len())? {
+            warn!("(Load) the cartridge {}", warning);
+        }
         self.load(address, cart)?;
         self.cartridge_address = address;
+        self.cartridge = cart.to_vec();
+        Ok(())
+    }
+
+    /// Runs `0NNN` calls on an emulated CDP1802 instead of skipping them, with
+    /// the VIP's interpreter image loaded at 0x000. Call it before `load_font`,
+    /// which then leaves the image alone.
+    pub fn enable_machine_code(&mut self, vip: Vip) -> anyhow::Result<()> {
+        self.load(0, &vip.interpreter)?;
+        self.vip = Some(vip);
+        Ok(())
+    }
+
+    pub fn set_memory_map(&mut self, memory_map: MemoryMap) {
+        self.memory_map = memory_map;
+    }
+
+    /// The file a hard reset reads the cartridge from again
+    pub fn set_cartridge_path(&mut self, path: &Path) {
+        self.cartridge_path = Some(path.to_path_buf());
+    }
+
+    /// Patches applied again whenever the cartridge file is re-read
+    pub fn set_patches(&mut self, patches: Vec<Patch>) {
+        self.patches = patches;
+    }
+
+    /// Hard resets whenever the cartridge file changes, keeping the bytes in `preserve`
+    pub fn watch_cartridge(&mut self, path: &Path, preserve: Vec<RangeInclusive<usize>>) {
+        self.set_cartridge_path(path);
+        self.watch = Some(Watched::new(path));
+        self.preserve = preserve;
+    }
+
+    /// Restarts the program: registers, timers, stack, keys and display are cleared
+    /// while memory is kept
+    pub fn soft_reset(&mut self) {
+        self.pc = self.cartridge_address;
+        self.register_pool = [0; REGISTER_COUNT];
+        self.register_i = 0;
+        self.delay_timer = 0;
+        self.sound_timer = 0;
+        self.stack.clear();
+        self.key_state = [false; 16];
+        self.get_key_state = GetKeyState::None;
+        self.clear_display();
+        self.display_buffer_dirty = true;
+        info!("(Reset) soft");
+    }
+
+    /// Clears memory and loads the font and cartridge again, re-reading the
+    /// cartridge file when there is one, then soft resets. A file that can't be
+    /// read, patched or loaded leaves the machine running the old cartridge.
+    pub fn hard_reset(&mut self) -> anyhow::Result<()> {
+        if let Some(path) = &self.cartridge_path {
+            let cartridge = patch::apply_all(&self.patches, cartridge::load_cartridge(path)?)?;
+            self.memory_map
+                .check_load(self.cartridge_address, cartridge.len())?;
+            self.cartridge = cartridge;
+        }
+        let (font, cartridge) = (mem::take(&mut self.font), mem::take(&mut self.cartridge));
+        self.memory.fill(0);
+        self.heatmap = Heatmap::new();
+        if let Some(vip) = &self.vip {
+            self.memory[..vip.interpreter.len()].copy_from_slice(&vip.interpreter);
+        }
+        self.load_font(self.font_address, font)?;
+        self.load_cartridge(self.cartridge_address, &cartridge)?;
+        self.soft_reset();
+        info!("(Reset) hard");
+        Ok(())
+    }
+
+    /// Keeps the program's state in memory, replacing the one saved before
+    pub fn save_state(&mut self) {
+        self.saved_state = Some(SavedState {
+            memory: self.memory.clone(),
+            registers: self.register_pool,
+            register_i: self.register_i,
+            pc: self.pc,
+            stack: self.stack.clone(),
+            delay_timer: self.delay_timer,
+            sound_timer: self.sound_timer,
+            display_buffer: self.display_buffer,
+        });
+        info!("(State) saved at {:0>4x}", self.pc);
+    }
+
+    /// Returns to the saved state, a key wait in progress starts over
+    pub fn load_state(&mut self) {
+        let Some(state) = &self.saved_state else {
+            warn!("(State) nothing saved");
+            return;
+        };
+        self.memory.copy_from_slice(&state.memory);
+        self.register_pool = state.registers;
+        self.register_i = state.register_i;
+        self.pc = state.pc;
+        self.stack.clone_from(&state.stack);
+        self.delay_timer = state.delay_timer;
+        self.sound_timer = state.sound_timer;
+        self.display_buffer = state.display_buffer;
+        self.get_key_state = GetKeyState::None;
+        self.at_breakpoint = false;
+        self.display_buffer_dirty = true;
+        info!("(State) loaded at {:0>4x}", self.pc);
+    }
+
+    /// Hard reset after the watched cartridge changed on disk
+    fn reload(&mut self) -> anyhow::Result<()> {
+        let saved: Vec<(usize, Vec<u8>)> = self
+            .preserve
+            .iter()
+            .map(|r| (*r.start(), self.memory[r.clone()].to_vec()))
+            .collect();
+        self.hard_reset()?;
+        for (start, bytes) in saved {
+            self.memory[start..start + bytes.len()].copy_from_slice(&bytes);
+        }
         Ok(())
     }
 
+    fn poll_watch(&mut self) {
+        let Some(watch) = self.watch.as_mut() else {
+            return;
+        };
+        if !self.tick_cnt.is_multiple_of(WATCH_FRAMES) || !watch.changed() {
+            return;
+        }
+        let path = watch.path().display().to_string();
+        match self.reload() {
+            Ok(()) => info!("(Watch) reloaded {}", path),
+            Err(e) => warn!("(Watch) failed to reload {}: {}", path, e),
+        }
+    }
+
     fn clear_display(&mut self) {
         for y in 0..DISPLAY_HEIGHT {
             for x in 0..DISPLAY_WIDTH {
@@ -209,18 +711,24 @@ where
     fn draw(&mut self, x: usize, y: usize, height: u8) -> anyhow::Result<()> {
         let x = x % DISPLAY_WIDTH;
         let y = y % DISPLAY_HEIGHT;
-        let mut i_addr = self.get_register_i();
+        let i_addr = self.get_register_i() as usize;
         self.clr_vf();
         for yi in 0..height as usize {
-            let srow_map = self.get_memory(i_addr as usize)?;
-            let ye = y + yi;
+            let srow_map = self.get_memory(i_addr + yi)?;
+            let mut ye = y + yi;
             if ye >= DISPLAY_HEIGHT {
-                break;
+                if self.quirks.clip {
+                    break;
+                }
+                ye %= DISPLAY_HEIGHT;
             }
             for (xi, sprite_mask) in SPRITE_MASK.iter().enumerate() {
-                let xe = x + xi;
+                let mut xe = x + xi;
                 if xe >= DISPLAY_WIDTH {
-                    break;
+                    if self.quirks.clip {
+                        break;
+                    }
+                    xe %= DISPLAY_WIDTH;
                 }
                 let cur_dis = self.display_buffer[ye][xe];
                 let sprite_dis = (srow_map & sprite_mask) != 0;
@@ -233,7 +741,6 @@ where
                     }
                 }
             }
-            i_addr += 1;
         }
         Ok(())
     }
@@ -244,58 +751,168 @@ where
     }
 
     fn tick(&mut self) -> anyhow::Result<()> {
+        if let Some(recorder) = self.recorder.as_mut() {
+            recorder.capture(&self.display_buffer, self.tick_cnt)?;
+        }
+        self.call_script(script::ON_FRAME_END, (self.tick_cnt as rhai::INT,))?;
         self.tick_at = Instant::now();
         self.tick_cnt += 1;
+        if self.frame_limit.is_some_and(|limit| self.tick_cnt >= limit) {
+            self.running = false;
+        }
+        let events = match self.movie.as_mut() {
+            Some(movie) => movie.events_until(self.tick_cnt),
+            None => vec![],
+        };
+        for event in events {
+            match event.key_event() {
+                Some(ke) => self.input(ke),
+                None => self.running = false,
+            }
+        }
         self.on_tick()?;
+        self.call_script(script::ON_FRAME_START, (self.tick_cnt as rhai::INT,))?;
         Ok(())
     }
 
     fn on_tick(&mut self) -> anyhow::Result<()> {
         self.update_delay_timer();
         self.update_sound_timer();
+        self.poll_watch();
+        if self.show_heatmap || (self.show_hud && self.filter.immediate()) {
+            self.display_buffer_dirty = true;
+        } else if !self.filter.immediate() {
+            let changed = self
+                .filter
+                .end_frame(&self.display_buffer, self.display_buffer_dirty);
+            if changed || self.show_hud {
+                self.render()?;
+            }
+        }
         Ok(())
     }
 
     fn handle_key_events(&mut self) -> anyhow::Result<()> {
-        let key_events = self.console.get_key_events(self.next_tick_left())?;
+        let timeout = self.next_tick_left();
+        let Some(console) = self.console.as_mut() else {
+            return Ok(());
+        };
+        let key_events = console.get_key_events(timeout)?;
         if !key_events.is_empty() {
             info!("(KeyEvents): {:?}", key_events);
         }
         for ke in key_events {
-            match ke {
-                KeyEvent::Pressed(k) => match k {
-                    Key::Quit => self.running = false,
-                    Key::Num(n) => {
-                        if matches!(self.get_key_state, GetKeyState::Paused) {
-                            self.get_key_state = GetKeyState::Pressed(n);
-                        }
-                        self.key_state[n as usize] = true;
+            self.input(ke);
+        }
+        Ok(())
+    }
+
+    /// Applies a key event as if it came from the console
+    pub fn input(&mut self, ke: KeyEvent) {
+        match ke {
+            KeyEvent::Pressed(k) => match k {
+                Key::Quit => self.running = false,
+                Key::Pause if self.debugger.is_none() => {
+                    self.paused = !self.paused;
+                    self.display_buffer_dirty = true;
+                }
+                Key::Pause => {}
+                Key::Reset => self.soft_reset(),
+                Key::HardReset => {
+                    if let Err(e) = self.hard_reset() {
+                        warn!("(Reset) {}", e);
+                    }
+                }
+                Key::Hud => {
+                    self.show_hud = !self.show_hud;
+                    self.display_buffer_dirty = true;
+                }
+                Key::Heatmap => {
+                    self.show_heatmap = !self.show_heatmap;
+                    self.display_buffer_dirty = true;
+                }
+                Key::Screenshot => {
+                    let path = self.screenshot.path(self.tick_cnt);
+                    match self.save_screenshot(&path) {
+                        Ok(()) => info!("(Screenshot) {}", path.display()),
+                        Err(e) => warn!("(Screenshot) {}: {}", path.display(), e),
                     }
-                },
-                KeyEvent::Released(k) => {
-                    if let Key::Num(n) = k {
-                        if let GetKeyState::Pressed(k) = self.get_key_state
-                            && k == n
-                        {
-                            self.get_key_state = GetKeyState::Released(k);
+                }
+                Key::Record => {
+                    let result = match self.recorder {
+                        Some(_) => self.stop_recording(),
+                        None => {
+                            self.start_recording(&self.screenshot.recording_path(self.tick_cnt))
                         }
-                        self.key_state[n as usize] = false;
+                    };
+                    if let Err(e) = result {
+                        warn!("(Record) {}", e);
                     }
                 }
+                Key::SaveState => self.save_state(),
+                Key::LoadState => self.load_state(),
+                Key::Num(n) => {
+                    if matches!(self.get_key_state, GetKeyState::Paused) {
+                        self.get_key_state = GetKeyState::Pressed(n);
+                    }
+                    self.key_state[n as usize] = true;
+                }
+            },
+            KeyEvent::Released(k) => {
+                if let Key::Num(n) = k {
+                    if let GetKeyState::Pressed(k) = self.get_key_state
+                        && k == n
+                    {
+                        self.get_key_state = GetKeyState::Released(k);
+                    }
+                    self.key_state[n as usize] = false;
+                }
             }
         }
-        Ok(())
     }
 
     fn display(&mut self) -> anyhow::Result<()> {
-        if self.display_buffer_dirty {
-            self.trace_display();
-            self.console.draw(&self.display_buffer)?;
-            self.display_buffer_dirty = false;
+        if self.display_buffer_dirty && (self.show_heatmap || self.filter.immediate()) {
+            self.render()?;
+        }
+        Ok(())
+    }
+
+    fn render(&mut self) -> anyhow::Result<()> {
+        self.trace_display();
+        self.fps.add(1);
+        let hud = self.show_hud.then(|| self.hud());
+        if let Some(console) = self.console.as_mut() {
+            if self.show_heatmap {
+                console.draw_heatmap(&self.heatmap)?;
+            } else {
+                console.draw(&self.filter.intensity(&self.display_buffer), hud.as_ref())?;
+            }
         }
+        self.display_buffer_dirty = false;
         Ok(())
     }
 
+    /// Status shown in the console's status bar
+    pub fn hud(&self) -> Hud {
+        let state = match self.get_key_state {
+            _ if self.paused => RunState::Paused,
+            GetKeyState::Paused | GetKeyState::Pressed(_) => RunState::WaitingForKey,
+            GetKeyState::None | GetKeyState::Released(_) => RunState::Running,
+        };
+        Hud {
+            rom: self.rom_name.clone(),
+            quirks: self.quirks.describe(self.platform),
+            state,
+            ips: self.ips.rate(),
+            fps: self.fps.rate(),
+            delay_timer: self.delay_timer,
+            sound_timer: self.sound_timer,
+            keys: self.key_state,
+            controls: self.controls.clone(),
+        }
+    }
+
     fn advance_pc(&mut self, op_distance: usize) -> anyhow::Result<()> {
         let distance = op_distance * 2;
         if self.pc + distance >= MEMORY_SIZE {
@@ -310,7 +927,77 @@ where
         Ok(())
     }
 
-    fn set_pc(&mut self, pc: usize) -> anyhow::Result<()> {
+    /// Instructions executed since boot
+    pub fn instructions(&self) -> u64 {
+        self.instructions
+    }
+
+    pub fn display_buffer(&self) -> &[[bool; DISPLAY_WIDTH]; DISPLAY_HEIGHT] {
+        &self.display_buffer
+    }
+
+    pub fn frame(&self) -> u128 {
+        self.tick_cnt
+    }
+
+    /// Swaps memory with `memory`, which lends it to a script hook and takes
+    /// it back without copying
+    pub fn swap_memory(&mut self, memory: &mut Vec<u8>) {
+        mem::swap(&mut self.memory, memory);
+    }
+
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
+
+    pub fn stack(&self) -> &[usize] {
+        &self.stack
+    }
+
+    /// Drops return addresses off the stack, it can't grow this way
+    pub fn truncate_stack(&mut self, len: usize) -> anyhow::Result<()> {
+        if len > self.stack.len() {
+            anyhow::bail!(
+                "the stack holds {} addresses, can't set sp to {}",
+                self.stack.len(),
+                len
+            );
+        }
+        self.stack.truncate(len);
+        Ok(())
+    }
+
+    pub fn delay_timer(&self) -> u8 {
+        self.delay_timer
+    }
+
+    pub fn set_delay_timer(&mut self, val: u8) {
+        self.delay_timer = val;
+    }
+
+    pub fn sound_timer(&self) -> u8 {
+        self.sound_timer
+    }
+
+    pub fn set_sound_timer(&mut self, val: u8) {
+        self.sound_timer = val;
+    }
+
+    /// Reads memory without recording the access
+    pub fn peek(&self, addr: usize) -> Option<u8> {
+        self.memory.get(addr).copied()
+    }
+
+    /// Writes memory without recording the access
+    pub fn poke(&mut self, addr: usize, data: u8) -> anyhow::Result<()> {
+        if addr >= MEMORY_SIZE {
+            anyhow::bail!("memory overflow");
+        }
+        self.memory[addr] = data;
+        Ok(())
+    }
+
+    pub fn set_pc(&mut self, pc: usize) -> anyhow::Result<()> {
         if pc >= MEMORY_SIZE {
             anyhow::bail!("pc overflow");
         }
@@ -318,7 +1005,7 @@ where
         Ok(())
     }
 
-    fn set_register(&mut self, reg_id: u8, val: u8) -> anyhow::Result<()> {
+    pub fn set_register(&mut self, reg_id: u8, val: u8) -> anyhow::Result<()> {
         let reg_id: usize = reg_id as usize;
         if reg_id >= REGISTER_COUNT {
             anyhow::bail!("invalid general register id {}", reg_id);
@@ -335,7 +1022,7 @@ where
         self.set_register(0xF, 0).unwrap();
     }
 
-    fn get_register(&self, reg_id: u8) -> anyhow::Result<u8> {
+    pub fn get_register(&self, reg_id: u8) -> anyhow::Result<u8> {
         let reg_id: usize = reg_id as usize;
         if reg_id >= REGISTER_COUNT {
             anyhow::bail!("invalid general register id {}", reg_id);
@@ -343,44 +1030,60 @@ where
         Ok(self.register_pool[reg_id])
     }
 
-    fn get_register_i(&self) -> u16 {
+    pub fn get_register_i(&self) -> u16 {
         self.register_i
     }
 
-    fn set_register_i(&mut self, val: u16) -> anyhow::Result<()> {
-        if val >= (MEMORY_SIZE as u16) {
+    pub fn set_register_i(&mut self, val: u16) -> anyhow::Result<()> {
+        if val >= (MEMORY_SIZE as u16) && self.digit_rom(val as usize).is_none() {
             anyhow::bail!("reg i over flow {:0>4x}", val);
         }
         self.register_i = val;
         Ok(())
     }
 
-    fn get_memory(&self, addr: usize) -> anyhow::Result<u8> {
+    fn get_memory(&mut self, addr: usize) -> anyhow::Result<u8> {
+        if let Some(offset) = self.digit_rom(addr) {
+            return Ok(self.font.bytes()[offset]);
+        }
         if addr >= MEMORY_SIZE {
             anyhow::bail!("memory overflow");
         }
+        self.heatmap.record_read(addr);
         Ok(self.memory[addr])
     }
 
     fn set_memory(&mut self, addr: usize, data: u8) -> anyhow::Result<()> {
+        if self.digit_rom(addr).is_some() {
+            anyhow::bail!("{:#06x} is in the VIP's digit ROM", addr);
+        }
         if addr >= MEMORY_SIZE {
             anyhow::bail!("memory overflow");
         }
+        self.heatmap.record_write(addr, self.pc);
         self.memory[addr] = data;
-        Ok(())
+        self.call_script(
+            script::ON_MEMORY_WRITE,
+            (addr as rhai::INT, data as rhai::INT),
+        )
     }
 
-    fn get_opcode(&self, addr: usize) -> anyhow::Result<u16> {
+    fn get_opcode(&mut self, addr: usize) -> anyhow::Result<u16> {
+        if addr + 1 >= MEMORY_SIZE {
+            anyhow::bail!("memory overflow");
+        }
+        if let Some(writer) = self.heatmap.record_instruction(addr..addr + 2) {
+            warn!(
+                "(SelfModified)[pc|{:x}] executing [{:x}] written by [{:x}]",
+                self.pc, addr, writer
+            );
+        }
         Ok(u16::from_be_bytes([
-            self.get_memory(addr)?,
-            self.get_memory(addr + 1)?,
+            self.memory[addr],
+            self.memory[addr + 1],
         ]))
     }
 
-    fn get_operation(&mut self) -> anyhow::Result<opcode::Operation> {
-        Ok(opcode::parse_opcode(self.get_opcode(self.pc)?))
-    }
-
     fn next_tick_left(&self) -> Duration {
         let elapsed = self.tick_at.elapsed();
         if TICK_RATE >= elapsed {
@@ -391,18 +1094,44 @@ where
     }
 
     fn step(&mut self) -> anyhow::Result<()> {
-        let operation = self.get_operation()?;
+        self.at_breakpoint = false;
+        if self.script.as_ref().is_some_and(|s| s.watches(self.pc)) {
+            self.call_script(script::ON_EXEC, (self.pc as rhai::INT,))?;
+        }
+        let opcode = self.get_opcode(self.pc)?;
+        let operation = opcode::parse_opcode(opcode);
         trace!(
-            "[{:x}] {:x}: {}",
-            self.pc,
-            self.get_opcode(self.pc)?,
-            &operation
+            "[{}] {:x}: {}",
+            self.symbols.describe(self.pc),
+            opcode,
+            operation.labeled(&self.symbols)
         );
         use opcode::Operation::*;
         match operation.clone() {
-            CallSysC(_) => {
-                self.advance()?;
-            }
+            CallSysC(c) => match self.vip.as_mut() {
+                Some(vip) => {
+                    vip::store(&mut self.memory, &self.register_pool, &self.display_buffer);
+                    let (pc, i) = vip.call(
+                        &mut self.memory,
+                        &self.key_state,
+                        c,
+                        (self.pc + 2) as u16,
+                        self.register_i,
+                    )?;
+                    vip::restore(
+                        &self.memory,
+                        &mut self.register_pool,
+                        &mut self.display_buffer,
+                    );
+                    self.display_buffer_dirty = true;
+                    self.set_register_i(i)?;
+                    self.set_pc(pc as usize)?;
+                }
+                None => {
+                    warn!("(CallSys) skipped {:#05x}, machine code isn't enabled", c);
+                    self.advance()?;
+                }
+            },
             Clear => {
                 self.clear_display();
                 self.advance()?;
@@ -454,17 +1183,23 @@ where
             }
             Or(x, y) => {
                 self.set_register(x, self.get_register(x)? | self.get_register(y)?)?;
-                self.clr_vf();
+                if self.quirks.logic_vf_reset {
+                    self.clr_vf();
+                }
                 self.advance()?;
             }
             And(x, y) => {
                 self.set_register(x, self.get_register(x)? & self.get_register(y)?)?;
-                self.clr_vf();
+                if self.quirks.logic_vf_reset {
+                    self.clr_vf();
+                }
                 self.advance()?;
             }
             Xor(x, y) => {
                 self.set_register(x, self.get_register(x)? ^ self.get_register(y)?)?;
-                self.clr_vf();
+                if self.quirks.logic_vf_reset {
+                    self.clr_vf();
+                }
                 self.advance()?;
             }
             Add(x, y) => {
@@ -493,7 +1228,7 @@ where
                 self.advance()?;
             }
             Shr(x, y) => {
-                let yv = self.get_register(y)?;
+                let yv = self.get_register(if self.quirks.shift_vy { y } else { x })?;
                 self.set_register(x, yv.unbounded_shr(1))?;
                 if yv & 1 == 1 {
                     self.set_vf();
@@ -515,7 +1250,7 @@ where
                 self.advance()?;
             }
             Shl(x, y) => {
-                let yv = self.get_register(y)?;
+                let yv = self.get_register(if self.quirks.shift_vy { y } else { x })?;
                 self.set_register(x, yv.unbounded_shl(1))?;
                 if yv & 0x80 == 0x80 {
                     self.set_vf();
@@ -536,7 +1271,12 @@ where
                 self.advance()?;
             }
             JumpV0C(c) => {
-                let entry = self.get_register(0)? as u16;
+                let reg = if self.quirks.jump_vx {
+                    (c >> 8) as u8
+                } else {
+                    0
+                };
+                let entry = self.get_register(reg)? as u16;
                 match entry.checked_add(c) {
                     Some(addr) => self.set_pc(addr as usize)?,
                     None => anyhow::bail!("jumpV0C address overflow"),
@@ -552,6 +1292,11 @@ where
                 let y = self.get_register(y)?;
                 self.draw(x as usize, y as usize, c)?;
                 self.advance()?;
+                let collision = self.get_register(0xF)? == 1;
+                self.call_script(
+                    script::ON_DRAW,
+                    (x as rhai::INT, y as rhai::INT, c as rhai::INT, collision),
+                )?;
             }
             SkipEqKey(x) => {
                 let key = self.get_register(x)?;
@@ -581,6 +1326,7 @@ where
                 GetKeyState::None => {
                     info!("(GetKey::None) machine -> paused");
                     self.get_key_state = GetKeyState::Paused;
+                    self.call_script(script::ON_KEY_WAIT, (x as rhai::INT,))?;
                 }
                 GetKeyState::Released(key) => {
                     info!("(GetKey::Released({:0>2x})), machine -> none", key);
@@ -604,8 +1350,8 @@ where
             }
             SetIFont(x) => {
                 let xv = self.get_register(x)?;
-                let offset: usize = (xv as usize).checked_mul(self.font_address).unwrap();
-                self.set_register_i(self.font_address.checked_add(offset).unwrap() as u16)?;
+                let address = self.font_address + self.font.offset(xv);
+                self.set_register_i(address as u16)?;
                 self.advance()?;
             }
             Bcd(x) => {
@@ -625,16 +1371,21 @@ where
                     self.set_memory(iaddr, self.get_register(xi)?)?;
                     iaddr += 1;
                 }
-                self.set_register_i(iaddr as u16)?;
+                if self.quirks.load_store_increment {
+                    self.set_register_i(iaddr as u16)?;
+                }
                 self.advance()?;
             }
             Restore(x) => {
                 let mut iaddr = self.get_register_i() as usize;
                 for xi in 0..=x {
-                    self.set_register(xi, self.get_memory(iaddr)?)?;
+                    let v = self.get_memory(iaddr)?;
+                    self.set_register(xi, v)?;
                     iaddr += 1;
                 }
-                self.set_register_i(iaddr as u16)?;
+                if self.quirks.load_store_increment {
+                    self.set_register_i(iaddr as u16)?;
+                }
                 self.advance()?;
             }
             Unknown(c) => {
@@ -648,3 +1399,113 @@ where
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::CARTRIDGE_ADDRESS;
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+    use std::fs;
+
+    #[test]
+    fn load_state_returns_to_the_saved_state() {
+        let mut machine = Machine::headless(StdRng::seed_from_u64(0), Duration::ZERO);
+        machine
+            .load_cartridge(CARTRIDGE_ADDRESS, &[0x60, 0x05])
+            .unwrap();
+        machine.set_pc(CARTRIDGE_ADDRESS).unwrap();
+        machine.set_register(3, 7).unwrap();
+        machine.set_register_i(0x300).unwrap();
+        machine.stack.push(0x204);
+        machine.display_buffer[1][2] = true;
+        machine.save_state();
+
+        machine.step().unwrap();
+        machine.poke(0x200, 0).unwrap();
+        machine.set_register(3, 9).unwrap();
+        machine.stack.clear();
+        machine.clear_display();
+        machine.load_state();
+
+        assert_eq!(machine.pc(), CARTRIDGE_ADDRESS);
+        assert_eq!(machine.peek(0x200), Some(0x60));
+        assert_eq!(machine.get_register(3).unwrap(), 7);
+        assert_eq!(machine.get_register_i(), 0x300);
+        assert_eq!(machine.stack(), [0x204]);
+        assert!(machine.display_buffer[1][2]);
+    }
+
+    #[test]
+    fn hard_reset_keeps_the_cartridge_when_the_new_one_does_not_fit() {
+        let path = std::env::temp_dir().join(format!("bchip8-reset-{}.ch8", std::process::id()));
+        fs::write(&path, [0x60, 0x05]).unwrap();
+        let mut machine = Machine::headless(StdRng::seed_from_u64(0), Duration::ZERO);
+        machine
+            .load_cartridge(CARTRIDGE_ADDRESS, &[0x60, 0x05])
+            .unwrap();
+        machine.set_cartridge_path(&path);
+        machine.set_register(3, 7).unwrap();
+
+        fs::write(&path, vec![0x12; MEMORY_SIZE]).unwrap();
+        let result = machine.hard_reset();
+        fs::remove_file(&path).unwrap();
+
+        assert!(result.is_err());
+        assert_eq!(machine.cartridge, [0x60, 0x05]);
+        assert_eq!(machine.peek(CARTRIDGE_ADDRESS), Some(0x60));
+        assert_eq!(machine.get_register(3).unwrap(), 7);
+    }
+
+    #[test]
+    fn fx29_steps_through_the_font_by_its_digit_height() {
+        let path = std::env::temp_dir().join(format!("bchip8-font-{}.bin", std::process::id()));
+        // seven bytes a digit, each holding its digit
+        fs::write(&path, (0..16).flat_map(|d| [d; 7]).collect::<Vec<u8>>()).unwrap();
+        let custom = Font::load(&path);
+        fs::remove_file(&path).unwrap();
+
+        for (font, stride) in [(Font::default(), 5), (custom.unwrap(), 7)] {
+            let mut machine = Machine::headless(StdRng::seed_from_u64(0), Duration::ZERO);
+            machine.load_font(crate::font::FONT_ADDRESS, font).unwrap();
+            machine
+                .load_cartridge(CARTRIDGE_ADDRESS, &[0x60, 0x0A, 0xF0, 0x29])
+                .unwrap();
+            machine.set_pc(CARTRIDGE_ADDRESS).unwrap();
+            machine.step().unwrap();
+            machine.step().unwrap();
+
+            let i = machine.get_register_i() as usize;
+            assert_eq!(i, crate::font::FONT_ADDRESS + 10 * stride);
+            let expected = if stride == 5 { 0xF0 } else { 0x0A };
+            assert_eq!(machine.peek(i), Some(expected));
+        }
+    }
+
+    #[test]
+    fn machine_code_keeps_the_font_clear_of_the_interpreter() {
+        let mut machine = Machine::headless(StdRng::seed_from_u64(0), Duration::ZERO);
+        machine
+            .enable_machine_code(Vip::new(vip::stand_in()).unwrap())
+            .unwrap();
+        machine
+            .load_font(crate::font::FONT_ADDRESS, Font::default())
+            .unwrap();
+        // a call into the interpreter, digit 0 in V0, then drawing it
+        machine
+            .load_cartridge(CARTRIDGE_ADDRESS, &[0x01, 0x00, 0xF0, 0x29, 0xD0, 0x15])
+            .unwrap();
+        machine.set_pc(CARTRIDGE_ADDRESS).unwrap();
+        assert_eq!(machine.peek(crate::font::FONT_ADDRESS), Some(0xD4));
+
+        machine.step().unwrap();
+        assert_eq!(machine.pc(), CARTRIDGE_ADDRESS + 2);
+        machine.step().unwrap();
+        assert_eq!(machine.get_register_i() as usize, vip::DIGITS);
+        machine.step().unwrap();
+        assert_eq!(
+            machine.display_buffer[0][..5],
+            [true, true, true, true, false]
+        );
+    }
+}
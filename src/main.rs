@@ -1,47 +1,907 @@
+mod analysis;
+mod asm;
 mod cartridge;
+mod cdp1802;
+mod config;
 mod console;
+mod dap;
+mod debugger;
+mod expect;
+mod filter;
 mod font;
+mod gdb;
+mod graphics;
+mod heatmap;
+mod hud;
+mod info;
+mod keymap;
 mod machine;
+mod memory_map;
+mod movie;
+mod octo;
+mod octo_cartridge;
 mod opcode;
+mod patch;
+mod quirks;
+mod recorder;
+mod render;
+mod romdb;
+mod romfile;
+mod screenshot;
+mod script;
+mod source_map;
+mod symbols;
+mod theme;
+mod vip;
 
-use clap::Parser;
+use clap::{Args, CommandFactory, Parser, Subcommand};
 use machine::Machine;
+use rand::SeedableRng;
+use rand::rngs::StdRng;
+use std::env;
+use std::ffi::OsString;
+use std::fs;
 use std::path;
-use std::{fs, time::Duration};
+use std::time::{Duration, Instant};
 
 #[derive(Parser)]
-#[command(version, about, long_about=None)]
+#[command(version, about, long_about = None)]
 struct Cli {
+    #[command(subcommand)]
+    command: Command,
+
+    #[arg(long, short, default_value = "chip8.log", global = true)]
+    log_file: path::PathBuf,
+
+    /// Config file to read instead of bchip8/config.toml in the user's config directory
+    #[arg(long, value_name = "path", global = true)]
+    config: Option<path::PathBuf>,
+
+    /// Directory with a copy of the CHIP-8 ROM database to identify ROMs with
+    #[arg(long, value_name = "dir", global = true)]
+    rom_db: Option<path::PathBuf>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Run a cartridge in the terminal, also when no command is given
+    Run(RunArgs),
+    /// Print the instructions of a cartridge
+    Disasm(DisasmArgs),
+    /// Assemble the disassembler's syntax into a cartridge
+    Asm(AsmArgs),
+    /// Print the size, hash, likely platform and opcode statistics of a cartridge
+    Info {
+        #[arg(value_name = "cartridge")]
+        cartridge: path::PathBuf,
+    },
+    /// Run a cartridge headless for a number of frames and check the machine state
+    Test(TestArgs),
+    /// Measure how many instructions per second the interpreter runs headless
+    Bench(BenchArgs),
+    /// Create an IPS or BPS patch that turns one cartridge into another
+    MakePatch(MakePatchArgs),
+}
+
+#[derive(Args)]
+struct QuirkArgs {
+    /// Interpreter whose quirks to follow, `vip` unless a config file names one
+    #[arg(long, value_enum)]
+    platform: Option<quirks::Platform>,
+
+    /// Change one quirk of the platform, such as `shift_vy=false`, may be repeated
+    #[arg(long, value_name = "name=bool", value_parser = quirks::parse_quirk)]
+    quirk: Vec<(String, bool)>,
+
+    /// Built-in hex digit font, the platform's own unless a config file names one
+    #[arg(long, value_enum)]
+    font: Option<font::FontSet>,
+
+    /// File of sixteen hex digit sprites to use as the font
+    #[arg(long, value_name = "path")]
+    font_file: Option<path::PathBuf>,
+
+    /// Run `0NNN` calls as COSMAC VIP machine code instead of skipping them
+    #[arg(long, overrides_with = "no_machine_code")]
+    machine_code: bool,
+
+    /// Skip `0NNN` calls even when a config file enables machine code
+    #[arg(long, overrides_with = "machine_code")]
+    no_machine_code: bool,
+
+    /// Image of the COSMAC VIP's CHIP-8 interpreter to load at 0x000, implies --machine-code
+    #[arg(long, value_name = "path")]
+    vip_interpreter: Option<path::PathBuf>,
+}
+
+/// A setting with `--name` and `--no-name` flags, `None` when neither is given
+/// so the config files decide
+fn switch(on: bool, off: bool) -> Option<bool> {
+    match (on, off) {
+        (true, _) => Some(true),
+        (_, true) => Some(false),
+        _ => None,
+    }
+}
+
+impl QuirkArgs {
+    fn config(&self) -> config::Config {
+        config::Config {
+            platform: self.platform,
+            quirks: self.quirk.iter().cloned().collect(),
+            font: self.font,
+            font_file: self.font_file.clone(),
+            machine_code: switch(self.machine_code, self.no_machine_code),
+            vip_interpreter: self.vip_interpreter.clone(),
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Args)]
+struct RunArgs {
+    #[arg(value_name = "cartridge", required_unless_present = "dap")]
+    cartridge: Option<path::PathBuf>,
+
+    /// Microseconds per instruction, 1000 unless a config file sets it
+    #[arg(long, short)]
+    cycle_micro: Option<u64>,
+
+    #[command(flatten)]
+    quirks: QuirkArgs,
+
+    /// Export the memory access heatmap on exit (.csv, otherwise .ppm image)
+    #[arg(long, value_name = "path")]
+    heatmap: Option<path::PathBuf>,
+
+    /// Wait for a gdb remote connection on this local port before booting
+    #[arg(long, value_name = "port")]
+    gdb: Option<u16>,
+
+    /// Serve the Debug Adapter Protocol over `stdio` or a local port, the program comes from `launch`
+    #[arg(long, value_name = "stdio|port", conflicts_with = "gdb")]
+    dap: Option<dap::Endpoint>,
+
+    /// Symbol file (`ADDRESS LABEL` lines or Octo JSON) used for traces and breakpoints
+    #[arg(long, value_name = "path")]
+    symbols: Option<path::PathBuf>,
+
+    /// Break at a label or address once gdb continues, may be repeated
+    #[arg(long = "break", value_name = "label|addr", requires = "gdb")]
+    breakpoints: Vec<String>,
+
+    /// Save the display to this image on exit (.png, .pbm, .pgm or .ppm)
+    #[arg(long, value_name = "path")]
+    screenshot: Option<path::PathBuf>,
+
+    /// Directory for screenshots taken with F12 and recordings toggled with F9
+    #[arg(long, value_name = "dir", default_value = ".")]
+    screenshot_dir: path::PathBuf,
+
+    /// Record the display to an animated .gif, or PNG frames in a directory
+    #[arg(long, value_name = "path")]
+    record: Option<path::PathBuf>,
+
+    /// Keypad input to replay, `FRAME press|release KEY` or `FRAME quit` per line
+    #[arg(long, value_name = "path")]
+    movie: Option<path::PathBuf>,
+
+    /// Run without a terminal, input comes from --movie, --script or a debugger
+    #[arg(long, default_value_t = false)]
+    headless: bool,
+
+    /// Halt after this many 60 Hz frames
+    #[arg(long, value_name = "n")]
+    frames: Option<u128>,
+
+    /// Integer pixel scale of screenshots and recordings
+    #[arg(long, value_name = "n", default_value_t = 1, value_parser = clap::value_parser!(u16).range(1..))]
+    screenshot_scale: u16,
+
+    /// Rhai script with hooks for automation and instrumentation
+    #[arg(long, value_name = "path")]
+    script: Option<path::PathBuf>,
+
+    /// How pixels are drawn into terminal cells
+    #[arg(long, value_enum)]
+    render: Option<render::RenderMode>,
+
+    /// Draw the display as a Kitty or sixel image when the terminal supports it
+    #[arg(long, value_enum)]
+    graphics: Option<graphics::GraphicsMode>,
+
+    /// Keypad layout, used unless --keymap names a preset
+    #[arg(long, value_enum)]
+    layout: Option<keymap::Layout>,
+
+    /// TOML keymap with keypad keys, hotkeys and per-ROM overrides
+    #[arg(long, value_name = "path")]
+    keymap: Option<path::PathBuf>,
+
+    /// How key releases are detected, `timeout` suits terminals that only send presses
+    #[arg(long, value_enum, default_value_t)]
+    key_release: console::KeyRelease,
+
+    /// Without release events, how long a key press without auto-repeat is held,
+    /// longer than the usual 500-660 ms before a held key starts repeating
+    #[arg(long, value_name = "ms", default_value_t = 700)]
+    key_hold_ms: u64,
+
+    /// IPS or BPS patch to apply to the cartridge, may be repeated to apply several in order
+    #[arg(long, value_name = "path")]
+    patch: Vec<path::PathBuf>,
+
+    /// Reload the cartridge whenever the file changes
+    #[arg(long, default_value_t = false)]
+    watch: bool,
+
+    /// Memory kept across --watch reloads, such as `0xE00-0xFFF`, may be repeated
+    #[arg(long, value_name = "start-end", value_parser = cartridge::parse_range, requires = "watch")]
+    preserve: Vec<std::ops::RangeInclusive<usize>>,
+
+    /// Show the status bar with timers, speed and the keypad, F1 toggles it
+    #[arg(long, overrides_with = "no_hud")]
+    hud: bool,
+
+    /// Hide the status bar even when a config file shows it
+    #[arg(long, overrides_with = "hud")]
+    no_hud: bool,
+
+    /// Anti-flicker filter for sprites redrawn with XOR
+    #[arg(long, value_enum)]
+    filter: Option<filter::FilterMode>,
+
+    /// Display colours
+    #[arg(long, value_enum)]
+    theme: Option<theme::ThemeName>,
+
+    /// Lit pixel colour, overriding the theme
+    #[arg(long, value_name = "#rrggbb", value_parser = theme::parse_color)]
+    fg: Option<theme::Rgb>,
+
+    /// Background colour, overriding the theme
+    #[arg(long, value_name = "#rrggbb", value_parser = theme::parse_color)]
+    bg: Option<theme::Rgb>,
+
+    /// Background, plane 1 and optionally plane 2 and overlap colours for XO-CHIP
+    #[arg(long, value_name = "colours", value_parser = theme::parse_color, value_delimiter = ',')]
+    palette: Vec<theme::Rgb>,
+}
+
+impl RunArgs {
+    /// The settings given as flags, to merge over the config files
+    fn config(&self) -> config::Config {
+        config::Config {
+            cycle_micro: self.cycle_micro,
+            layout: self.layout,
+            keymap: self.keymap.clone(),
+            render: self.render,
+            graphics: self.graphics,
+            filter: self.filter,
+            theme: self.theme,
+            fg: self.fg,
+            bg: self.bg,
+            palette: self.palette.clone(),
+            hud: switch(self.hud, self.no_hud),
+            ..self.quirks.config()
+        }
+    }
+}
+
+#[derive(Args)]
+struct DisasmArgs {
     #[arg(value_name = "cartridge")]
     cartridge: path::PathBuf,
 
-    #[arg(long, short, default_value_t = false)]
-    disassemble: bool,
+    /// First address to print
+    #[arg(long, value_name = "addr", value_parser = parse_addr)]
+    start: Option<usize>,
 
-    #[arg(long, short, default_value_t = 1000)]
-    cycle_micro: u64,
+    /// Last address to print
+    #[arg(long, value_name = "addr", value_parser = parse_addr)]
+    end: Option<usize>,
 
-    #[arg(long, short, default_value = "chip8.log")]
-    log_file: path::PathBuf,
+    #[arg(long, value_enum, default_value_t)]
+    format: cartridge::DisasmFormat,
+
+    /// Symbol file whose labels replace addresses
+    #[arg(long, value_name = "path")]
+    symbols: Option<path::PathBuf>,
+
+    /// Platform whose program start the cartridge is loaded at, `vip` by default
+    #[arg(long, value_enum, default_value_t)]
+    platform: quirks::Platform,
+}
+
+#[derive(Args)]
+struct AsmArgs {
+    #[arg(value_name = "source")]
+    source: path::PathBuf,
+
+    /// Cartridge to write, the source with a `.ch8` extension by default
+    #[arg(long, short, value_name = "path")]
+    output: Option<path::PathBuf>,
+
+    /// Also write the labels as a symbol file
+    #[arg(long, value_name = "path")]
+    symbols: Option<path::PathBuf>,
+
+    /// Platform whose program start the program is assembled for, `vip` by default
+    #[arg(long, value_enum, default_value_t)]
+    platform: quirks::Platform,
+}
+
+#[derive(Args)]
+struct MakePatchArgs {
+    #[arg(value_name = "original")]
+    original: path::PathBuf,
+
+    #[arg(value_name = "modified")]
+    modified: path::PathBuf,
+
+    /// Patch file to write
+    #[arg(long, short, value_name = "path")]
+    output: path::PathBuf,
+
+    /// Patch format, from the extension of the output by default
+    #[arg(long, value_enum)]
+    format: Option<patch::Format>,
+}
+
+#[derive(Args)]
+struct TestArgs {
+    #[arg(value_name = "cartridge")]
+    cartridge: path::PathBuf,
+
+    /// IPS or BPS patch to apply to the cartridge, may be repeated
+    #[arg(long, value_name = "path")]
+    patch: Vec<path::PathBuf>,
+
+    /// 60 Hz frames to run before checking
+    #[arg(long, value_name = "n", default_value_t = 600)]
+    frames: u128,
+
+    /// Instructions per frame, 16 is about the speed of `run`
+    #[arg(long, value_name = "n", default_value_t = 16)]
+    ipf: u64,
+
+    /// Seed for the random number generator
+    #[arg(long, value_name = "n", default_value_t = 0)]
+    seed: u64,
+
+    #[command(flatten)]
+    quirks: QuirkArgs,
+
+    /// Keypad input to replay
+    #[arg(long, value_name = "path")]
+    movie: Option<path::PathBuf>,
+
+    /// Rhai script with hooks
+    #[arg(long, value_name = "path")]
+    script: Option<path::PathBuf>,
+
+    /// Symbol file for labels in --expect-pc
+    #[arg(long, value_name = "path")]
+    symbols: Option<path::PathBuf>,
+
+    /// Save the display to this image when the run ends
+    #[arg(long, value_name = "path")]
+    screenshot: Option<path::PathBuf>,
+
+    /// Expected program counter, a label or address
+    #[arg(long, value_name = "label|addr")]
+    expect_pc: Option<String>,
+
+    /// Expected register value such as `V3=1f` or `I=2a0`, may be repeated
+    #[arg(long, value_name = "VX=NN", value_parser = expect::parse_register)]
+    expect_reg: Vec<expect::Expectation>,
+
+    /// Expected memory byte such as `0x300=07`, may be repeated
+    #[arg(long, value_name = "ADDR=NN", value_parser = expect::parse_memory)]
+    expect_mem: Vec<expect::Expectation>,
+
+    /// Expected display, a plain .pbm image such as a screenshot
+    #[arg(long, value_name = "path")]
+    expect_screen: Option<path::PathBuf>,
+}
+
+#[derive(Args)]
+struct BenchArgs {
+    #[arg(value_name = "cartridge")]
+    cartridge: path::PathBuf,
+
+    /// 60 Hz frames to run
+    #[arg(long, value_name = "n", default_value_t = 600)]
+    frames: u128,
+
+    /// Instructions per frame
+    #[arg(long, value_name = "n", default_value_t = 10_000)]
+    ipf: u64,
+
+    #[command(flatten)]
+    quirks: QuirkArgs,
+}
+
+fn parse_addr(s: &str) -> anyhow::Result<usize> {
+    source_map::parse_address(s).ok_or_else(|| anyhow::anyhow!("invalid address {}", s))
+}
+
+/// Tokens `arg` and its value take up when it is a global option
+fn global_option_len(cmd: &clap::Command, arg: &str) -> Option<usize> {
+    cmd.get_arguments()
+        .filter(|a| a.is_global_set())
+        .find_map(|a| {
+            let long = a.get_long().map(|l| format!("--{}", l));
+            let short = a.get_short().map(|s| format!("-{}", s));
+            let takes_value = a.get_action().takes_values();
+            if long.as_deref() == Some(arg) || short.as_deref() == Some(arg) {
+                Some(if takes_value { 2 } else { 1 })
+            } else if long.is_some_and(|l| arg.starts_with(&format!("{}=", l)))
+                || (takes_value && short.is_some_and(|s| arg.starts_with(&s)))
+            {
+                Some(1)
+            } else {
+                None
+            }
+        })
+}
+
+/// Inserts `run` after the global options unless a command or `--help` or
+/// `--version` follows them, so `bchip8 game.ch8` runs the cartridge
+fn with_default_command(mut args: Vec<OsString>) -> Vec<OsString> {
+    let cmd = Cli::command();
+    let mut i = 1;
+    while let Some(len) = args
+        .get(i)
+        .and_then(|a| a.to_str())
+        .and_then(|a| global_option_len(&cmd, a))
+    {
+        i += len;
+    }
+    let Some(arg) = args.get(i) else {
+        return args;
+    };
+    let arg = arg.to_string_lossy();
+    let is_command = arg == "help"
+        || cmd
+            .get_subcommands()
+            .any(|c| c.get_name() == arg || c.get_all_aliases().any(|a| a == arg));
+    if !is_command && !["-h", "--help", "-V", "--version"].contains(&arg.as_ref()) {
+        args.insert(i, "run".into());
+    }
+    args
 }
 
 fn main() -> anyhow::Result<()> {
-    let cli = Cli::parse();
+    let cli = Cli::parse_from(with_default_command(env::args_os().collect()));
     let log_file = fs::File::create(cli.log_file).expect("Failed to create log file");
     env_logger::Builder::from_default_env()
         .target(env_logger::Target::Pipe(Box::new(log_file)))
         .init();
-    let cartridge = cartridge::load_cartridge(&cli.cartridge)?;
-    if cli.disassemble {
-        cartridge::debug_cartridge(&cartridge);
-        return Ok(());
+    let db = romdb::RomDb::open(cli.rom_db.as_deref())?;
+    let config = cli.config.as_deref();
+    match cli.command {
+        Command::Run(args) => run(args, config, &db),
+        Command::Disasm(args) => disasm(args),
+        Command::Asm(args) => asm(args),
+        Command::Info { cartridge } => {
+            let rom = cartridge::load_cartridge(&romfile::pick(&cartridge)?)?;
+            print!("{}", info::Info::new(&rom, &db));
+            Ok(())
+        }
+        Command::Test(args) => test(args, config, &db),
+        Command::Bench(args) => bench(args, config, &db),
+        Command::MakePatch(args) => make_patch(args),
+    }
+}
+
+fn run(args: RunArgs, config_path: Option<&path::Path>, db: &romdb::RomDb) -> anyhow::Result<()> {
+    let flags = args.config();
+    let mut dap = match args.dap {
+        Some(endpoint) => Some(dap::DapServer::connect(endpoint)?),
+        None => None,
+    };
+    let launch = match dap.as_mut() {
+        Some(dap) => dap.wait_for_launch()?,
+        None => dap::LaunchArgs::default(),
+    };
+    let Some(cartridge_path) = launch.program.or(args.cartridge) else {
+        anyhow::bail!("no cartridge to run");
+    };
+    let stdin = romfile::is_stdin(&cartridge_path);
+    if stdin && args.watch {
+        anyhow::bail!("--watch needs a cartridge file, not standard input");
+    }
+    let cartridge_path = romfile::pick(&cartridge_path)?;
+
+    let symbols = match launch.symbols.or(args.symbols) {
+        Some(path) => symbols::SymbolMap::load(&path)?,
+        None => symbols::SymbolMap::default(),
+    };
+
+    let patches = load_patches(&args.patch)?;
+    let (rom, known) = read_rom(&cartridge_path, &patches, db)?;
+    let config = known_config(config_path, &cartridge_path, &rom, known.as_ref(), flags)?;
+    let cartridge = rom.bytes;
+    let theme = config.theme.unwrap_or_default().theme().with_overrides(
+        config.fg,
+        config.bg,
+        &config.palette,
+    )?;
+
+    let file_name = romfile::display_name(&cartridge_path);
+    let rom_name = match &known {
+        Some(known) => known.name(),
+        None => file_name.clone(),
+    };
+    let layout = config.layout.unwrap_or_default();
+    let keymap = match &config.keymap {
+        Some(path) => keymap::Keymap::load(path, layout, &file_name)?,
+        None => keymap::Keymap::preset(layout),
+    };
+
+    let gdb = match args.gdb {
+        Some(port) => Some(gdb::GdbStub::listen(port)?),
+        None => None,
+    };
+
+    let memory_map = config.memory_map()?;
+    let start = memory_map.program.start;
+    // on stderr before the terminal switches to the emulator's screen
+    for warning in memory_map.check_load(start, cartridge.len())? {
+        eprintln!("warning: the cartridge {}", warning);
     }
 
     let rng = rand::rng();
-    let mut machine = Machine::new(rng, Duration::from_micros(cli.cycle_micro))?;
-    machine.load_font(font::FONT_ADDRESS, font::load_default_font())?;
-    machine.load_cartridge(cartridge::CARTRIDGE_ADDRESS, &cartridge)?;
+    let cycle = Duration::from_micros(config.cycle_micro.unwrap_or(1000));
+    let headless = args.headless || matches!(args.dap, Some(dap::Endpoint::Stdio));
+    let mut machine = if headless {
+        Machine::headless(rng, cycle)
+    } else {
+        Machine::new(rng, cycle)?
+    };
+    for name in &args.breakpoints {
+        match symbols.resolve(name) {
+            Some(addr) => machine.add_breakpoint(addr),
+            None => anyhow::bail!(
+                "unknown breakpoint label {}, addresses take a 0x prefix",
+                name
+            ),
+        }
+    }
+    machine.set_symbols(symbols);
+    machine.set_quirks(config.platform.unwrap_or_default(), config.quirks()?);
+    machine.set_render_mode(config.render.unwrap_or_default());
+    machine.set_graphics(config.graphics.unwrap_or_default());
+    machine.set_theme(theme);
+    machine.set_filter(config.filter.unwrap_or_default());
+    machine.set_hud(config.hud.unwrap_or(false), &rom_name);
+    if let Some(known) = &known {
+        machine.set_controls(known.key_hints());
+    }
+    machine.set_keymap(keymap);
+    machine.set_key_release(args.key_release, Duration::from_millis(args.key_hold_ms));
+    machine.set_screenshot_settings(screenshot::Settings {
+        dir: args.screenshot_dir,
+        scale: args.screenshot_scale as usize,
+    });
+    if let Some(gdb) = gdb {
+        machine.attach_debugger(Box::new(gdb));
+    }
+    if let Some(dap) = dap {
+        machine.attach_debugger(Box::new(dap));
+    }
+    if let Some(path) = &args.movie {
+        machine.set_movie(movie::Movie::load(path)?);
+    }
+    if let Some(frames) = args.frames {
+        machine.set_frame_limit(frames);
+    }
+    if let Some(path) = &args.record {
+        machine.start_recording(path)?;
+    }
+    machine.set_memory_map(memory_map);
+    if let Some(vip) = config.vip()? {
+        machine.enable_machine_code(vip)?;
+    }
+    machine.load_font(font::FONT_ADDRESS, config.font()?)?;
+    machine.load_cartridge(start, &cartridge)?;
+    machine.set_patches(patches);
+    // standard input can't be read again, a hard reset reuses the loaded bytes
+    if args.watch {
+        machine.watch_cartridge(&cartridge_path, args.preserve);
+    } else if !stdin {
+        machine.set_cartridge_path(&cartridge_path);
+    }
+    if let Some(path) = &args.script {
+        machine.attach_script(script::ScriptHost::load(path)?)?;
+    }
     machine.boot()?;
+    if let Some(path) = args.screenshot {
+        machine.save_screenshot(&path)?;
+    }
+    if let Some(path) = args.heatmap {
+        machine.heatmap().export(&path)?;
+    }
 
     Ok(())
 }
+
+fn disasm(args: DisasmArgs) -> anyhow::Result<()> {
+    let symbols = match &args.symbols {
+        Some(path) => symbols::SymbolMap::load(path)?,
+        None => symbols::SymbolMap::default(),
+    };
+    let cartridge = cartridge::load_cartridge(&romfile::pick(&args.cartridge)?)?;
+    let range = args.start.unwrap_or(0)..=args.end.unwrap_or(usize::MAX);
+    let start = memory_map::MemoryMap::new(args.platform).program.start;
+    cartridge::debug_cartridge(&cartridge, start, &symbols, range, args.format);
+    Ok(())
+}
+
+fn load_patches(paths: &[path::PathBuf]) -> anyhow::Result<Vec<patch::Patch>> {
+    paths.iter().map(|path| patch::Patch::load(path)).collect()
+}
+
+fn make_patch(args: MakePatchArgs) -> anyhow::Result<()> {
+    let Some(format) = args
+        .format
+        .or_else(|| patch::Format::from_path(&args.output))
+    else {
+        anyhow::bail!(
+            "{}: name it .ips or .bps, or pass --format",
+            args.output.display()
+        );
+    };
+    let original = cartridge::load_cartridge(&args.original)?;
+    let modified = cartridge::load_cartridge(&args.modified)?;
+    let bytes = patch::create(format, &original, &modified)?;
+    fs::write(&args.output, &bytes)?;
+    println!("{}: {} bytes", args.output.display(), bytes.len());
+    Ok(())
+}
+
+fn asm(args: AsmArgs) -> anyhow::Result<()> {
+    let source = fs::read_to_string(&args.source)?;
+    let start = memory_map::MemoryMap::new(args.platform).program.start;
+    let program = asm::assemble(&source, start)
+        .map_err(|e| anyhow::anyhow!("{}: {}", args.source.display(), e))?;
+    let output = args
+        .output
+        .unwrap_or_else(|| args.source.with_extension("ch8"));
+    fs::write(&output, &program.bytes)?;
+    if let Some(path) = &args.symbols {
+        fs::write(path, program.symbols())?;
+    }
+    println!("{}: {} bytes", output.display(), program.bytes.len());
+    Ok(())
+}
+
+/// Reads `cartridge` with `patches` applied and finds it in the ROM database,
+/// first by the unpatched ROM, whose settings a fix or translation keeps, then
+/// by the patched one
+fn read_rom(
+    cartridge: &path::Path,
+    patches: &[patch::Patch],
+    db: &romdb::RomDb,
+) -> anyhow::Result<(romfile::RomFile, Option<romdb::Match>)> {
+    let mut rom = romfile::read(cartridge)?;
+    let original = cartridge::sha1_hex(&rom.bytes);
+    rom.bytes = patch::apply_all(patches, rom.bytes)?;
+    let known = db
+        .lookup(&original)
+        .or_else(|| db.lookup(&cartridge::sha1_hex(&rom.bytes)));
+    Ok((rom, known))
+}
+
+/// The config for `rom`, read from `cartridge`: the global file, what the ROM
+/// database recommends or analysis guesses for unknown ROMs, the settings the
+/// ROM file came with, the sidecar and then `flags`
+fn known_config(
+    config_path: Option<&path::Path>,
+    cartridge: &path::Path,
+    rom: &romfile::RomFile,
+    known: Option<&romdb::Match>,
+    flags: config::Config,
+) -> anyhow::Result<config::Config> {
+    let recommended = match known {
+        Some(known) => known.config(),
+        None => {
+            // the code is followed from where the platform chosen without a guess loads it
+            let unguessed = config::Config::for_rom(config_path, cartridge, rom.config.clone())?
+                .merge(flags.clone());
+            let start = unguessed.memory_map()?.program.start;
+            let analysis = analysis::Analysis::new(&rom.bytes, start);
+            log::info!("unknown ROM, guessed platform {}", analysis.platform);
+            analysis.config()
+        }
+    };
+    Ok(config::Config::for_rom(
+        config_path,
+        cartridge,
+        recommended.merge(rom.config.clone()),
+    )?
+    .merge(flags))
+}
+
+/// A headless machine set up by `config` with the cartridge loaded, running
+/// `ipf` instructions per frame for `frames` frames
+fn headless_machine(
+    cartridge: &path::Path,
+    rom: &[u8],
+    config: &config::Config,
+    seed: u64,
+    ipf: u64,
+    frames: u128,
+) -> anyhow::Result<Machine<StdRng>> {
+    let mut machine = Machine::headless(StdRng::seed_from_u64(seed), Duration::ZERO);
+    machine.set_quirks(config.platform.unwrap_or_default(), config.quirks()?);
+    machine.set_instructions_per_frame(ipf);
+    machine.set_frame_limit(frames);
+    let memory_map = config.memory_map()?;
+    let start = memory_map.program.start;
+    machine.set_memory_map(memory_map);
+    if let Some(vip) = config.vip()? {
+        machine.enable_machine_code(vip)?;
+    }
+    machine.load_font(font::FONT_ADDRESS, config.font()?)?;
+    machine.load_cartridge(start, rom)?;
+    if !romfile::is_stdin(cartridge) {
+        machine.set_cartridge_path(cartridge);
+    }
+    Ok(machine)
+}
+
+fn test(args: TestArgs, config_path: Option<&path::Path>, db: &romdb::RomDb) -> anyhow::Result<()> {
+    let cartridge = romfile::pick(&args.cartridge)?;
+    let patches = load_patches(&args.patch)?;
+    let (rom, known) = read_rom(&cartridge, &patches, db)?;
+    let config = known_config(
+        config_path,
+        &cartridge,
+        &rom,
+        known.as_ref(),
+        args.quirks.config(),
+    )?;
+    let mut machine = headless_machine(
+        &cartridge,
+        &rom.bytes,
+        &config,
+        args.seed,
+        args.ipf,
+        args.frames,
+    )?;
+    machine.set_patches(patches);
+    if let Some(path) = &args.symbols {
+        machine.set_symbols(symbols::SymbolMap::load(path)?);
+    }
+    if let Some(path) = &args.movie {
+        machine.set_movie(movie::Movie::load(path)?);
+    }
+    if let Some(path) = &args.script {
+        machine.attach_script(script::ScriptHost::load(path)?)?;
+    }
+    machine.boot()?;
+    if let Some(path) = &args.screenshot {
+        machine.save_screenshot(path)?;
+    }
+
+    let mut expectations = vec![];
+    expectations.extend(args.expect_pc.map(expect::Expectation::Pc));
+    expectations.extend(args.expect_reg);
+    expectations.extend(args.expect_mem);
+    expectations.extend(args.expect_screen.map(expect::Expectation::Screen));
+    let mut failed = 0;
+    for expectation in &expectations {
+        match expectation.check(&machine)? {
+            Ok(()) => println!("ok    {}", expectation),
+            Err(found) => {
+                println!("FAIL  {}, found {}", expectation, found);
+                failed += 1;
+            }
+        }
+    }
+    println!(
+        "{} frames, {} instructions, {} of {} checks failed",
+        machine.frame(),
+        machine.instructions(),
+        failed,
+        expectations.len()
+    );
+    if failed > 0 {
+        anyhow::bail!("{} checks failed", failed);
+    }
+    Ok(())
+}
+
+fn bench(
+    args: BenchArgs,
+    config_path: Option<&path::Path>,
+    db: &romdb::RomDb,
+) -> anyhow::Result<()> {
+    let cartridge = romfile::pick(&args.cartridge)?;
+    let (rom, known) = read_rom(&cartridge, &[], db)?;
+    let config = known_config(
+        config_path,
+        &cartridge,
+        &rom,
+        known.as_ref(),
+        args.quirks.config(),
+    )?;
+    let mut machine = headless_machine(&cartridge, &rom.bytes, &config, 0, args.ipf, args.frames)?;
+    let started = Instant::now();
+    machine.boot()?;
+    let elapsed = started.elapsed().as_secs_f64();
+    let instructions = machine.instructions();
+    println!(
+        "{} instructions in {} frames, {:.3} s",
+        instructions,
+        machine.frame(),
+        elapsed
+    );
+    println!(
+        "{:.2} MIPS, {:.0}x the default speed of `run`",
+        instructions as f64 / elapsed / 1e6,
+        instructions as f64 / elapsed / 1000.0
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &str) -> Cli {
+        let args = args.split_whitespace().map(OsString::from).collect();
+        Cli::try_parse_from(with_default_command(args)).unwrap()
+    }
+
+    #[test]
+    fn global_options_go_before_or_after_the_command() {
+        let cli = parse("bchip8 --config x.toml info rom.ch8");
+        assert_eq!(cli.config, Some("x.toml".into()));
+        assert!(matches!(cli.command, Command::Info { .. }));
+
+        let cli = parse("bchip8 -l log disasm rom.ch8");
+        assert_eq!(cli.log_file, path::PathBuf::from("log"));
+        assert!(matches!(cli.command, Command::Disasm(_)));
+
+        let cli = parse("bchip8 --rom-db=db info rom.ch8 --config x.toml");
+        assert_eq!(cli.rom_db, Some("db".into()));
+        assert_eq!(cli.config, Some("x.toml".into()));
+    }
+
+    #[test]
+    fn switches_can_turn_a_setting_off() {
+        let run = |args| match parse(args).command {
+            Command::Run(run) => run.config(),
+            _ => panic!("{} didn't run", args),
+        };
+        assert_eq!(run("bchip8 rom.ch8").hud, None);
+        assert_eq!(run("bchip8 rom.ch8 --hud").hud, Some(true));
+        assert_eq!(run("bchip8 rom.ch8 --no-hud").hud, Some(false));
+        assert_eq!(run("bchip8 rom.ch8 --hud --no-hud").hud, Some(false));
+        assert_eq!(
+            run("bchip8 rom.ch8 --no-machine-code").machine_code,
+            Some(false)
+        );
+    }
+
+    #[test]
+    fn without_a_command_the_cartridge_runs() {
+        for args in [
+            "bchip8 rom.ch8 --hud",
+            "bchip8 --hud rom.ch8",
+            "bchip8 --config x.toml rom.ch8",
+        ] {
+            let Command::Run(run) = parse(args).command else {
+                panic!("{} didn't run", args);
+            };
+            assert_eq!(run.cartridge, Some("rom.ch8".into()));
+        }
+        let Command::Run(run) = parse("bchip8 --dap stdio").command else {
+            panic!("--dap didn't run");
+        };
+        assert!(run.cartridge.is_none());
+    }
+}
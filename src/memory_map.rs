@@ -0,0 +1,97 @@
+//! The layout of the 4 KB of memory on each platform, for checking what is
+//! loaded into it.
+//!
+//! Below the program area sits the interpreter's own area, which holds the font.
+//! Programs start at 0x200, or 0x600 on the ETI-660, and may run up to the end of
+//! memory. The COSMAC VIP also kept its stack, work area and display buffer in
+//! the top 352 bytes, so a ROM reaching into them was too large for a real VIP.
+
+use crate::font::{FONT_ADDRESS, Font, FontSet};
+use crate::machine::MEMORY_SIZE;
+use crate::quirks::Platform;
+use std::ops::Range;
+
+#[derive(Debug, Clone)]
+pub struct MemoryMap {
+    pub font: Range<usize>,
+    /// where programs are loaded and start running
+    pub program: Range<usize>,
+    /// memory the original interpreter used for itself above the program area
+    pub reserved_top: Option<Range<usize>>,
+}
+
+impl Default for MemoryMap {
+    fn default() -> Self {
+        MemoryMap::new(Platform::default())
+    }
+}
+
+fn overlap(a: &Range<usize>, b: &Range<usize>) -> Option<Range<usize>> {
+    let overlap = a.start.max(b.start)..a.end.min(b.end);
+    (!overlap.is_empty()).then_some(overlap)
+}
+
+/// `0x050-0x09f`, an inclusive range as the rest of the program prints them
+fn span(range: &Range<usize>) -> String {
+    format!("{:#05x}-{:#05x}", range.start, range.end - 1)
+}
+
+impl MemoryMap {
+    pub fn new(platform: Platform) -> Self {
+        let program_start = match platform {
+            Platform::Eti660 => 0x600,
+            _ => 0x200,
+        };
+        MemoryMap {
+            font: FONT_ADDRESS
+                ..FONT_ADDRESS + Font::builtin(FontSet::for_platform(platform)).bytes().len(),
+            program: program_start..MEMORY_SIZE,
+            reserved_top: match platform {
+                Platform::Vip => Some(0xEA0..MEMORY_SIZE),
+                _ => None,
+            },
+        }
+    }
+
+    /// The map with `font` in place of the platform's own
+    pub fn with_font(mut self, font: &Font) -> Self {
+        self.font = FONT_ADDRESS..FONT_ADDRESS + font.bytes().len();
+        self
+    }
+
+    /// Fails when `size` bytes at `address` don't fit in memory, and otherwise
+    /// returns a warning for each area they would overwrite
+    pub fn check_load(&self, address: usize, size: usize) -> anyhow::Result<Vec<String>> {
+        let end = address + size;
+        if end > MEMORY_SIZE {
+            anyhow::bail!(
+                "{} bytes at {:#05x} don't fit in memory, at most {} do",
+                size,
+                address,
+                MEMORY_SIZE.saturating_sub(address)
+            );
+        }
+        let loaded = address..end;
+        let mut warnings = vec![];
+        if let Some(font) = overlap(&loaded, &self.font) {
+            warnings.push(format!("overwrites the font at {}", span(&font)));
+        }
+        if let Some(below) = overlap(&loaded, &(0..self.program.start)) {
+            warnings.push(format!(
+                "loads into the interpreter's area at {}",
+                span(&below)
+            ));
+        }
+        if let Some(reserved) = self
+            .reserved_top
+            .as_ref()
+            .and_then(|top| overlap(&loaded, top))
+        {
+            warnings.push(format!(
+                "reaches {}, where the COSMAC VIP kept its stack and display",
+                span(&reserved)
+            ));
+        }
+        Ok(warnings)
+    }
+}
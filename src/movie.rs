@@ -0,0 +1,74 @@
+//! Scripted keypad input for headless runs.
+//!
+//! One event per line, `FRAME press KEY`, `FRAME release KEY` or `FRAME quit`,
+//! where `FRAME` counts 60 Hz frames from boot and `KEY` is a hex keypad digit.
+//! `#` starts a comment.
+
+use crate::console::{Key, KeyEvent};
+use crate::source_map::strip_comment;
+use std::fs;
+use std::path::Path;
+
+#[derive(Debug, Clone, Copy)]
+pub enum MovieEvent {
+    Press(u8),
+    Release(u8),
+    Quit,
+}
+
+pub struct Movie {
+    events: Vec<(u128, MovieEvent)>,
+    next: usize,
+}
+
+impl Movie {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let text = fs::read_to_string(path)?;
+        let mut events = vec![];
+        for (n, line) in text.lines().enumerate() {
+            let line = strip_comment(line);
+            if line.is_empty() {
+                continue;
+            }
+            let err =
+                || anyhow::anyhow!("{}:{}: invalid movie line {}", path.display(), n + 1, line);
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let frame: u128 = fields[0].parse().map_err(|_| err())?;
+            let key = || match fields.get(2).map(|k| u8::from_str_radix(k, 16)) {
+                Some(Ok(k)) if k < 16 && fields.len() == 3 => Ok(k),
+                _ => Err(err()),
+            };
+            let event = match fields.get(1).copied() {
+                Some("press") => MovieEvent::Press(key()?),
+                Some("release") => MovieEvent::Release(key()?),
+                Some("quit") if fields.len() == 2 => MovieEvent::Quit,
+                _ => return Err(err()),
+            };
+            events.push((frame, event));
+        }
+        events.sort_by_key(|(frame, _)| *frame);
+        Ok(Movie { events, next: 0 })
+    }
+
+    /// Events due at or before `frame` that have not been returned yet
+    pub fn events_until(&mut self, frame: u128) -> Vec<MovieEvent> {
+        let due = self.events[self.next..]
+            .iter()
+            .take_while(|(f, _)| *f <= frame)
+            .map(|(_, e)| *e)
+            .collect::<Vec<_>>();
+        self.next += due.len();
+        due
+    }
+}
+
+impl MovieEvent {
+    /// The keypad event, `None` for quit
+    pub fn key_event(&self) -> Option<KeyEvent> {
+        match self {
+            MovieEvent::Press(k) => Some(KeyEvent::Pressed(Key::Num(*k))),
+            MovieEvent::Release(k) => Some(KeyEvent::Released(Key::Num(*k))),
+            MovieEvent::Quit => None,
+        }
+    }
+}
@@ -0,0 +1,899 @@
+//! Compiler for Octo (<https://github.com/JohnEarnest/Octo>), the high-level
+//! assembly language that Octo cartridges carry their programs in.
+//!
+//! The language of the Octo manual is covered: `: label`, `:const`, `:alias`,
+//! `:calc` (evaluated right to left, like Octo), `:macro`, `:org`, `:byte`,
+//! `:pointer`, `:next`, `:unpack` and `:call`, `if ... then`,
+//! `if ... begin ... else ... end`, `loop ... while ... again`, and the
+//! SUPER-CHIP and XO-CHIP instructions. Debugger directives are skipped and
+//! `:stringmode` isn't supported.
+//! ```text
+//! : main
+//!     i := smile
+//!     v0 := 10
+//!     loop
+//!         sprite v0 v1 5
+//!         v0 += 8
+//!         if v0 != 50 then
+//!     again
+//! : smile
+//!     0x24 0x24 0x00 0x81 0x7E
+//! ```
+
+use crate::asm::Program;
+use crate::cartridge::CARTRIDGE_ADDRESS;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+
+/// Octo's XO-CHIP memory, the most a program can fill
+const MAX_ADDRESS: usize = 0x10000;
+
+#[derive(Clone)]
+struct Token {
+    text: String,
+    line: usize,
+}
+
+/// Splits on whitespace, dropping `#` comments and keeping `"strings"` whole
+fn tokenize(source: &str) -> VecDeque<Token> {
+    let mut tokens = VecDeque::new();
+    for (n, text) in source.lines().enumerate() {
+        let mut rest = text.trim_start();
+        while !rest.is_empty() && !rest.starts_with('#') {
+            let end = match rest.strip_prefix('"') {
+                Some(quoted) => quoted.find('"').map(|i| i + 2).unwrap_or(rest.len()),
+                None => rest.find(char::is_whitespace).unwrap_or(rest.len()),
+            };
+            tokens.push_back(Token {
+                text: rest[..end].to_string(),
+                line: n + 1,
+            });
+            rest = rest[end..].trim_start();
+        }
+    }
+    tokens
+}
+
+/// How a label's address is written into an instruction once it is known
+#[derive(Clone, Copy)]
+enum Fixup {
+    /// the low 12 bits of the opcode
+    Nnn,
+    /// a whole 16-bit word
+    Word,
+    /// ORed into a byte, the high byte of the address
+    High,
+    Low,
+}
+
+/// An open `if`, `else` or `loop`, and the jumps waiting for its end
+enum Flow {
+    If(usize),
+    Else(usize),
+    Loop(usize, Vec<usize>),
+}
+
+enum Rhs {
+    Reg(u8),
+    Byte(u8),
+}
+
+struct Compiler {
+    tokens: VecDeque<Token>,
+    line: usize,
+    rom: Vec<u8>,
+    here: usize,
+    labels: BTreeMap<String, usize>,
+    constants: HashMap<String, f64>,
+    aliases: HashMap<String, u8>,
+    macros: HashMap<String, (Vec<String>, Vec<Token>)>,
+    fixups: Vec<(usize, String, Fixup, usize)>,
+    flow: Vec<Flow>,
+}
+
+pub fn compile(source: &str) -> anyhow::Result<Program> {
+    let tokens = tokenize(source);
+    let starts_with_main = tokens.len() >= 2 && tokens[0].text == ":" && tokens[1].text == "main";
+    let mut compiler = Compiler {
+        tokens,
+        line: 1,
+        rom: vec![],
+        here: CARTRIDGE_ADDRESS,
+        labels: BTreeMap::new(),
+        constants: HashMap::new(),
+        aliases: HashMap::new(),
+        macros: HashMap::new(),
+        fixups: vec![],
+        flow: vec![],
+    };
+    // like Octo, programs that don't begin at main begin with a jump to it
+    if !starts_with_main {
+        compiler.emit(0x1000)?;
+        let main = (CARTRIDGE_ADDRESS, "main".to_string(), Fixup::Nnn, 1);
+        compiler.fixups.push(main);
+    }
+    while let Some(token) = compiler.tokens.pop_front() {
+        compiler.line = token.line;
+        compiler
+            .statement(&token.text)
+            .map_err(|e| anyhow::anyhow!("line {}: {}", compiler.line, e))?;
+    }
+    compiler.finish()
+}
+
+/// Whether `text` looks like an Octo program, one with a `: main` label
+pub fn is_source(text: &str) -> bool {
+    let tokens = tokenize(text);
+    tokens
+        .iter()
+        .zip(tokens.iter().skip(1))
+        .any(|(colon, main)| colon.text == ":" && main.text == "main")
+}
+
+fn is_reserved(name: &str) -> bool {
+    matches!(
+        name,
+        ":=" | "+="
+            | "-="
+            | "=-"
+            | "|="
+            | "&="
+            | "^="
+            | ">>="
+            | "<<="
+            | "=="
+            | "!="
+            | "<"
+            | ">"
+            | "<="
+            | ">="
+            | "key"
+            | "-key"
+            | "hex"
+            | "bighex"
+            | "long"
+            | "random"
+            | "delay"
+            | "buzzer"
+            | "pitch"
+            | "i"
+            | "if"
+            | "then"
+            | "begin"
+            | "else"
+            | "end"
+            | "loop"
+            | "while"
+            | "again"
+            | "{"
+            | "}"
+            | "-"
+    )
+}
+
+fn parse_number(text: &str) -> Option<f64> {
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text),
+    };
+    let n = if let Some(hex) = digits.strip_prefix("0x") {
+        i64::from_str_radix(hex, 16).ok()? as f64
+    } else if let Some(bin) = digits.strip_prefix("0b") {
+        i64::from_str_radix(bin, 2).ok()? as f64
+    } else if digits.starts_with(|c: char| c.is_ascii_digit()) {
+        digits.parse::<f64>().ok()?
+    } else {
+        return None;
+    };
+    Some(if negative { -n } else { n })
+}
+
+impl Compiler {
+    fn next(&mut self) -> anyhow::Result<String> {
+        let token = self
+            .tokens
+            .pop_front()
+            .ok_or_else(|| anyhow::anyhow!("unexpected end of the program"))?;
+        self.line = token.line;
+        Ok(token.text)
+    }
+
+    fn expect(&mut self, text: &str) -> anyhow::Result<()> {
+        match self.next()? {
+            token if token == text => Ok(()),
+            token => anyhow::bail!("expected {}, found {}", text, token),
+        }
+    }
+
+    fn peek_is(&self, text: &str) -> bool {
+        self.tokens.front().is_some_and(|t| t.text == text)
+    }
+
+    fn byte(&mut self, byte: u8) -> anyhow::Result<()> {
+        if self.here >= MAX_ADDRESS {
+            anyhow::bail!(
+                "the program doesn't fit in {:#x} bytes of memory",
+                MAX_ADDRESS
+            );
+        }
+        let offset = self.here - CARTRIDGE_ADDRESS;
+        if self.rom.len() <= offset {
+            self.rom.resize(offset + 1, 0);
+        }
+        self.rom[offset] = byte;
+        self.here += 1;
+        Ok(())
+    }
+
+    fn emit(&mut self, op: u16) -> anyhow::Result<()> {
+        let [high, low] = op.to_be_bytes();
+        self.byte(high)?;
+        self.byte(low)
+    }
+
+    fn write_word(&mut self, addr: usize, word: u16) {
+        let offset = addr - CARTRIDGE_ADDRESS;
+        self.rom[offset..offset + 2].copy_from_slice(&word.to_be_bytes());
+    }
+
+    fn register(&self, text: &str) -> Option<u8> {
+        if let Some(x) = self.aliases.get(text) {
+            return Some(*x);
+        }
+        let digit = text.strip_prefix(['v', 'V'])?;
+        match u8::from_str_radix(digit, 16) {
+            Ok(x) if digit.len() == 1 => Some(x),
+            _ => None,
+        }
+    }
+
+    fn next_register(&mut self) -> anyhow::Result<u8> {
+        let token = self.next()?;
+        self.register(&token)
+            .ok_or_else(|| anyhow::anyhow!("expected a register, found {}", token))
+    }
+
+    fn constant(&self, text: &str) -> Option<f64> {
+        parse_number(text).or_else(|| self.constants.get(text).copied())
+    }
+
+    /// A number, constant or label defined so far
+    fn value(&self, text: &str) -> Option<f64> {
+        self.constant(text)
+            .or_else(|| self.labels.get(text).map(|a| *a as f64))
+    }
+
+    fn next_value(&mut self, min: i64, max: i64) -> anyhow::Result<i64> {
+        let token = self.next()?;
+        let value = match token.as_str() {
+            "{" => self.calc_block()?,
+            _ => self
+                .value(&token)
+                .ok_or_else(|| anyhow::anyhow!("unknown constant {}", token))?,
+        };
+        let n = value as i64;
+        if !(min..=max).contains(&n) {
+            anyhow::bail!("{} is out of range {}..={}", token, min, max);
+        }
+        Ok(n)
+    }
+
+    fn next_byte(&mut self) -> anyhow::Result<u8> {
+        Ok(self.next_value(-128, 255)? as u8)
+    }
+
+    fn next_nibble(&mut self) -> anyhow::Result<u8> {
+        Ok(self.next_value(0, 15)? as u8)
+    }
+
+    fn check_name(&self, name: &str) -> anyhow::Result<()> {
+        if is_reserved(name) || self.register(name).is_some() || parse_number(name).is_some() {
+            anyhow::bail!("{} can't be used as a name", name);
+        }
+        Ok(())
+    }
+
+    /// Writes the address of `name` now, or once it is defined
+    fn address(&mut self, at: usize, name: String, fixup: Fixup) -> anyhow::Result<()> {
+        match self.value(&name) {
+            Some(addr) => self.apply(at, addr as i64, fixup, &name),
+            None if self.check_name(&name).is_ok() => {
+                self.fixups.push((at, name, fixup, self.line));
+                Ok(())
+            }
+            None => anyhow::bail!("expected an address, found {}", name),
+        }
+    }
+
+    fn apply(&mut self, at: usize, addr: i64, fixup: Fixup, name: &str) -> anyhow::Result<()> {
+        let max = match fixup {
+            Fixup::Nnn => 0xFFF,
+            _ => 0xFFFF,
+        };
+        if !(0..=max).contains(&addr) {
+            anyhow::bail!("{} is {:#x}, out of range for this instruction", name, addr);
+        }
+        let offset = at - CARTRIDGE_ADDRESS;
+        match fixup {
+            Fixup::Nnn => {
+                let op = u16::from_be_bytes([self.rom[offset], 0]);
+                self.write_word(at, op | addr as u16);
+            }
+            Fixup::Word => self.write_word(at, addr as u16),
+            Fixup::High => self.rom[offset] |= (addr >> 8) as u8,
+            Fixup::Low => self.rom[offset] = addr as u8,
+        }
+        Ok(())
+    }
+
+    fn address_op(&mut self, op: u16) -> anyhow::Result<()> {
+        let at = self.here;
+        self.emit(op)?;
+        let name = self.next()?;
+        self.address(at, name, Fixup::Nnn)
+    }
+
+    fn define(&mut self, name: String, addr: usize) -> anyhow::Result<()> {
+        self.check_name(&name)?;
+        if self.labels.insert(name.clone(), addr).is_some() {
+            anyhow::bail!("label {} defined twice", name);
+        }
+        Ok(())
+    }
+
+    /// The tokens up to the `}` matching an already read `{`
+    fn block(&mut self) -> anyhow::Result<Vec<Token>> {
+        let mut depth = 0;
+        let mut body = vec![];
+        loop {
+            let token = self
+                .tokens
+                .pop_front()
+                .ok_or_else(|| anyhow::anyhow!("missing }}"))?;
+            match token.text.as_str() {
+                "{" => depth += 1,
+                "}" if depth == 0 => return Ok(body),
+                "}" => depth -= 1,
+                _ => {}
+            }
+            body.push(token);
+        }
+    }
+
+    fn calc_block(&mut self) -> anyhow::Result<f64> {
+        let tokens = self.block()?;
+        let texts: Vec<&str> = tokens.iter().map(|t| t.text.as_str()).collect();
+        match self.calc(&texts)? {
+            (value, []) => Ok(value),
+            (_, rest) => anyhow::bail!("unexpected {} in expression", rest[0]),
+        }
+    }
+
+    /// `term (op expression)?`, so operators apply right to left
+    fn calc<'a>(&self, tokens: &'a [&'a str]) -> anyhow::Result<(f64, &'a [&'a str])> {
+        let (lhs, rest) = self.calc_term(tokens)?;
+        let (op, rest) = match rest {
+            [] | [")", ..] => return Ok((lhs, rest)),
+            [op, rest @ ..] => (op, rest),
+        };
+        let (rhs, rest) = self.calc(rest)?;
+        let (a, b) = (lhs as i64, rhs as i64);
+        let bool = |b: bool| if b { 1.0 } else { 0.0 };
+        let value = match *op {
+            "+" => lhs + rhs,
+            "-" => lhs - rhs,
+            "*" => lhs * rhs,
+            "/" if rhs == 0.0 => anyhow::bail!("division by zero"),
+            "/" => lhs / rhs,
+            "%" if b == 0 => anyhow::bail!("division by zero"),
+            "%" => (a % b) as f64,
+            "&" => (a & b) as f64,
+            "|" => (a | b) as f64,
+            "^" => (a ^ b) as f64,
+            "<<" => (a << (b & 63)) as f64,
+            ">>" => (a >> (b & 63)) as f64,
+            "pow" => lhs.powf(rhs),
+            "min" => lhs.min(rhs),
+            "max" => lhs.max(rhs),
+            "<" => bool(lhs < rhs),
+            ">" => bool(lhs > rhs),
+            "<=" => bool(lhs <= rhs),
+            ">=" => bool(lhs >= rhs),
+            "==" => bool(lhs == rhs),
+            "!=" => bool(lhs != rhs),
+            _ => anyhow::bail!("unknown operator {}", op),
+        };
+        Ok((value, rest))
+    }
+
+    fn calc_term<'a>(&self, tokens: &'a [&'a str]) -> anyhow::Result<(f64, &'a [&'a str])> {
+        let Some((first, rest)) = tokens.split_first() else {
+            anyhow::bail!("expression ends early");
+        };
+        let unary = |f: fn(f64) -> f64| -> anyhow::Result<(f64, &'a [&'a str])> {
+            let (value, rest) = self.calc_term(rest)?;
+            Ok((f(value), rest))
+        };
+        match *first {
+            "(" => match self.calc(rest)? {
+                (value, [")", rest @ ..]) => Ok((value, rest)),
+                _ => anyhow::bail!("missing )"),
+            },
+            "-" => unary(|v| -v),
+            "~" => unary(|v| !(v as i64) as f64),
+            "!" => unary(|v| if v == 0.0 { 1.0 } else { 0.0 }),
+            "abs" => unary(f64::abs),
+            "sqrt" => unary(f64::sqrt),
+            "sin" => unary(f64::sin),
+            "cos" => unary(f64::cos),
+            "tan" => unary(f64::tan),
+            "exp" => unary(f64::exp),
+            "log" => unary(f64::ln),
+            "floor" => unary(f64::floor),
+            "ceil" => unary(f64::ceil),
+            "@" => {
+                let (addr, rest) = self.calc_term(rest)?;
+                let byte = (addr as usize)
+                    .checked_sub(CARTRIDGE_ADDRESS)
+                    .and_then(|offset| self.rom.get(offset))
+                    .copied()
+                    .unwrap_or(0);
+                Ok((byte as f64, rest))
+            }
+            "HERE" => Ok((self.here as f64, rest)),
+            "PI" => Ok((std::f64::consts::PI, rest)),
+            "E" => Ok((std::f64::consts::E, rest)),
+            name => match self.value(name) {
+                Some(value) => Ok((value, rest)),
+                None => anyhow::bail!("unknown constant {} in expression", name),
+            },
+        }
+    }
+
+    fn statement(&mut self, token: &str) -> anyhow::Result<()> {
+        if let Some(x) = self.register(token) {
+            return self.register_op(x);
+        }
+        if let Some((params, body)) = self.macros.get(token).cloned() {
+            return self.expand(params, body);
+        }
+        let xop = |base: u16, x: u8| base | (x as u16) << 8;
+        match token {
+            ":" => {
+                let name = self.next()?;
+                self.define(name, self.here)?;
+            }
+            ":alias" => {
+                let name = self.next()?;
+                let x = self.next_register()?;
+                self.check_name(&name)?;
+                self.aliases.insert(name, x);
+            }
+            ":const" => {
+                let name = self.next()?;
+                self.check_name(&name)?;
+                let value = self.next_value(i64::MIN, i64::MAX)?;
+                self.constants.insert(name, value as f64);
+            }
+            ":calc" => {
+                let name = self.next()?;
+                self.check_name(&name)?;
+                self.expect("{")?;
+                let value = self.calc_block()?;
+                self.constants.insert(name, value);
+            }
+            ":macro" => {
+                let name = self.next()?;
+                self.check_name(&name)?;
+                let mut params = vec![];
+                loop {
+                    match self.next()?.as_str() {
+                        "{" => break,
+                        param => params.push(param.to_string()),
+                    }
+                }
+                let body = self.block()?;
+                self.macros.insert(name, (params, body));
+            }
+            ":byte" => {
+                let byte = self.next_byte()?;
+                self.byte(byte)?;
+            }
+            ":pointer" => {
+                let at = self.here;
+                self.emit(0)?;
+                let name = self.next()?;
+                self.address(at, name, Fixup::Word)?;
+            }
+            ":org" => {
+                let addr = self.next_value(CARTRIDGE_ADDRESS as i64, MAX_ADDRESS as i64 - 1)?;
+                self.here = addr as usize;
+            }
+            ":next" => {
+                let name = self.next()?;
+                self.define(name, self.here + 1)?;
+            }
+            ":unpack" => {
+                let high = if self.peek_is("long") {
+                    self.next()?;
+                    0
+                } else {
+                    self.next_nibble()? << 4
+                };
+                let name = self.next()?;
+                self.emit(0x6000 | high as u16)?;
+                self.address(self.here - 1, name.clone(), Fixup::High)?;
+                self.emit(0x6100)?;
+                self.address(self.here - 1, name, Fixup::Low)?;
+            }
+            ":call" => self.address_op(0x2000)?,
+            ":breakpoint" => {
+                self.next()?;
+            }
+            ":monitor" => {
+                self.next()?;
+                self.next()?;
+            }
+            ":assert" => {
+                if self.tokens.front().is_some_and(|t| t.text.starts_with('"')) {
+                    self.next()?;
+                }
+                self.expect("{")?;
+                if self.calc_block()? == 0.0 {
+                    anyhow::bail!("assertion failed");
+                }
+            }
+            ":stringmode" => anyhow::bail!(":stringmode isn't supported"),
+            "return" | ";" => self.emit(0x00EE)?,
+            "clear" => self.emit(0x00E0)?,
+            "exit" => self.emit(0x00FD)?,
+            "lores" => self.emit(0x00FE)?,
+            "hires" => self.emit(0x00FF)?,
+            "scroll-right" => self.emit(0x00FB)?,
+            "scroll-left" => self.emit(0x00FC)?,
+            "scroll-down" => {
+                let n = self.next_nibble()?;
+                self.emit(0x00C0 | n as u16)?;
+            }
+            "scroll-up" => {
+                let n = self.next_nibble()?;
+                self.emit(0x00D0 | n as u16)?;
+            }
+            "audio" => self.emit(0xF002)?,
+            "plane" => {
+                let n = self.next_nibble()?;
+                self.emit(0xF001 | (n as u16) << 8)?;
+            }
+            "bcd" => {
+                let x = self.next_register()?;
+                self.emit(xop(0xF033, x))?;
+            }
+            "save" | "load" => {
+                let x = self.next_register()?;
+                if self.peek_is("-") {
+                    self.next()?;
+                    let y = self.next_register()?;
+                    let low = if token == "save" { 2 } else { 3 };
+                    self.emit(xop(0x5000 | (y as u16) << 4 | low, x))?;
+                } else {
+                    self.emit(xop(if token == "save" { 0xF055 } else { 0xF065 }, x))?;
+                }
+            }
+            "saveflags" => {
+                let x = self.next_register()?;
+                self.emit(xop(0xF075, x))?;
+            }
+            "loadflags" => {
+                let x = self.next_register()?;
+                self.emit(xop(0xF085, x))?;
+            }
+            "delay" | "buzzer" | "pitch" => {
+                self.expect(":=")?;
+                let x = self.next_register()?;
+                let low = match token {
+                    "delay" => 0x15,
+                    "buzzer" => 0x18,
+                    _ => 0x3A,
+                };
+                self.emit(xop(0xF000 | low, x))?;
+            }
+            "sprite" => {
+                let x = self.next_register()?;
+                let y = self.next_register()?;
+                let n = self.next_nibble()?;
+                self.emit(0xD000 | (x as u16) << 8 | (y as u16) << 4 | n as u16)?;
+            }
+            "jump" => self.address_op(0x1000)?,
+            "jump0" => self.address_op(0xB000)?,
+            "native" => self.address_op(0x0000)?,
+            "i" => self.i_op()?,
+            "if" => {
+                let (x, op, rhs) = self.condition()?;
+                match self.next()?.as_str() {
+                    "then" => self.skip_unless(x, op, rhs)?,
+                    "begin" => {
+                        self.skip_unless(x, negate(op), rhs)?;
+                        self.flow.push(Flow::If(self.here));
+                        self.emit(0x1000)?;
+                    }
+                    other => anyhow::bail!("expected then or begin, found {}", other),
+                }
+            }
+            "else" => {
+                let Some(Flow::If(jump)) = self.flow.pop() else {
+                    anyhow::bail!("else without if ... begin");
+                };
+                self.flow.push(Flow::Else(self.here));
+                self.emit(0x1000)?;
+                self.write_word(jump, 0x1000 | self.here as u16);
+            }
+            "end" => match self.flow.pop() {
+                Some(Flow::If(jump) | Flow::Else(jump)) => {
+                    self.write_word(jump, 0x1000 | self.here as u16)
+                }
+                _ => anyhow::bail!("end without if ... begin"),
+            },
+            "loop" => self.flow.push(Flow::Loop(self.here, vec![])),
+            "while" => {
+                let (x, op, rhs) = self.condition()?;
+                self.skip_unless(x, negate(op), rhs)?;
+                let at = self.here;
+                self.emit(0x1000)?;
+                match self
+                    .flow
+                    .iter_mut()
+                    .rev()
+                    .find(|f| matches!(f, Flow::Loop(..)))
+                {
+                    Some(Flow::Loop(_, exits)) => exits.push(at),
+                    _ => anyhow::bail!("while outside a loop"),
+                }
+            }
+            "again" => {
+                let Some(Flow::Loop(start, exits)) = self.flow.pop() else {
+                    anyhow::bail!("again without loop");
+                };
+                self.emit(0x1000 | start as u16)?;
+                for exit in exits {
+                    self.write_word(exit, 0x1000 | self.here as u16);
+                }
+            }
+            _ if self.constant(token).is_some() => {
+                let byte = self.constant(token).unwrap_or_default() as i64;
+                if !(-128..=255).contains(&byte) {
+                    anyhow::bail!("{} doesn't fit in a byte", token);
+                }
+                self.byte(byte as u8)?;
+            }
+            // a bare name is a call, to a label that may come later
+            name => {
+                self.check_name(name)?;
+                let at = self.here;
+                self.emit(0x2000)?;
+                self.address(at, name.to_string(), Fixup::Nnn)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn expand(&mut self, params: Vec<String>, body: Vec<Token>) -> anyhow::Result<()> {
+        let mut args = HashMap::new();
+        for param in params {
+            args.insert(param, self.next()?);
+        }
+        for token in body.into_iter().rev() {
+            let text = args.get(&token.text).cloned().unwrap_or(token.text);
+            self.tokens.push_front(Token {
+                text,
+                line: token.line,
+            });
+        }
+        Ok(())
+    }
+
+    fn register_op(&mut self, x: u8) -> anyhow::Result<()> {
+        let op = self.next()?;
+        let xy = |low: u16, y: u8| 0x8000 | (x as u16) << 8 | (y as u16) << 4 | low;
+        let xnn = |base: u16, n: u8| base | (x as u16) << 8 | n as u16;
+        let rhs = self.next()?;
+        let y = self.register(&rhs);
+        let opcode = match (op.as_str(), y) {
+            (":=", Some(y)) => xy(0, y),
+            (":=", None) if rhs == "random" => xnn(0xC000, self.next_byte()?),
+            (":=", None) if rhs == "key" => xnn(0xF00A, 0),
+            (":=", None) if rhs == "delay" => xnn(0xF007, 0),
+            ("+=", Some(y)) => xy(4, y),
+            ("-=", Some(y)) => xy(5, y),
+            ("=-", Some(y)) => xy(7, y),
+            ("|=", Some(y)) => xy(1, y),
+            ("&=", Some(y)) => xy(2, y),
+            ("^=", Some(y)) => xy(3, y),
+            (">>=", Some(y)) => xy(6, y),
+            ("<<=", Some(y)) => xy(0xE, y),
+            (":=" | "+=" | "-=", None) => {
+                self.tokens.push_front(Token {
+                    text: rhs,
+                    line: self.line,
+                });
+                let n = self.next_byte()?;
+                match op.as_str() {
+                    ":=" => xnn(0x6000, n),
+                    "+=" => xnn(0x7000, n),
+                    _ => xnn(0x7000, n.wrapping_neg()),
+                }
+            }
+            _ => anyhow::bail!("invalid operation v{:x} {} {}", x, op, rhs),
+        };
+        self.emit(opcode)
+    }
+
+    fn i_op(&mut self) -> anyhow::Result<()> {
+        let op = self.next()?;
+        let rhs = self.next()?;
+        match (op.as_str(), rhs.as_str()) {
+            (":=", "hex") => {
+                let x = self.next_register()?;
+                self.emit(0xF029 | (x as u16) << 8)
+            }
+            (":=", "bighex") => {
+                let x = self.next_register()?;
+                self.emit(0xF030 | (x as u16) << 8)
+            }
+            (":=", "long") => {
+                self.emit(0xF000)?;
+                let at = self.here;
+                self.emit(0)?;
+                let name = self.next()?;
+                self.address(at, name, Fixup::Word)
+            }
+            (":=", _) => {
+                let at = self.here;
+                self.emit(0xA000)?;
+                self.address(at, rhs, Fixup::Nnn)
+            }
+            ("+=", _) => match self.register(&rhs) {
+                Some(x) => self.emit(0xF01E | (x as u16) << 8),
+                None => anyhow::bail!("i += takes a register, found {}", rhs),
+            },
+            _ => anyhow::bail!("invalid operation i {} {}", op, rhs),
+        }
+    }
+
+    /// `vx op rhs`, or `vx key` and `vx -key`
+    fn condition(&mut self) -> anyhow::Result<(u8, String, Rhs)> {
+        let x = self.next_register()?;
+        let op = self.next()?;
+        if op == "key" || op == "-key" {
+            return Ok((x, op, Rhs::Byte(0)));
+        }
+        if !matches!(op.as_str(), "==" | "!=" | "<" | ">" | "<=" | ">=") {
+            anyhow::bail!("invalid comparison {}", op);
+        }
+        let rhs = match self.tokens.front().and_then(|t| self.register(&t.text)) {
+            Some(y) => {
+                self.next()?;
+                Rhs::Reg(y)
+            }
+            None => Rhs::Byte(self.next_byte()?),
+        };
+        Ok((x, op, rhs))
+    }
+
+    /// Emits the code that skips the next instruction unless `vx op rhs` holds.
+    /// Ordered comparisons go through VF, as in Octo.
+    fn skip_unless(&mut self, x: u8, op: String, rhs: Rhs) -> anyhow::Result<()> {
+        let x16 = (x as u16) << 8;
+        match (op.as_str(), &rhs) {
+            ("==", Rhs::Byte(n)) => self.emit(0x4000 | x16 | *n as u16),
+            ("==", Rhs::Reg(y)) => self.emit(0x9000 | x16 | (*y as u16) << 4),
+            ("!=", Rhs::Byte(n)) => self.emit(0x3000 | x16 | *n as u16),
+            ("!=", Rhs::Reg(y)) => self.emit(0x5000 | x16 | (*y as u16) << 4),
+            ("key", _) => self.emit(0xE0A1 | x16),
+            ("-key", _) => self.emit(0xE09E | x16),
+            _ => {
+                match rhs {
+                    Rhs::Byte(n) => self.emit(0x6F00 | n as u16)?,
+                    Rhs::Reg(y) => self.emit(0x8F00 | (y as u16) << 4)?,
+                }
+                // 8FX7 leaves VF = 1 when vx >= rhs, 8FX5 when vx <= rhs
+                let (low, holds) = match op.as_str() {
+                    ">=" => (7, 1),
+                    "<" => (7, 0),
+                    "<=" => (5, 1),
+                    _ => (5, 0),
+                };
+                self.emit(0x8F00 | (x as u16) << 4 | low)?;
+                self.emit(0x4F00 | holds)
+            }
+        }
+    }
+
+    fn finish(mut self) -> anyhow::Result<Program> {
+        if !self.flow.is_empty() {
+            anyhow::bail!("an if ... begin or loop isn't closed at the end of the program");
+        }
+        for (at, name, fixup, line) in std::mem::take(&mut self.fixups) {
+            let Some(addr) = self.labels.get(&name).copied() else {
+                anyhow::bail!("line {}: undefined label {}", line, name);
+            };
+            self.apply(at, addr as i64, fixup, &name)
+                .map_err(|e| anyhow::anyhow!("line {}: {}", line, e))?;
+        }
+        Ok(Program {
+            bytes: self.rom,
+            labels: self.labels,
+        })
+    }
+}
+
+fn negate(op: String) -> String {
+    match op.as_str() {
+        "==" => "!=",
+        "!=" => "==",
+        "key" => "-key",
+        "-key" => "key",
+        "<" => ">=",
+        ">=" => "<",
+        ">" => "<=",
+        _ => ">",
+    }
+    .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bytes(source: &str) -> Vec<u8> {
+        compile(source).unwrap().bytes
+    }
+
+    #[test]
+    fn calls_are_subroutine_calls() {
+        let source = ": main\n :call sub\n sub\n native 0x123\n jump main\n: sub\n return";
+        assert_eq!(
+            bytes(source),
+            [0x22, 0x08, 0x22, 0x08, 0x01, 0x23, 0x12, 0x00, 0x00, 0xEE]
+        );
+    }
+
+    #[test]
+    fn programs_not_at_main_jump_to_it() {
+        assert_eq!(
+            bytes(": data 0xFF\n: main clear"),
+            [0x12, 0x03, 0xFF, 0x00, 0xE0]
+        );
+    }
+
+    #[test]
+    fn macros_expand_with_their_arguments() {
+        let source = ":macro twice reg { reg += 1 reg += 1 }\n: main\n twice v3\n twice v4";
+        assert_eq!(
+            bytes(source),
+            [0x12, 0x02, 0x73, 0x01, 0x73, 0x01, 0x74, 0x01, 0x74, 0x01]
+        );
+    }
+
+    #[test]
+    fn calc_evaluates_right_to_left() {
+        let source = ":calc size { 10 - 4 - 3 }\n:const base 2\n: main\n v0 := size\n v1 := base";
+        // the jump to main comes first, as the program doesn't start with it
+        assert_eq!(bytes(source), [0x12, 0x02, 0x60, 0x09, 0x61, 0x02]);
+    }
+
+    #[test]
+    fn loops_and_conditions_become_skips_and_jumps() {
+        let source = ": main\n loop\n v0 += 1\n while v0 != 5\n again\n if v0 == 3 then v1 := 2";
+        assert_eq!(
+            bytes(source),
+            [
+                0x70, 0x01, 0x40, 0x05, 0x12, 0x08, 0x12, 0x00, 0x40, 0x03, 0x61, 0x02
+            ]
+        );
+
+        let source = ": main\n if v0 == v1 begin v2 := 1 else v2 := 2 end";
+        assert_eq!(
+            bytes(source),
+            [0x50, 0x10, 0x12, 0x08, 0x62, 0x01, 0x12, 0x0A, 0x62, 0x02]
+        );
+    }
+}
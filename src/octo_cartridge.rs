@@ -0,0 +1,163 @@
+//! Octo cartridges: GIF images with a program and its settings hidden in the
+//! colour indices of their pixels.
+//!
+//! The low two bits of four pixels make a byte, high bits first, running through
+//! every frame. The bytes are a 32-bit big-endian length and that much JSON,
+//! `{"program": "<Octo source>", "options": {...}}`.
+
+use crate::config::{self, Config};
+use crate::quirks::Platform;
+use crate::theme;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+
+/// Settings of the Octo IDE saved with the program
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Options {
+    /// instructions per frame
+    tickrate: Option<u64>,
+    background_color: Option<String>,
+    fill_color: Option<String>,
+    fill_color2: Option<String>,
+    blend_color: Option<String>,
+    shift_quirks: Option<bool>,
+    load_store_quirks: Option<bool>,
+    clip_quirks: Option<bool>,
+    jump_quirks: Option<bool>,
+    logic_quirks: Option<bool>,
+    /// 3583 bytes on SUPER-CHIP, 65024 on XO-CHIP
+    max_size: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Cartridge {
+    /// Octo source
+    pub program: String,
+    #[serde(default)]
+    pub options: Options,
+}
+
+pub fn is_cartridge(bytes: &[u8]) -> bool {
+    bytes.starts_with(b"GIF87a") || bytes.starts_with(b"GIF89a")
+}
+
+pub fn decode(bytes: &[u8]) -> anyhow::Result<Cartridge> {
+    let mut options = gif::DecodeOptions::new();
+    options.set_color_output(gif::ColorOutput::Indexed);
+    let mut decoder = options.read_info(bytes)?;
+    let mut data = vec![];
+    while let Some(frame) = decoder.read_next_frame()? {
+        for pixels in frame.buffer.chunks_exact(4) {
+            data.push(pixels.iter().fold(0, |byte, p| byte << 2 | (p & 3)));
+        }
+    }
+    let not_a_cartridge = || anyhow::anyhow!("a GIF image but not an Octo cartridge");
+    let (size, payload) = data.split_first_chunk::<4>().ok_or_else(not_a_cartridge)?;
+    let payload = payload
+        .get(..u32::from_be_bytes(*size) as usize)
+        .ok_or_else(not_a_cartridge)?;
+    serde_json::from_slice(payload).map_err(|_| not_a_cartridge())
+}
+
+impl Options {
+    /// The settings as a layer below the user's config files
+    pub fn config(&self) -> anyhow::Result<Config> {
+        let mut quirks = BTreeMap::new();
+        let mut add = |name: &str, value: Option<bool>| {
+            if let Some(value) = value {
+                quirks.insert(name.to_string(), value);
+            }
+        };
+        add("shift_vy", self.shift_quirks.map(|shift| !shift));
+        add(
+            "load_store_increment",
+            self.load_store_quirks.map(|leave| !leave),
+        );
+        add("clip", self.clip_quirks);
+        add("jump_vx", self.jump_quirks);
+        add("logic_vf_reset", self.logic_quirks);
+
+        let color = |c: &Option<String>| c.as_deref().map(theme::parse_color).transpose();
+        let (bg, fg) = (color(&self.background_color)?, color(&self.fill_color)?);
+        let planes = (fg, color(&self.fill_color2)?, color(&self.blend_color)?);
+        let palette = match (bg, planes) {
+            (Some(bg), (Some(fg), Some(fg2), Some(blend))) => vec![bg, fg, fg2, blend],
+            _ => vec![],
+        };
+        Ok(Config {
+            cycle_micro: self.tickrate.and_then(config::cycle_micro),
+            platform: match self.max_size {
+                Some(size) if size > 0xE00 => Some(Platform::Xochip),
+                Some(0xDFF) => Some(Platform::Schip),
+                _ => None,
+            },
+            quirks,
+            fg,
+            bg,
+            palette,
+            ..Default::default()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A GIF carrying `data` in the low bits of its pixels
+    fn image(data: &[u8]) -> Vec<u8> {
+        let mut pixels: Vec<u8> = data
+            .iter()
+            .flat_map(|byte| [6, 4, 2, 0].map(|shift| byte >> shift & 3))
+            .collect();
+        let width = 32;
+        pixels.resize(pixels.len().div_ceil(width) * width, 0);
+
+        let palette = [
+            0, 0, 0, 0x55, 0x55, 0x55, 0xAA, 0xAA, 0xAA, 0xFF, 0xFF, 0xFF,
+        ];
+        let height = (pixels.len() / width) as u16;
+        let mut gif = vec![];
+        let mut encoder = gif::Encoder::new(&mut gif, width as u16, height, &palette).unwrap();
+        let frame = gif::Frame {
+            width: width as u16,
+            height,
+            buffer: pixels.into(),
+            ..Default::default()
+        };
+        encoder.write_frame(&frame).unwrap();
+        drop(encoder);
+        gif
+    }
+
+    /// A cartridge holding `json`, as Octo saves one
+    fn cartridge(json: &str) -> Vec<u8> {
+        let mut data = (json.len() as u32).to_be_bytes().to_vec();
+        data.extend(json.as_bytes());
+        image(&data)
+    }
+
+    #[test]
+    fn the_program_and_options_come_out_of_the_pixels() {
+        let json = r#"{"program": ": main\n jump main", "options": {"tickrate": 20, "shiftQuirks": true, "maxSize": 3583}}"#;
+        let gif = cartridge(json);
+        assert!(is_cartridge(&gif));
+
+        let cartridge = decode(&gif).unwrap();
+        assert_eq!(cartridge.program, ": main\n jump main");
+        let config = cartridge.options.config().unwrap();
+        assert_eq!(config.platform, Some(Platform::Schip));
+        assert_eq!(config.quirks, [("shift_vy".to_string(), false)].into());
+        assert!(config.cycle_micro.is_some());
+    }
+
+    #[test]
+    fn an_ordinary_gif_is_not_a_cartridge() {
+        // a length running past the end of the image
+        assert!(decode(&image(&[0, 0, 1, 0, b'{', b'}'])).is_err());
+        assert!(decode(&cartridge("not json")).is_err());
+        assert!(decode(&cartridge(r#"{"options": {}}"#)).is_err());
+        assert!(decode(b"GIF89a").is_err());
+    }
+}
@@ -1,3 +1,4 @@
+use crate::symbols::SymbolMap;
 use std::fmt;
 
 #[derive(Clone)]
@@ -154,7 +155,7 @@ impl fmt::Display for Operation {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         use Operation::*;
         match self {
-            CallSysC(addr) => write!(f, "call_sys {:0>12x}", addr),
+            CallSysC(addr) => write!(f, "call_sys #{:x}", addr),
             Clear => write!(f, "cls"),
             Return => write!(f, "ret"),
             JumpC(addr) => write!(f, "jmp #{:x}", addr),
@@ -194,6 +195,81 @@ impl fmt::Display for Operation {
     }
 }
 
+impl Operation {
+    /// The opcode pattern, such as `8XY4`
+    pub fn pattern(&self) -> &'static str {
+        use Operation::*;
+        match self {
+            CallSysC(_) => "0NNN",
+            Clear => "00E0",
+            Return => "00EE",
+            JumpC(_) => "1NNN",
+            CallC(_) => "2NNN",
+            SkipEqC(..) => "3XNN",
+            SkipNeC(..) => "4XNN",
+            SkipEq(..) => "5XY0",
+            SetC(..) => "6XNN",
+            AddC(..) => "7XNN",
+            Set(..) => "8XY0",
+            Or(..) => "8XY1",
+            And(..) => "8XY2",
+            Xor(..) => "8XY3",
+            Add(..) => "8XY4",
+            Sub(..) => "8XY5",
+            Shr(..) => "8XY6",
+            SubRev(..) => "8XY7",
+            Shl(..) => "8XYE",
+            SkipNe(..) => "9XY0",
+            SetIC(_) => "ANNN",
+            JumpV0C(_) => "BNNN",
+            RandC(..) => "CXNN",
+            DrawC(..) => "DXYN",
+            SkipEqKey(_) => "EX9E",
+            SkipNeKey(_) => "EXA1",
+            GetDelayTimer(_) => "FX07",
+            GetKey(_) => "FX0A",
+            SetDelayTimer(_) => "FX15",
+            SetSoundTimer(_) => "FX18",
+            AddI(_) => "FX1E",
+            SetIFont(_) => "FX29",
+            Bcd(_) => "FX33",
+            Store(_) => "FX55",
+            Restore(_) => "FX65",
+            Unknown(_) => "????",
+        }
+    }
+}
+
+/// Displays an operation with its address operand replaced by a label when one is known
+pub struct Labeled<'a> {
+    operation: &'a Operation,
+    symbols: &'a SymbolMap,
+}
+
+impl Operation {
+    pub fn labeled<'a>(&'a self, symbols: &'a SymbolMap) -> Labeled<'a> {
+        Labeled {
+            operation: self,
+            symbols,
+        }
+    }
+}
+
+impl fmt::Display for Labeled<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use Operation::*;
+        let label = |addr: &u16| self.symbols.label(*addr as usize);
+        match self.operation {
+            CallSysC(addr) if let Some(l) = label(addr) => write!(f, "call_sys {}", l),
+            JumpC(addr) if let Some(l) = label(addr) => write!(f, "jmp {}", l),
+            CallC(addr) if let Some(l) = label(addr) => write!(f, "call {}", l),
+            SetIC(addr) if let Some(l) = label(addr) => write!(f, "set vI, {}", l),
+            JumpV0C(addr) if let Some(l) = label(addr) => write!(f, "jmp vI({})", l),
+            op => write!(f, "{}", op),
+        }
+    }
+}
+
 pub fn parse_opcode(opcode: u16) -> Operation {
     use Operation::*;
     let op_category = op0(opcode);
@@ -0,0 +1,431 @@
+//! IPS and BPS patches, the way fixes and translations of ROMs are shared.
+//!
+//! The format of a patch is told by its header. A BPS patch carries the CRC32 of
+//! the ROM it was made from, of the result and of itself, and all three are
+//! checked, so a patch applied to the wrong ROM fails instead of corrupting it.
+
+use crate::machine::MEMORY_SIZE;
+use clap::ValueEnum;
+use flate2::Crc;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const IPS_HEADER: &[u8] = b"PATCH";
+const IPS_END: &[u8] = b"EOF";
+/// The largest offset an IPS record can hold
+const IPS_MAX_SIZE: usize = 0xFFFFFF;
+const BPS_HEADER: &[u8] = b"BPS1";
+/// Three CRC32s
+const BPS_FOOTER_SIZE: usize = 12;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Format {
+    Ips,
+    Bps,
+}
+
+impl Format {
+    /// The format for a patch file name, from its extension
+    pub fn from_path(path: &Path) -> Option<Format> {
+        let ext = path.extension()?.to_str()?.to_lowercase();
+        match ext.as_str() {
+            "ips" => Some(Format::Ips),
+            "bps" => Some(Format::Bps),
+            _ => None,
+        }
+    }
+}
+
+pub struct Patch {
+    path: PathBuf,
+    format: Format,
+    bytes: Vec<u8>,
+}
+
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = Crc::new();
+    crc.update(bytes);
+    crc.sum()
+}
+
+impl Patch {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let bytes = fs::read(path).map_err(|e| anyhow::anyhow!("{}: {}", path.display(), e))?;
+        let format = if bytes.starts_with(IPS_HEADER) {
+            Format::Ips
+        } else if bytes.starts_with(BPS_HEADER) {
+            Format::Bps
+        } else {
+            anyhow::bail!("{}: not an IPS or BPS patch", path.display());
+        };
+        Ok(Patch {
+            path: path.to_path_buf(),
+            format,
+            bytes,
+        })
+    }
+
+    pub fn apply(&self, rom: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let patched = match self.format {
+            Format::Ips => apply_ips(&self.bytes, rom),
+            Format::Bps => apply_bps(&self.bytes, rom),
+        };
+        patched.map_err(|e| anyhow::anyhow!("{}: {}", self.path.display(), e))
+    }
+}
+
+/// `rom` with each patch applied in turn
+pub fn apply_all(patches: &[Patch], rom: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+    patches.iter().try_fold(rom, |rom, patch| patch.apply(&rom))
+}
+
+/// A patch that turns `original` into `modified`
+pub fn create(format: Format, original: &[u8], modified: &[u8]) -> anyhow::Result<Vec<u8>> {
+    match format {
+        Format::Ips => create_ips(original, modified),
+        Format::Bps => Ok(create_bps(original, modified)),
+    }
+}
+
+fn be(bytes: &[u8]) -> usize {
+    bytes.iter().fold(0, |n, b| n << 8 | *b as usize)
+}
+
+fn write_at(rom: &mut Vec<u8>, offset: usize, data: &[u8]) -> anyhow::Result<()> {
+    let end = offset + data.len();
+    if end > MEMORY_SIZE {
+        anyhow::bail!("the patch writes up to {:#x}, past the end of memory", end);
+    }
+    if rom.len() < end {
+        rom.resize(end, 0);
+    }
+    rom[offset..end].copy_from_slice(data);
+    Ok(())
+}
+
+/// Records of a 3-byte offset, a 2-byte size and the data, or a size of 0, a
+/// 2-byte count and a byte to repeat. `EOF` ends them, optionally followed by
+/// the size to truncate the ROM to.
+fn apply_ips(patch: &[u8], rom: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let mut rom = rom.to_vec();
+    let mut pos = IPS_HEADER.len();
+    let mut take = |n: usize| -> anyhow::Result<&[u8]> {
+        let bytes = patch
+            .get(pos..pos + n)
+            .ok_or_else(|| anyhow::anyhow!("the patch ends before its EOF marker"))?;
+        pos += n;
+        Ok(bytes)
+    };
+    loop {
+        let offset = take(3)?;
+        if offset == IPS_END {
+            break;
+        }
+        let offset = be(offset);
+        match be(take(2)?) {
+            0 => {
+                let count = be(take(2)?);
+                let value = take(1)?[0];
+                write_at(&mut rom, offset, &vec![value; count])?;
+            }
+            size => write_at(&mut rom, offset, take(size)?)?,
+        }
+    }
+    if let Ok(size) = take(3) {
+        rom.truncate(be(size));
+    }
+    Ok(rom)
+}
+
+fn create_ips(original: &[u8], modified: &[u8]) -> anyhow::Result<Vec<u8>> {
+    if modified.len() > IPS_MAX_SIZE {
+        anyhow::bail!("IPS can't address past {:#x}, use BPS", IPS_MAX_SIZE);
+    }
+    let same = |i: usize| original.get(i) == Some(&modified[i]);
+    let mut patch = IPS_HEADER.to_vec();
+    let mut i = 0;
+    while i < modified.len() {
+        if same(i) {
+            i += 1;
+            continue;
+        }
+        // a record at the offset spelling "EOF" would read as the end
+        let start = if i == be(IPS_END) { i - 1 } else { i };
+        let mut end = i;
+        while end < modified.len() && end - start < 0xFFFF && !same(end) {
+            end += 1;
+        }
+        patch.extend(&(start as u32).to_be_bytes()[1..]);
+        patch.extend(((end - start) as u16).to_be_bytes());
+        patch.extend(&modified[start..end]);
+        i = end;
+    }
+    patch.extend(IPS_END);
+    if modified.len() < original.len() {
+        patch.extend(&(modified.len() as u32).to_be_bytes()[1..]);
+    }
+    Ok(patch)
+}
+
+/// BPS numbers: 7 bits per byte, low first, the last byte with its top bit set
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl Reader<'_> {
+    fn take(&mut self, n: usize) -> anyhow::Result<&[u8]> {
+        let bytes = self
+            .bytes
+            .get(self.pos..self.pos.saturating_add(n))
+            .ok_or_else(|| anyhow::anyhow!("the patch is truncated"))?;
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    fn number(&mut self) -> anyhow::Result<usize> {
+        let overflow = || anyhow::anyhow!("a number in the patch overflows");
+        let (mut n, mut shift) = (0usize, 1usize);
+        loop {
+            let byte = self.take(1)?[0];
+            n = ((byte & 0x7F) as usize)
+                .checked_mul(shift)
+                .and_then(|bits| n.checked_add(bits))
+                .ok_or_else(overflow)?;
+            if byte & 0x80 != 0 {
+                return Ok(n);
+            }
+            shift = shift.checked_mul(0x80).ok_or_else(overflow)?;
+            n = n.checked_add(shift).ok_or_else(overflow)?;
+        }
+    }
+
+    fn signed(&mut self) -> anyhow::Result<isize> {
+        let n = self.number()?;
+        let magnitude = (n >> 1) as isize;
+        Ok(if n & 1 != 0 { -magnitude } else { magnitude })
+    }
+}
+
+fn push_number(patch: &mut Vec<u8>, mut n: usize) {
+    loop {
+        let low = (n & 0x7F) as u8;
+        n >>= 7;
+        if n == 0 {
+            patch.push(0x80 | low);
+            return;
+        }
+        patch.push(low);
+        n -= 1;
+    }
+}
+
+fn apply_bps(patch: &[u8], rom: &[u8]) -> anyhow::Result<Vec<u8>> {
+    if patch.len() < BPS_HEADER.len() + BPS_FOOTER_SIZE {
+        anyhow::bail!("the patch is truncated");
+    }
+    let (body, footer) = patch.split_at(patch.len() - BPS_FOOTER_SIZE);
+    let crc = |i: usize| u32::from_le_bytes([0, 1, 2, 3].map(|b| footer[i * 4 + b]));
+    if crc32(&patch[..patch.len() - 4]) != crc(2) {
+        anyhow::bail!("the patch is damaged, its CRC32 doesn't match");
+    }
+    if crc32(rom) != crc(0) {
+        anyhow::bail!(
+            "the patch is for a ROM with CRC32 {:08x}, this one has {:08x}",
+            crc(0),
+            crc32(rom)
+        );
+    }
+
+    let mut reader = Reader {
+        bytes: body,
+        pos: BPS_HEADER.len(),
+    };
+    let source_size = reader.number()?;
+    let target_size = reader.number()?;
+    let metadata_size = reader.number()?;
+    reader.take(metadata_size)?;
+    if source_size != rom.len() {
+        anyhow::bail!("the patch is for a {} byte ROM", source_size);
+    }
+    if target_size > MEMORY_SIZE {
+        anyhow::bail!(
+            "the patch makes a {} byte ROM, larger than memory",
+            target_size
+        );
+    }
+    let out_of_range = || anyhow::anyhow!("the patch copies from outside the ROM");
+    let mut target: Vec<u8> = Vec::with_capacity(target_size);
+    let (mut source_offset, mut target_offset) = (0isize, 0isize);
+    while reader.pos < body.len() {
+        let action = reader.number()?;
+        let length = (action >> 2) + 1;
+        if target.len() + length > target_size {
+            anyhow::bail!(
+                "the patch writes past the {} byte ROM it makes",
+                target_size
+            );
+        }
+        match action & 3 {
+            // source read, from the same offset as the output
+            0 => {
+                let start = target.len();
+                target.extend(rom.get(start..start + length).ok_or_else(out_of_range)?);
+            }
+            // target read, from the patch
+            1 => target.extend(reader.take(length)?),
+            // source copy, from anywhere in the ROM
+            2 => {
+                source_offset += reader.signed()?;
+                let start = usize::try_from(source_offset).map_err(|_| out_of_range())?;
+                target.extend(rom.get(start..start + length).ok_or_else(out_of_range)?);
+                source_offset += length as isize;
+            }
+            // target copy, from output already written, which may overlap
+            _ => {
+                target_offset += reader.signed()?;
+                for _ in 0..length {
+                    let from = usize::try_from(target_offset).map_err(|_| out_of_range())?;
+                    let byte = *target.get(from).ok_or_else(out_of_range)?;
+                    target.push(byte);
+                    target_offset += 1;
+                }
+            }
+        }
+    }
+    if target.len() != target_size || crc32(&target) != crc(1) {
+        anyhow::bail!("the patched ROM doesn't match the CRC32 the patch expects");
+    }
+    Ok(target)
+}
+
+/// Runs of bytes that are unchanged, read from the ROM, and of changed bytes
+fn create_bps(original: &[u8], modified: &[u8]) -> Vec<u8> {
+    let same = |i: usize| original.get(i) == Some(&modified[i]);
+    let mut patch = BPS_HEADER.to_vec();
+    push_number(&mut patch, original.len());
+    push_number(&mut patch, modified.len());
+    push_number(&mut patch, 0);
+    let mut i = 0;
+    while i < modified.len() {
+        let (start, unchanged) = (i, same(i));
+        while i < modified.len() && same(i) == unchanged {
+            i += 1;
+        }
+        let action = if unchanged { 0 } else { 1 };
+        push_number(&mut patch, (i - start - 1) << 2 | action);
+        if !unchanged {
+            patch.extend(&modified[start..i]);
+        }
+    }
+    patch.extend(crc32(original).to_le_bytes());
+    patch.extend(crc32(modified).to_le_bytes());
+    patch.extend(crc32(&patch).to_le_bytes());
+    patch
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ROM: [u8; 4] = [0x60, 0x05, 0x12, 0x02];
+
+    /// A BPS patch of `ROM` with `body` after the header and valid CRC32s,
+    /// expecting an empty result
+    fn bps(body: &[u8]) -> Vec<u8> {
+        let mut patch = BPS_HEADER.to_vec();
+        patch.extend(body);
+        patch.extend(crc32(&ROM).to_le_bytes());
+        patch.extend(crc32(&[]).to_le_bytes());
+        patch.extend(crc32(&patch).to_le_bytes());
+        patch
+    }
+
+    fn sizes(source: usize, target: usize) -> Vec<u8> {
+        let mut body = vec![];
+        push_number(&mut body, source);
+        push_number(&mut body, target);
+        push_number(&mut body, 0);
+        body
+    }
+
+    #[test]
+    fn ips_round_trips() {
+        let modified = [0x60, 0x07, 0x12, 0x02, 0x00, 0xE0];
+        let patch = create_ips(&ROM, &modified).unwrap();
+        assert_eq!(apply_ips(&patch, &ROM).unwrap(), modified);
+        let shorter = create_ips(&ROM, &ROM[..2]).unwrap();
+        assert_eq!(apply_ips(&shorter, &ROM).unwrap(), ROM[..2]);
+    }
+
+    #[test]
+    fn ips_runs_repeat_a_byte() {
+        let mut patch = IPS_HEADER.to_vec();
+        // 3 bytes of 0xAA at offset 2
+        patch.extend([0, 0, 2, 0, 0, 0, 3, 0xAA]);
+        patch.extend(IPS_END);
+        assert_eq!(
+            apply_ips(&patch, &ROM).unwrap(),
+            [0x60, 0x05, 0xAA, 0xAA, 0xAA]
+        );
+    }
+
+    #[test]
+    fn ips_records_past_memory_are_refused() {
+        let mut patch = IPS_HEADER.to_vec();
+        // a run at the last offset IPS can address
+        patch.extend([0xFF, 0xFF, 0xFE, 0, 0, 0xFF, 0xFF, 0]);
+        patch.extend(IPS_END);
+        let err = apply_ips(&patch, &ROM).unwrap_err();
+        assert!(
+            err.to_string().contains("past the end of memory"),
+            "{}",
+            err
+        );
+
+        let mut patch = IPS_HEADER.to_vec();
+        patch.extend([0x00, 0x10, 0x00, 0, 1, 0xFF]);
+        patch.extend(IPS_END);
+        assert!(apply_ips(&patch, &ROM).is_err());
+    }
+
+    #[test]
+    fn bps_round_trips() {
+        let modified = [0x60, 0x07, 0x12, 0x02, 0x00];
+        let patch = create_bps(&ROM, &modified);
+        assert_eq!(apply_bps(&patch, &ROM).unwrap(), modified);
+    }
+
+    #[test]
+    fn bps_numbers_that_overflow_are_refused() {
+        let mut body = vec![0; 12];
+        body.push(0x80);
+        let err = apply_bps(&bps(&body), &ROM).unwrap_err();
+        assert!(err.to_string().contains("overflows"), "{}", err);
+    }
+
+    #[test]
+    fn bps_targets_larger_than_memory_are_refused() {
+        let err = apply_bps(&bps(&sizes(ROM.len(), usize::MAX / 2)), &ROM).unwrap_err();
+        assert!(err.to_string().contains("larger than memory"), "{}", err);
+    }
+
+    #[test]
+    fn bps_actions_past_the_target_are_refused() {
+        let mut body = sizes(ROM.len(), 4);
+        // a target copy of 2^40 bytes
+        push_number(&mut body, (1 << 40) << 2 | 3);
+        push_number(&mut body, 0);
+        let err = apply_bps(&bps(&body), &ROM).unwrap_err();
+        assert!(err.to_string().contains("writes past"), "{}", err);
+    }
+
+    #[test]
+    fn bps_metadata_past_the_patch_is_refused() {
+        let mut body = sizes(ROM.len(), 4);
+        body.pop();
+        push_number(&mut body, usize::MAX);
+        let err = apply_bps(&bps(&body), &ROM).unwrap_err();
+        assert!(err.to_string().contains("truncated"), "{}", err);
+    }
+}
@@ -0,0 +1,160 @@
+//! Behaviours that differ between CHIP-8 interpreters.
+//!
+//! A [`Platform`] picks a set of quirks, `--quirk name=true|false` then changes
+//! single ones.
+
+use clap::ValueEnum;
+use serde::Deserialize;
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Platform {
+    /// the original COSMAC VIP interpreter
+    #[default]
+    Vip,
+    /// the ETI-660, CHIP-8 with programs loaded at 0x600
+    Eti660,
+    /// CHIP-48 on the HP-48
+    Chip48,
+    /// SUPER-CHIP 1.1
+    Schip,
+    /// Octo's XO-CHIP
+    Xochip,
+}
+
+impl Platform {
+    pub fn quirks(&self) -> Quirks {
+        match self {
+            Platform::Vip | Platform::Eti660 => Quirks {
+                shift_vy: true,
+                load_store_increment: true,
+                logic_vf_reset: true,
+                jump_vx: false,
+                clip: true,
+            },
+            Platform::Chip48 => Quirks {
+                shift_vy: false,
+                load_store_increment: true,
+                logic_vf_reset: false,
+                jump_vx: true,
+                clip: true,
+            },
+            Platform::Schip => Quirks {
+                shift_vy: false,
+                load_store_increment: false,
+                logic_vf_reset: false,
+                jump_vx: true,
+                clip: true,
+            },
+            Platform::Xochip => Quirks {
+                shift_vy: true,
+                load_store_increment: true,
+                logic_vf_reset: false,
+                jump_vx: false,
+                clip: false,
+            },
+        }
+    }
+}
+
+impl fmt::Display for Platform {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Platform::Vip => "CHIP-8",
+            Platform::Eti660 => "ETI-660",
+            Platform::Chip48 => "CHIP-48",
+            Platform::Schip => "SUPER-CHIP",
+            Platform::Xochip => "XO-CHIP",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Quirks {
+    /// `8XY6`/`8XYE` shift VY into VX instead of shifting VX in place
+    pub shift_vy: bool,
+    /// `FX55`/`FX65` leave I pointing past the last register
+    pub load_store_increment: bool,
+    /// `8XY1`-`8XY3` clear VF
+    pub logic_vf_reset: bool,
+    /// `BNNN` jumps to NNN plus VX, X being the top nibble of NNN, instead of V0
+    pub jump_vx: bool,
+    /// sprites are cut at the display edges instead of wrapping around
+    pub clip: bool,
+}
+
+impl Default for Quirks {
+    fn default() -> Self {
+        Platform::default().quirks()
+    }
+}
+
+/// Quirk names accepted by `--quirk`
+pub const NAMES: [&str; 5] = [
+    "shift_vy",
+    "load_store_increment",
+    "logic_vf_reset",
+    "jump_vx",
+    "clip",
+];
+
+impl Quirks {
+    pub fn set(&mut self, name: &str, value: bool) -> anyhow::Result<()> {
+        let quirk = match name {
+            "shift_vy" => &mut self.shift_vy,
+            "load_store_increment" => &mut self.load_store_increment,
+            "logic_vf_reset" => &mut self.logic_vf_reset,
+            "jump_vx" => &mut self.jump_vx,
+            "clip" => &mut self.clip,
+            _ => anyhow::bail!(
+                "unknown quirk {}, expected one of {}",
+                name,
+                NAMES.join(", ")
+            ),
+        };
+        *quirk = value;
+        Ok(())
+    }
+
+    /// The name of `platform` while these are its quirks, or `custom` once some
+    /// were changed
+    pub fn describe(&self, platform: Platform) -> String {
+        if platform.quirks() == *self {
+            platform.to_string()
+        } else {
+            "custom".to_string()
+        }
+    }
+}
+
+/// Parses a `--quirk` override such as `shift_vy=false`
+pub fn parse_quirk(s: &str) -> anyhow::Result<(String, bool)> {
+    let (name, value) = s.split_once('=').unwrap_or((s, "true"));
+    let value = match value {
+        "true" | "on" | "1" => true,
+        "false" | "off" | "0" => false,
+        _ => anyhow::bail!("invalid quirk value {}, expected true or false", value),
+    };
+    let name = name.trim().replace('-', "_");
+    Quirks::default().set(&name, value)?;
+    Ok((name, value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn platforms_sharing_quirks_keep_their_names() {
+        let eti660 = Platform::Eti660.quirks();
+        assert_eq!(eti660, Platform::Vip.quirks());
+        assert_eq!(eti660.describe(Platform::Eti660), "ETI-660");
+        assert_eq!(eti660.describe(Platform::Vip), "CHIP-8");
+
+        let mut changed = eti660;
+        changed.set("clip", false).unwrap();
+        assert_eq!(changed.describe(Platform::Eti660), "custom");
+    }
+}
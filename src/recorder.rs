@@ -0,0 +1,133 @@
+use crate::machine::{DISPLAY_HEIGHT, DISPLAY_WIDTH};
+use crate::screenshot;
+use crate::theme::{self, Theme};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+type Display = [[bool; DISPLAY_WIDTH]; DISPLAY_HEIGHT];
+
+/// GIF delays are in hundredths of a second and most viewers stretch anything
+/// shorter than two
+const MIN_GIF_DELAY: u16 = 2;
+
+enum Output {
+    Gif(gif::Encoder<io::BufWriter<fs::File>>),
+    /// numbered PNG files in a directory, named after the frame they appear on
+    Frames(PathBuf),
+}
+
+/// Captures the display once per 60 Hz frame, frames identical to the previous
+/// one are dropped and lengthen it instead
+pub struct Recorder {
+    output: Output,
+    scale: usize,
+    theme: Theme,
+    first_frame: Option<u128>,
+    /// last distinct display and the frame it appeared on
+    pending: Option<(Display, u128)>,
+}
+
+/// The size of a GIF frame at `scale`, which GIF limits to 65535 pixels a side
+fn gif_size(scale: usize) -> anyhow::Result<(u16, u16)> {
+    let side = |pixels: usize| {
+        u16::try_from(pixels * scale)
+            .map_err(|_| anyhow::anyhow!("a GIF can't be recorded at scale {}", scale))
+    };
+    Ok((side(DISPLAY_WIDTH)?, side(DISPLAY_HEIGHT)?))
+}
+
+/// Centiseconds from the start of the recording to `frame`
+fn centis(frame: u128) -> u128 {
+    (frame * 100 + 30) / 60
+}
+
+impl Recorder {
+    /// Records to an animated GIF for `.gif` paths, otherwise to PNG frames in
+    /// the directory `path`
+    pub fn start(path: &Path, scale: usize, theme: Theme) -> anyhow::Result<Self> {
+        let output = match path.extension().and_then(|e| e.to_str()) {
+            Some(ext) if ext.eq_ignore_ascii_case("gif") => {
+                let (width, height) = gif_size(scale)?;
+                let file = io::BufWriter::new(fs::File::create(path)?);
+                let palette: Vec<u8> = [theme::BACKGROUND, theme::PLANE_1]
+                    .iter()
+                    .flat_map(|i| theme.rgb(*i))
+                    .collect();
+                let mut encoder = gif::Encoder::new(file, width, height, &palette)?;
+                encoder.set_repeat(gif::Repeat::Infinite)?;
+                Output::Gif(encoder)
+            }
+            _ => {
+                fs::create_dir_all(path)?;
+                Output::Frames(path.to_path_buf())
+            }
+        };
+        Ok(Recorder {
+            output,
+            scale,
+            theme,
+            first_frame: None,
+            pending: None,
+        })
+    }
+
+    /// Captures the display as shown during `frame`
+    pub fn capture(&mut self, display_buffer: &Display, frame: u128) -> anyhow::Result<()> {
+        let first = *self.first_frame.get_or_insert(frame);
+        let frame = frame - first;
+        match &self.pending {
+            Some((last, _)) if last == display_buffer => return Ok(()),
+            _ => {}
+        }
+        match &mut self.output {
+            Output::Gif(_) => {
+                if let Some((_, start)) = self.pending {
+                    let delay = centis(frame) - centis(start);
+                    if delay < MIN_GIF_DELAY as u128 {
+                        // too short to show, the newer display takes its place
+                        self.pending = Some((*display_buffer, start));
+                        return Ok(());
+                    }
+                    self.write_pending(frame)?;
+                }
+            }
+            Output::Frames(dir) => {
+                let path = dir.join(format!("frame-{:06}.png", frame));
+                screenshot::save(&path, display_buffer, self.scale, &self.theme)?;
+            }
+        }
+        self.pending = Some((*display_buffer, frame));
+        Ok(())
+    }
+
+    fn write_pending(&mut self, until: u128) -> anyhow::Result<()> {
+        let (Output::Gif(encoder), Some((display, start))) = (&mut self.output, &self.pending)
+        else {
+            return Ok(());
+        };
+        let delay = (centis(until) - centis(*start)).clamp(MIN_GIF_DELAY as u128, u16::MAX as u128);
+        let (width, height) = gif_size(self.scale)?;
+        let frame = gif::Frame {
+            width,
+            height,
+            delay: delay as u16,
+            buffer: screenshot::indexed(display, self.scale).into(),
+            ..Default::default()
+        };
+        encoder.write_frame(&frame)?;
+        Ok(())
+    }
+
+    /// Writes the last frame, shown until `frame`, and closes the file
+    pub fn finish(mut self, frame: u128) -> anyhow::Result<()> {
+        let until = frame - self.first_frame.unwrap_or(frame);
+        if let Some((_, start)) = self.pending {
+            self.write_pending(until.max(start + 1))?;
+        }
+        if let Output::Gif(encoder) = self.output {
+            encoder.into_inner()?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,256 @@
+//! Packing the display into terminal cells.
+//!
+//! Each mode draws a number of dots per cell, from one full block to the 2x4
+//! dots of braille, and pixels are scaled up by whole numbers of dots. `Auto`
+//! picks the mode and scale that give the largest image fitting the terminal.
+
+use clap::ValueEnum;
+use serde::Deserialize;
+use std::cmp::Reverse;
+
+/// How display pixels are packed into terminal cells
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RenderMode {
+    /// Pick the mode and scale giving the largest image that fits
+    #[default]
+    Auto,
+    /// One pixel is two full blocks side by side
+    Block,
+    /// Two pixels per cell, stacked vertically
+    HalfBlock,
+    /// 2x2 pixels per cell as quadrant blocks
+    Quadrant,
+    /// 2x4 braille dots per cell
+    Braille,
+}
+
+const QUADRANTS: [char; 16] = [
+    ' ', '▘', '▝', '▀', '▖', '▌', '▞', '▛', '▗', '▚', '▐', '▜', '▄', '▙', '▟', '█',
+];
+
+/// The largest scale tried, which keeps the widest display's size in cells
+/// well inside a `u16`
+const MAX_SCALE: u16 = 64;
+
+/// Braille dot bits indexed by [y][x] inside a cell
+const BRAILLE_DOTS: [[u32; 2]; 4] = [[0x01, 0x08], [0x02, 0x10], [0x04, 0x20], [0x40, 0x80]];
+
+/// A render mode resolved against a target area
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Layout {
+    pub mode: RenderMode,
+    pub scale: u16,
+    /// size of the image in cells
+    pub width: u16,
+    pub height: u16,
+}
+
+impl RenderMode {
+    const CONCRETE: [RenderMode; 4] = [
+        RenderMode::Block,
+        RenderMode::HalfBlock,
+        RenderMode::Quadrant,
+        RenderMode::Braille,
+    ];
+
+    /// Dots per terminal cell
+    fn cell_dots(&self) -> (u16, u16) {
+        match self {
+            RenderMode::Auto | RenderMode::Block => (1, 1),
+            RenderMode::HalfBlock => (1, 2),
+            RenderMode::Quadrant => (2, 2),
+            RenderMode::Braille => (2, 4),
+        }
+    }
+
+    /// Dots per display pixel at scale 1, chosen so pixels come out roughly square
+    /// given terminal cells twice as tall as wide, except that quadrants map 2x2
+    /// pixels to a cell and so draw them twice as tall as wide
+    fn pixel_dots(&self) -> (u16, u16) {
+        match self {
+            RenderMode::Auto | RenderMode::Block => (2, 1),
+            RenderMode::HalfBlock | RenderMode::Quadrant | RenderMode::Braille => (1, 1),
+        }
+    }
+
+    fn layout_at(&self, scale: u16, width: usize, height: usize) -> Layout {
+        let (cx, cy) = self.cell_dots();
+        let (px, py) = self.pixel_dots();
+        Layout {
+            mode: *self,
+            scale,
+            width: (width as u16 * px * scale).div_ceil(cx),
+            height: (height as u16 * py * scale).div_ceil(cy),
+        }
+    }
+
+    /// Largest scale at which `width`x`height` pixels fit into `cols`x`rows` cells
+    fn fit(&self, width: usize, height: usize, cols: u16, rows: u16) -> Option<Layout> {
+        let fits = |l: &Layout| l.width <= cols && l.height <= rows;
+        (1..=MAX_SCALE)
+            .map(|scale| self.layout_at(scale, width, height))
+            .take_while(fits)
+            .last()
+    }
+
+    pub fn layout(&self, width: usize, height: usize, cols: u16, rows: u16) -> Layout {
+        match self {
+            RenderMode::Auto => Self::CONCRETE
+                .iter()
+                .filter_map(|m| m.fit(width, height, cols, rows))
+                .min_by_key(|l| {
+                    // physical pixel width in half cells, earlier modes win ties
+                    let (cx, _) = l.mode.cell_dots();
+                    let (px, _) = l.mode.pixel_dots();
+                    Reverse(px * l.scale * 2 / cx)
+                })
+                .unwrap_or_else(|| RenderMode::Braille.layout_at(1, width, height)),
+            mode => mode
+                .fit(width, height, cols, rows)
+                .unwrap_or_else(|| mode.layout_at(1, width, height)),
+        }
+    }
+}
+
+impl Layout {
+    /// The display pixel under dot (`dx`, `dy`) of cell (`col`, `row`)
+    fn dot_pixel(&self, col: u16, row: u16, dx: u16, dy: u16) -> (usize, usize) {
+        let (cx, cy) = self.mode.cell_dots();
+        let (px, py) = self.mode.pixel_dots();
+        let x = (col * cx + dx) / (px * self.scale);
+        let y = (row * cy + dy) / (py * self.scale);
+        (x as usize, y as usize)
+    }
+
+    /// The largest `value` of the pixels covered by cell (`col`, `row`)
+    pub fn cell_max(&self, col: u16, row: u16, value: impl Fn(usize, usize) -> u8) -> u8 {
+        let (cx, cy) = self.mode.cell_dots();
+        (0..cy)
+            .flat_map(|dy| (0..cx).map(move |dx| (dx, dy)))
+            .map(|(dx, dy)| {
+                let (x, y) = self.dot_pixel(col, row, dx, dy);
+                value(x, y)
+            })
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// The glyph of cell (`col`, `row`) of the image, `None` for an empty cell
+    pub fn glyph(&self, col: u16, row: u16, pixel: impl Fn(usize, usize) -> bool) -> Option<char> {
+        let dot = |dx: u16, dy: u16| {
+            let (x, y) = self.dot_pixel(col, row, dx, dy);
+            pixel(x, y)
+        };
+        let glyph = match self.mode {
+            RenderMode::Auto | RenderMode::Block => {
+                if dot(0, 0) {
+                    '█'
+                } else {
+                    ' '
+                }
+            }
+            RenderMode::HalfBlock => match (dot(0, 0), dot(0, 1)) {
+                (true, true) => '█',
+                (true, false) => '▀',
+                (false, true) => '▄',
+                (false, false) => ' ',
+            },
+            RenderMode::Quadrant => {
+                let bits = dot(0, 0) as usize
+                    | (dot(1, 0) as usize) << 1
+                    | (dot(0, 1) as usize) << 2
+                    | (dot(1, 1) as usize) << 3;
+                QUADRANTS[bits]
+            }
+            RenderMode::Braille => {
+                let mut bits = 0;
+                for (dy, row) in BRAILLE_DOTS.iter().enumerate() {
+                    for (dx, bit) in row.iter().enumerate() {
+                        if dot(dx as u16, dy as u16) {
+                            bits |= bit;
+                        }
+                    }
+                }
+                if bits == 0 {
+                    ' '
+                } else {
+                    char::from_u32(0x2800 + bits).unwrap()
+                }
+            }
+        };
+        (glyph != ' ').then_some(glyph)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn layout(mode: RenderMode, scale: u16, width: u16, height: u16) -> Layout {
+        Layout {
+            mode,
+            scale,
+            width,
+            height,
+        }
+    }
+
+    #[test]
+    fn auto_picks_the_largest_image_that_fits() {
+        let auto = RenderMode::Auto;
+        // blocks are too wide for 80 columns, half blocks win the tie with braille
+        assert_eq!(
+            auto.layout(64, 32, 80, 24),
+            layout(RenderMode::HalfBlock, 1, 64, 16)
+        );
+        assert_eq!(
+            auto.layout(64, 32, 130, 40),
+            layout(RenderMode::Block, 1, 128, 32)
+        );
+        assert_eq!(
+            auto.layout(128, 64, 80, 24),
+            layout(RenderMode::Braille, 1, 64, 16)
+        );
+        // nothing fits, so the smallest image is drawn and clipped
+        assert_eq!(
+            auto.layout(64, 32, 10, 5),
+            layout(RenderMode::Braille, 1, 32, 8)
+        );
+    }
+
+    #[test]
+    fn a_mode_is_scaled_up_to_fill_the_area() {
+        assert_eq!(
+            RenderMode::HalfBlock.layout(64, 32, 200, 50),
+            layout(RenderMode::HalfBlock, 3, 192, 48)
+        );
+        assert_eq!(
+            RenderMode::Quadrant.layout(64, 32, 40, 20),
+            layout(RenderMode::Quadrant, 1, 32, 16)
+        );
+        // the scale stops before the size in cells overflows
+        assert_eq!(
+            RenderMode::Block.layout(128, 64, u16::MAX, u16::MAX).scale,
+            MAX_SCALE
+        );
+    }
+
+    #[test]
+    fn glyphs_show_the_pixels_of_a_cell() {
+        let top_row = |_: usize, y: usize| y == 0;
+        let half = RenderMode::HalfBlock.layout(64, 32, 64, 16);
+        assert_eq!(half.glyph(0, 0, top_row), Some('▀'));
+        assert_eq!(half.glyph(0, 1, top_row), None);
+
+        let top_right = |x: usize, y: usize| x == 1 && y == 0;
+        let quadrant = RenderMode::Quadrant.layout(64, 32, 32, 16);
+        assert_eq!(quadrant.glyph(0, 0, top_right), Some('▝'));
+        assert_eq!(quadrant.glyph(0, 0, |_, _| true), Some('█'));
+
+        let left_column = |x: usize, _: usize| x == 0;
+        let braille = RenderMode::Braille.layout(64, 32, 32, 8);
+        assert_eq!(braille.glyph(0, 0, left_column), Some('⡇'));
+        assert_eq!(braille.glyph(1, 0, left_column), None);
+    }
+}
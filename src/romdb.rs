@@ -0,0 +1,243 @@
+//! ROM identification against the community CHIP-8 database
+//! (<https://github.com/chip-8/chip-8-database>).
+//!
+//! A database is a directory with the project's `programs.json` and
+//! `sha1-hashes.json`. None is bundled: a copy in `bchip8/chip-8-database`
+//! under the user's config directory, or the one given with `--rom-db`, is read,
+//! and without one no ROM is recognised.
+
+use crate::config::{self, Config};
+use crate::quirks::Platform;
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+#[derive(Deserialize)]
+struct Program {
+    title: String,
+    #[serde(default)]
+    authors: Vec<String>,
+    release: Option<String>,
+    #[serde(default)]
+    roms: HashMap<String, Rom>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Rom {
+    #[serde(default)]
+    platforms: Vec<String>,
+    /// instructions per frame
+    tickrate: Option<u64>,
+    #[serde(default)]
+    quirky_platforms: HashMap<String, QuirkSet>,
+    #[serde(default)]
+    keys: BTreeMap<String, u8>,
+}
+
+/// Quirks as the database names them, each relative to plain CHIP-8
+#[derive(Deserialize, Default, Clone)]
+#[serde(rename_all = "camelCase")]
+struct QuirkSet {
+    shift: Option<bool>,
+    memory_leave_i_unchanged: Option<bool>,
+    wrap: Option<bool>,
+    jump: Option<bool>,
+    logic: Option<bool>,
+}
+
+impl QuirkSet {
+    fn quirks(&self) -> BTreeMap<String, bool> {
+        let mut quirks = BTreeMap::new();
+        let mut add = |name: &str, value: Option<bool>| {
+            if let Some(value) = value {
+                quirks.insert(name.to_string(), value);
+            }
+        };
+        add("shift_vy", self.shift.map(|shift| !shift));
+        add(
+            "load_store_increment",
+            self.memory_leave_i_unchanged.map(|leave| !leave),
+        );
+        add("clip", self.wrap.map(|wrap| !wrap));
+        add("jump_vx", self.jump);
+        add("logic_vf_reset", self.logic);
+        quirks
+    }
+}
+
+/// The platform for a database platform id, `None` for ones not emulated
+fn platform(id: &str) -> Option<Platform> {
+    match id {
+        "originalChip8" | "hybridVIP" | "modernChip8" => Some(Platform::Vip),
+        "chip48" => Some(Platform::Chip48),
+        "superchip1" | "superchip" => Some(Platform::Schip),
+        "xochip" => Some(Platform::Xochip),
+        _ => None,
+    }
+}
+
+/// What the database knows about one ROM
+#[derive(Debug, Clone)]
+pub struct Match {
+    pub title: String,
+    pub authors: Vec<String>,
+    pub release: Option<String>,
+    pub platform: Option<Platform>,
+    pub tickrate: Option<u64>,
+    pub quirks: BTreeMap<String, bool>,
+    /// what keypad keys do, such as `left` for 4
+    pub keys: BTreeMap<String, u8>,
+}
+
+impl Match {
+    /// Title with authors and year, such as `Tetris (Fran Dachille, 1991)`
+    pub fn name(&self) -> String {
+        let mut credits = self.authors.clone();
+        credits.extend(self.release.clone());
+        if credits.is_empty() {
+            self.title.clone()
+        } else {
+            format!("{} ({})", self.title, credits.join(", "))
+        }
+    }
+
+    /// Keypad keys and what they do, such as `4 a  5 left`
+    pub fn key_hints(&self) -> String {
+        let mut keys: Vec<(&u8, &String)> = self.keys.iter().map(|(k, v)| (v, k)).collect();
+        keys.sort();
+        let hints: Vec<String> = keys
+            .iter()
+            .map(|(key, action)| format!("{:X} {}", key, action))
+            .collect();
+        hints.join("  ")
+    }
+
+    /// The recommended settings, as a layer below the user's config files
+    pub fn config(&self) -> Config {
+        Config {
+            cycle_micro: self.tickrate.and_then(config::cycle_micro),
+            platform: self.platform,
+            quirks: self.quirks.clone(),
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct RomDb {
+    programs: Vec<Program>,
+    /// SHA-1 to index in `programs`
+    hashes: HashMap<String, usize>,
+}
+
+/// Where a full copy of the database is looked for
+pub fn default_dir() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("bchip8").join("chip-8-database"))
+}
+
+impl RomDb {
+    /// The database in `dir`, or in the default directory when it exists, and
+    /// an empty one otherwise
+    pub fn open(dir: Option<&Path>) -> anyhow::Result<Self> {
+        let mut db = RomDb::default();
+        let dir = match dir {
+            Some(dir) => Some(dir.to_path_buf()),
+            None => default_dir().filter(|dir| dir.is_dir()),
+        };
+        if let Some(dir) = dir {
+            let read = |name: &str| {
+                let path = dir.join(name);
+                fs::read_to_string(&path).map_err(|e| anyhow::anyhow!("{}: {}", path.display(), e))
+            };
+            db.add(&read("programs.json")?, &read("sha1-hashes.json")?)
+                .map_err(|e| anyhow::anyhow!("{}: {}", dir.display(), e))?;
+        }
+        Ok(db)
+    }
+
+    fn add(&mut self, programs: &str, hashes: &str) -> anyhow::Result<()> {
+        let programs: Vec<Program> = serde_json::from_str(programs)?;
+        let hashes: HashMap<String, usize> = serde_json::from_str(hashes)?;
+        let offset = self.programs.len();
+        for (hash, index) in hashes {
+            if index >= programs.len() {
+                anyhow::bail!("hash {} points past the last program", hash);
+            }
+            self.hashes.insert(hash.to_lowercase(), offset + index);
+        }
+        self.programs.extend(programs);
+        Ok(())
+    }
+
+    /// Looks up a ROM by the SHA-1 of its bytes, as from `cartridge::sha1_hex`
+    pub fn lookup(&self, sha1: &str) -> Option<Match> {
+        let program = &self.programs[*self.hashes.get(sha1)?];
+        let rom = program.roms.get(sha1)?;
+        let (id, platform) = rom
+            .platforms
+            .iter()
+            .find_map(|id| platform(id).map(|p| (id, p)))
+            .unzip();
+        let quirks = id
+            .and_then(|id| rom.quirky_platforms.get(id))
+            .map(|q| q.quirks())
+            .unwrap_or_default();
+        Some(Match {
+            title: program.title.clone(),
+            authors: program.authors.clone(),
+            release: program.release.clone(),
+            platform,
+            tickrate: rom.tickrate,
+            quirks,
+            keys: rom.keys.clone(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PROGRAMS: &str = r#"[{
+        "title": "Tetris",
+        "authors": ["Fran Dachille"],
+        "release": "1991",
+        "roms": {
+            "5f518084744bf3cb8733f6e5454dfd1634320563": {
+                "platforms": ["superchip", "originalChip8"],
+                "tickrate": 15,
+                "quirkyPlatforms": {"superchip": {"shift": false}},
+                "keys": {"left": 5}
+            }
+        }
+    }]"#;
+    const HASHES: &str = r#"{"5F518084744BF3CB8733F6E5454DFD1634320563": 0}"#;
+
+    #[test]
+    fn a_database_directory_identifies_its_roms() {
+        let dir = std::env::temp_dir().join(format!("bchip8-romdb-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("programs.json"), PROGRAMS).unwrap();
+        fs::write(dir.join("sha1-hashes.json"), HASHES).unwrap();
+        let db = RomDb::open(Some(&dir));
+        fs::remove_dir_all(&dir).unwrap();
+        let db = db.unwrap();
+
+        let known = db
+            .lookup("5f518084744bf3cb8733f6e5454dfd1634320563")
+            .unwrap();
+        assert_eq!(known.title, "Tetris");
+        assert_eq!(known.platform, Some(Platform::Schip));
+        assert_eq!(known.tickrate, Some(15));
+        assert_eq!(known.quirks, [("shift_vy".to_string(), true)].into());
+        assert!(db.lookup("0000").is_none());
+    }
+
+    #[test]
+    fn a_missing_database_directory_is_an_error() {
+        let dir = std::env::temp_dir().join("bchip8-romdb-missing");
+        assert!(RomDb::open(Some(&dir)).is_err());
+    }
+}
@@ -0,0 +1,335 @@
+//! Reading ROMs in the forms they are shared in.
+//!
+//! The format is detected from the content: zip archives, gzip files, Octo
+//! cartridge GIFs and Octo source, Intel HEX, hex text as pasted from forums
+//! (`A2 1E C2 01`, `0xA2, 0x1E` or a hexdump with `0200:` offsets), and raw
+//! binaries otherwise. `-` reads standard input. One
+//! ROM of an archive holding several is chosen with `games.zip#tetris.ch8`.
+
+use crate::cartridge::CARTRIDGE_ADDRESS;
+use crate::config::Config;
+use crate::machine::MEMORY_SIZE;
+use crate::{octo, octo_cartridge};
+use flate2::read::GzDecoder;
+use std::fs;
+use std::io::{self, BufRead, Cursor, IsTerminal, Read, Write};
+use std::path::{Path, PathBuf};
+
+/// The path that reads standard input
+pub const STDIN: &str = "-";
+
+/// Extensions that mark the ROMs among other files in an archive
+const ROM_EXTENSIONS: [&str; 4] = ["ch8", "c8", "sc8", "xo8"];
+
+/// Whether `path` reads standard input, with or without an archive member
+pub fn is_stdin(path: &Path) -> bool {
+    split_member(path).0 == Path::new(STDIN)
+}
+
+/// `games.zip#tetris.ch8` split into the file and the member, unless a file of
+/// that whole name exists
+pub fn split_member(path: &Path) -> (PathBuf, Option<String>) {
+    let whole = (path.to_path_buf(), None);
+    if path.exists() {
+        return whole;
+    }
+    let text = path.to_string_lossy();
+    match text.rsplit_once('#') {
+        Some((file, member)) if file == STDIN || Path::new(file).is_file() => {
+            (PathBuf::from(file), Some(member.to_string()))
+        }
+        _ => whole,
+    }
+}
+
+/// Name to show for a ROM, the member for one inside an archive
+pub fn display_name(path: &Path) -> String {
+    match split_member(path) {
+        (_, Some(member)) => Path::new(&member)
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or(member),
+        (file, None) => file
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_else(|| file.to_string_lossy().into_owned()),
+    }
+}
+
+/// A ROM and the settings that came with it
+pub struct RomFile {
+    pub bytes: Vec<u8>,
+    /// from an Octo cartridge, empty for other formats
+    pub config: Config,
+}
+
+impl From<Vec<u8>> for RomFile {
+    fn from(bytes: Vec<u8>) -> Self {
+        RomFile {
+            bytes,
+            config: Config::default(),
+        }
+    }
+}
+
+pub fn read(path: &Path) -> anyhow::Result<RomFile> {
+    let (file, member) = split_member(path);
+    let bytes = if file == Path::new(STDIN) {
+        let mut bytes = vec![];
+        io::stdin().read_to_end(&mut bytes)?;
+        bytes
+    } else {
+        fs::read(&file)?
+    };
+    decode(bytes, member.as_deref()).map_err(|e| anyhow::anyhow!("{}: {}", path.display(), e))
+}
+
+fn is_zip(bytes: &[u8]) -> bool {
+    bytes.starts_with(b"PK\x03\x04") || bytes.starts_with(b"PK\x05\x06")
+}
+
+fn decode(bytes: Vec<u8>, member: Option<&str>) -> anyhow::Result<RomFile> {
+    if is_zip(&bytes) {
+        return decode(zip_member(&bytes, member)?, None);
+    }
+    if member.is_some() {
+        anyhow::bail!("#NAME only picks a file inside a zip archive");
+    }
+    if bytes.starts_with(&[0x1f, 0x8b]) {
+        let mut data = vec![];
+        GzDecoder::new(&bytes[..]).read_to_end(&mut data)?;
+        return decode(data, None);
+    }
+    if octo_cartridge::is_cartridge(&bytes) {
+        let cartridge = octo_cartridge::decode(&bytes)?;
+        return Ok(RomFile {
+            bytes: octo::compile(&cartridge.program)?.bytes,
+            config: cartridge.options.config()?,
+        });
+    }
+    if let Some(rom) = intel_hex(&bytes) {
+        return rom.map(RomFile::from);
+    }
+    if let Some(rom) = hex_text(&bytes) {
+        return Ok(rom.into());
+    }
+    if let Some(source) = std::str::from_utf8(&bytes)
+        .ok()
+        .filter(|s| octo::is_source(s))
+    {
+        return Ok(octo::compile(source)?.bytes.into());
+    }
+    Ok(bytes.into())
+}
+
+/// Names of the ROMs in a zip archive, every file when none has a ROM extension
+fn zip_roms(bytes: &[u8]) -> anyhow::Result<Vec<String>> {
+    let archive = zip::ZipArchive::new(Cursor::new(bytes))?;
+    let files: Vec<String> = archive
+        .file_names()
+        .filter(|name| !name.ends_with('/'))
+        .map(String::from)
+        .collect();
+    let is_rom = |name: &String| {
+        Path::new(name)
+            .extension()
+            .and_then(|e| e.to_str())
+            .is_some_and(|e| ROM_EXTENSIONS.contains(&e.to_lowercase().as_str()))
+    };
+    let mut roms: Vec<String> = files.iter().filter(|n| is_rom(n)).cloned().collect();
+    if roms.is_empty() {
+        roms = files;
+    }
+    roms.sort();
+    Ok(roms)
+}
+
+fn zip_member(bytes: &[u8], member: Option<&str>) -> anyhow::Result<Vec<u8>> {
+    let roms = zip_roms(bytes)?;
+    let name = match (member, &roms[..]) {
+        (Some(member), _) => member.to_string(),
+        (None, []) => anyhow::bail!("the archive is empty"),
+        (None, [rom]) => rom.clone(),
+        (None, _) => anyhow::bail!(
+            "the archive holds {} ROMs, pick one with #NAME: {}",
+            roms.len(),
+            roms.join(", ")
+        ),
+    };
+    let mut archive = zip::ZipArchive::new(Cursor::new(bytes))?;
+    let mut file = archive
+        .by_name(&name)
+        .map_err(|_| anyhow::anyhow!("no {} in the archive", name))?;
+    let mut data = vec![];
+    file.read_to_end(&mut data)?;
+    Ok(data)
+}
+
+/// For an archive holding several ROMs, asks on the terminal which one to use
+/// and returns `path#name`. Other paths come back as they are.
+pub fn pick(path: &Path) -> anyhow::Result<PathBuf> {
+    let (file, member) = split_member(path);
+    if member.is_some() || file == Path::new(STDIN) || !file.is_file() {
+        return Ok(path.to_path_buf());
+    }
+    let bytes = fs::read(&file)?;
+    if !is_zip(&bytes) {
+        return Ok(path.to_path_buf());
+    }
+    let roms = zip_roms(&bytes)?;
+    // without a terminal to ask on, loading fails with the list instead
+    if roms.len() < 2 || !io::stdin().is_terminal() || !io::stderr().is_terminal() {
+        return Ok(path.to_path_buf());
+    }
+    let mut stderr = io::stderr();
+    for (n, rom) in roms.iter().enumerate() {
+        writeln!(stderr, "{:>3}  {}", n + 1, rom)?;
+    }
+    loop {
+        write!(stderr, "ROM to run [1-{}]: ", roms.len())?;
+        stderr.flush()?;
+        let mut line = String::new();
+        if io::stdin().lock().read_line(&mut line)? == 0 {
+            anyhow::bail!("no ROM picked");
+        }
+        let line = line.trim();
+        let picked = match line.parse::<usize>() {
+            Ok(n) if (1..=roms.len()).contains(&n) => Some(&roms[n - 1]),
+            _ => roms.iter().find(|rom| rom.as_str() == line),
+        };
+        if let Some(rom) = picked {
+            return Ok(PathBuf::from(format!("{}#{}", file.display(), rom)));
+        }
+    }
+}
+
+fn hex_digits(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Intel HEX records, placed so that an image linked at 0x200 starts the ROM.
+/// `None` when the text isn't Intel HEX.
+fn intel_hex(bytes: &[u8]) -> Option<anyhow::Result<Vec<u8>>> {
+    let text = std::str::from_utf8(bytes).ok()?;
+    let lines: Vec<&str> = text
+        .lines()
+        .map(|l| l.trim())
+        .filter(|l| !l.is_empty())
+        .collect();
+    if lines.is_empty() || !lines.iter().all(|l| l.starts_with(':')) {
+        return None;
+    }
+    Some(parse_intel_hex(&lines))
+}
+
+fn parse_intel_hex(lines: &[&str]) -> anyhow::Result<Vec<u8>> {
+    let mut base = 0usize;
+    let mut chunks: Vec<(usize, Vec<u8>)> = vec![];
+    for (n, line) in lines.iter().enumerate() {
+        let err = |what: &str| anyhow::anyhow!("Intel HEX line {}: {}", n + 1, what);
+        let record = hex_digits(&line[1..]).ok_or_else(|| err("bad hex digits"))?;
+        if record.len() < 5 || record.len() != record[0] as usize + 5 {
+            return Err(err("bad record length"));
+        }
+        if record.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) != 0 {
+            return Err(err("bad checksum"));
+        }
+        let addr = u16::from_be_bytes([record[1], record[2]]) as usize;
+        let data = &record[4..record.len() - 1];
+        match record[3] {
+            0x00 => chunks.push((base + addr, data.to_vec())),
+            0x01 => break,
+            0x02 if data.len() == 2 => {
+                base = (u16::from_be_bytes([data[0], data[1]]) as usize) << 4
+            }
+            0x04 if data.len() == 2 => {
+                base = (u16::from_be_bytes([data[0], data[1]]) as usize) << 16
+            }
+            // start addresses don't matter for a ROM
+            0x03 | 0x05 => {}
+            _ => return Err(err("unknown record type")),
+        }
+    }
+    let Some(start) = chunks.iter().map(|(addr, _)| *addr).min() else {
+        anyhow::bail!("Intel HEX file without data");
+    };
+    let origin = if start >= CARTRIDGE_ADDRESS {
+        CARTRIDGE_ADDRESS
+    } else {
+        start
+    };
+    let end = chunks
+        .iter()
+        .map(|(addr, data)| addr + data.len())
+        .max()
+        .unwrap_or(origin);
+    // checked before allocating, an extended address can put data gigabytes away
+    if end - origin > MEMORY_SIZE {
+        anyhow::bail!(
+            "Intel HEX data at {:#x}-{:#x} doesn't fit in {} bytes of memory",
+            origin,
+            end - 1,
+            MEMORY_SIZE
+        );
+    }
+    let mut rom = vec![0; end - origin];
+    for (addr, data) in chunks {
+        rom[addr - origin..addr - origin + data.len()].copy_from_slice(&data);
+    }
+    Ok(rom)
+}
+
+/// Hex bytes in text, separated by spaces or commas, optionally prefixed with
+/// `0x`, `$` or `#`, with `ADDR:` offsets skipped. `None` for anything else.
+fn hex_text(bytes: &[u8]) -> Option<Vec<u8>> {
+    let text = std::str::from_utf8(bytes).ok()?;
+    let mut digits = String::new();
+    for token in text.split(|c: char| c.is_whitespace() || c == ',') {
+        if token.is_empty() || token.ends_with(':') {
+            continue;
+        }
+        let stripped = token
+            .strip_prefix("0x")
+            .or_else(|| token.strip_prefix("0X"))
+            .or_else(|| token.strip_prefix('$'))
+            .or_else(|| token.strip_prefix('#'));
+        let hex = stripped.unwrap_or(token);
+        if hex.is_empty() || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+            return None;
+        }
+        if stripped.is_some() && hex.len() == 1 {
+            digits.push('0');
+        }
+        digits.push_str(hex);
+    }
+    if digits.is_empty() {
+        return None;
+    }
+    hex_digits(&digits)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn intel_hex_data_past_memory_is_refused() {
+        let rom = parse_intel_hex(&[":020200001200EA", ":00000001FF"]).unwrap();
+        assert_eq!(rom, [0x12, 0x00]);
+
+        // an extended linear address of 0x8000 puts the second record at 2 GB
+        let far = [
+            ":020200001200EA",
+            ":0200000480007A",
+            ":0100000001FE",
+            ":00000001FF",
+        ];
+        assert!(parse_intel_hex(&far).is_err());
+    }
+}
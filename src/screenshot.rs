@@ -0,0 +1,240 @@
+use crate::machine::{DISPLAY_HEIGHT, DISPLAY_WIDTH};
+use crate::theme::{self, Theme};
+use std::fs;
+use std::io::{self, Write};
+use std::path::{self, PathBuf};
+
+/// Netpbm's limit on the length of the lines of the plain formats
+const PLAIN_LINE: usize = 70;
+
+/// Image formats, picked from the file extension
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Png,
+    /// plain bitmap, 1 is a lit pixel
+    Pbm,
+    /// plain greymap with the luminance of the theme colours
+    Pgm,
+    /// binary pixmap in the theme colours
+    Ppm,
+}
+
+impl Format {
+    pub fn from_path(path: &path::Path) -> anyhow::Result<Self> {
+        let ext = path
+            .extension()
+            .and_then(|e| e.to_str())
+            .unwrap_or_default()
+            .to_lowercase();
+        match ext.as_str() {
+            "png" => Ok(Format::Png),
+            "pbm" => Ok(Format::Pbm),
+            "pgm" => Ok(Format::Pgm),
+            "ppm" => Ok(Format::Ppm),
+            _ => anyhow::bail!(
+                "unknown image format {}, expected .png, .pbm, .pgm or .ppm",
+                path.display()
+            ),
+        }
+    }
+}
+
+/// How screenshots and recordings taken with the hotkeys are written
+#[derive(Debug, Clone)]
+pub struct Settings {
+    pub dir: PathBuf,
+    pub scale: usize,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            dir: PathBuf::from("."),
+            scale: 1,
+        }
+    }
+}
+
+impl Settings {
+    /// Path of the hotkey screenshot taken at `frame`
+    pub fn path(&self, frame: u128) -> PathBuf {
+        self.dir.join(format!("screenshot-{:06}.png", frame))
+    }
+
+    /// Path of the hotkey recording started at `frame`
+    pub fn recording_path(&self, frame: u128) -> PathBuf {
+        self.dir.join(format!("recording-{:06}.gif", frame))
+    }
+}
+
+/// Palette indices of the display scaled up by `scale`, row by row
+pub fn indexed(display_buffer: &[[bool; DISPLAY_WIDTH]; DISPLAY_HEIGHT], scale: usize) -> Vec<u8> {
+    let mut pixels = Vec::with_capacity(DISPLAY_WIDTH * DISPLAY_HEIGHT * scale * scale);
+    for y in 0..DISPLAY_HEIGHT * scale {
+        for x in 0..DISPLAY_WIDTH * scale {
+            let index = if display_buffer[y / scale][x / scale] {
+                theme::PLANE_1
+            } else {
+                theme::BACKGROUND
+            };
+            pixels.push(index as u8);
+        }
+    }
+    pixels
+}
+
+/// Saves the display buffer scaled up by `scale` in the theme's colours
+pub fn save(
+    path: &path::Path,
+    display_buffer: &[[bool; DISPLAY_WIDTH]; DISPLAY_HEIGHT],
+    scale: usize,
+    theme: &Theme,
+) -> anyhow::Result<()> {
+    if scale == 0 {
+        anyhow::bail!("screenshot scale must be at least 1");
+    }
+    let format = Format::from_path(path)?;
+    let width = DISPLAY_WIDTH * scale;
+    let height = DISPLAY_HEIGHT * scale;
+    let lit = |x: usize, y: usize| display_buffer[y / scale][x / scale];
+    let index = |x: usize, y: usize| {
+        if lit(x, y) {
+            theme::PLANE_1
+        } else {
+            theme::BACKGROUND
+        }
+    };
+
+    let mut file = io::BufWriter::new(fs::File::create(path)?);
+    match format {
+        Format::Png => {
+            let mut encoder = png::Encoder::new(&mut file, width as u32, height as u32);
+            encoder.set_color(png::ColorType::Indexed);
+            encoder.set_depth(png::BitDepth::Eight);
+            encoder.set_palette(
+                [theme::BACKGROUND, theme::PLANE_1]
+                    .iter()
+                    .flat_map(|i| theme.rgb(*i))
+                    .collect::<Vec<u8>>(),
+            );
+            encoder
+                .write_header()?
+                .write_image_data(&indexed(display_buffer, scale))?;
+        }
+        Format::Pbm => {
+            writeln!(file, "P1\n{} {}", width, height)?;
+            for y in 0..height {
+                let samples = (0..width).map(|x| if lit(x, y) { "1" } else { "0" }.to_string());
+                write_plain(&mut file, samples)?;
+            }
+        }
+        Format::Pgm => {
+            writeln!(file, "P2\n{} {}\n255", width, height)?;
+            let luma =
+                |[r, g, b]: theme::Rgb| (r as u32 * 299 + g as u32 * 587 + b as u32 * 114) / 1000;
+            for y in 0..height {
+                let samples = (0..width).map(|x| luma(theme.rgb(index(x, y))).to_string());
+                write_plain(&mut file, samples)?;
+            }
+        }
+        Format::Ppm => {
+            write!(file, "P6\n{} {}\n255\n", width, height)?;
+            for y in 0..height {
+                for x in 0..width {
+                    file.write_all(&theme.rgb(index(x, y)))?;
+                }
+            }
+        }
+    }
+    file.flush()?;
+    Ok(())
+}
+
+/// Writes one row of a plain PBM or PGM, wrapping it to keep lines within
+/// `PLAIN_LINE` characters
+fn write_plain(w: &mut impl Write, samples: impl Iterator<Item = String>) -> io::Result<()> {
+    let mut len = 0;
+    for sample in samples {
+        if len > 0 && len + 1 + sample.len() > PLAIN_LINE {
+            writeln!(w)?;
+            len = 0;
+        } else if len > 0 {
+            write!(w, " ")?;
+            len += 1;
+        }
+        write!(w, "{}", sample)?;
+        len += sample.len();
+    }
+    writeln!(w)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A display with only the top left pixel lit
+    fn display() -> [[bool; DISPLAY_WIDTH]; DISPLAY_HEIGHT] {
+        let mut display = [[false; DISPLAY_WIDTH]; DISPLAY_HEIGHT];
+        display[0][0] = true;
+        display
+    }
+
+    fn screenshot(name: &str, scale: usize) -> Vec<u8> {
+        let path = std::env::temp_dir().join(format!("bchip8-{}-{}", std::process::id(), name));
+        save(&path, &display(), scale, &Theme::default()).unwrap();
+        let bytes = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn plain_formats_are_wrapped_netpbm() {
+        let pbm = String::from_utf8(screenshot("shot.pbm", 1)).unwrap();
+        let mut lines = pbm.lines();
+        assert_eq!(lines.next(), Some("P1"));
+        assert_eq!(lines.next(), Some("64 32"));
+        assert!(lines.clone().all(|l| l.len() <= PLAIN_LINE));
+        let samples: Vec<&str> = lines.flat_map(str::split_whitespace).collect();
+        assert_eq!(samples.len(), DISPLAY_WIDTH * DISPLAY_HEIGHT);
+        assert_eq!(samples[..2], ["1", "0"]);
+        assert!(samples[1..].iter().all(|s| *s == "0"));
+
+        let pgm = String::from_utf8(screenshot("shot.pgm", 1)).unwrap();
+        let mut lines = pgm.lines();
+        assert_eq!(
+            lines.by_ref().take(3).collect::<Vec<_>>(),
+            ["P2", "64 32", "255"]
+        );
+        assert!(lines.clone().all(|l| l.len() <= PLAIN_LINE));
+        let samples: Vec<u32> = lines
+            .flat_map(str::split_whitespace)
+            .map(|s| s.parse().unwrap())
+            .collect();
+        assert_eq!(samples.len(), DISPLAY_WIDTH * DISPLAY_HEIGHT);
+        assert!(samples[0] > samples[1]);
+    }
+
+    #[test]
+    fn ppm_and_png_hold_the_theme_colours() {
+        let theme = Theme::default();
+        let (lit, unlit) = (theme.rgb(theme::PLANE_1), theme.rgb(theme::BACKGROUND));
+
+        let ppm = screenshot("shot.ppm", 2);
+        let header = b"P6\n128 64\n255\n";
+        assert_eq!(&ppm[..header.len()], header);
+        let pixels = &ppm[header.len()..];
+        assert_eq!(pixels.len(), 128 * 64 * 3);
+        // the lit pixel is 2x2 at scale 2
+        assert_eq!(pixels[..6], [lit, lit].concat());
+        assert_eq!(pixels[128 * 3..128 * 3 + 9], [lit, lit, unlit].concat());
+
+        let png = screenshot("shot.png", 1);
+        let mut reader = png::Decoder::new(io::Cursor::new(png)).read_info().unwrap();
+        let info = reader.info();
+        assert_eq!((info.width, info.height), (64, 32));
+        assert_eq!(info.palette.as_deref(), Some(&[unlit, lit].concat()[..]));
+        let mut data = vec![0; reader.output_buffer_size().unwrap()];
+        reader.next_frame(&mut data).unwrap();
+        assert_eq!(data[..2], [theme::PLANE_1 as u8, theme::BACKGROUND as u8]);
+    }
+}
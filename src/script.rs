@@ -0,0 +1,222 @@
+//! Rhai scripting hooks.
+//!
+//! A script may define any of these callbacks, each called with `this` bound to an
+//! object map that persists between calls:
+//! `init()`, `on_frame_start(frame)`, `on_frame_end(frame)`, `on_exec(addr)` for
+//! addresses registered with `watch_exec(addr)`, `on_memory_write(addr, value)`,
+//! `on_key_wait(reg)` and `on_draw(x, y, height, collision)`.
+//!
+//! Callbacks read and modify the machine with `reg(n)`, `set_reg(n, v)`, `reg_i()`,
+//! `set_reg_i(v)`, `pc()`, `set_pc(v)`, `mem(addr)`, `set_mem(addr, v)`,
+//! `delay_timer()`, `set_delay_timer(v)`, `sound_timer()`, `set_sound_timer(v)`,
+//! `frame()`, `press_key(k)`, `release_key(k)`, `screenshot(path)` and `quit()`.
+
+use crate::console::{Key, KeyEvent};
+use crate::machine::{Machine, REGISTER_COUNT};
+use log::info;
+use rand::Rng;
+use rhai::{AST, CallFnOptions, Dynamic, Engine, FuncArgs, INT, Map, Scope};
+use std::cell::RefCell;
+use std::collections::{BTreeSet, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+pub const INIT: &str = "init";
+pub const ON_FRAME_START: &str = "on_frame_start";
+pub const ON_FRAME_END: &str = "on_frame_end";
+pub const ON_EXEC: &str = "on_exec";
+pub const ON_MEMORY_WRITE: &str = "on_memory_write";
+pub const ON_KEY_WAIT: &str = "on_key_wait";
+pub const ON_DRAW: &str = "on_draw";
+
+/// Machine state as seen by the script during a callback
+#[derive(Default)]
+struct Bridge {
+    registers: [u8; REGISTER_COUNT],
+    register_i: u16,
+    pc: usize,
+    delay_timer: u8,
+    sound_timer: u8,
+    frame: u128,
+    /// the machine's memory, lent for the length of a callback
+    memory: Vec<u8>,
+    key_events: Vec<KeyEvent>,
+    screenshots: Vec<PathBuf>,
+    watch: BTreeSet<usize>,
+    quit: bool,
+}
+
+pub struct ScriptHost {
+    engine: Engine,
+    ast: AST,
+    state: Dynamic,
+    bridge: Rc<RefCell<Bridge>>,
+    hooks: HashSet<String>,
+}
+
+impl ScriptHost {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let source = fs::read_to_string(path)?;
+        let bridge = Rc::new(RefCell::new(Bridge::default()));
+        let engine = Self::engine(&bridge);
+        let ast = engine
+            .compile(&source)
+            .map_err(|e| anyhow::anyhow!("{}: {}", path.display(), e))?;
+        let hooks = ast.iter_functions().map(|f| f.name.to_string()).collect();
+        Ok(ScriptHost {
+            engine,
+            ast,
+            state: Dynamic::from_map(Map::new()),
+            bridge,
+            hooks,
+        })
+    }
+
+    fn engine(bridge: &Rc<RefCell<Bridge>>) -> Engine {
+        let mut engine = Engine::new();
+        engine.on_print(|s| info!("(Script) {}", s));
+        engine.on_debug(|s, _, pos| info!("(Script) {:?} {}", pos, s));
+
+        let b = bridge.clone();
+        engine.register_fn("reg", move |n: INT| {
+            b.borrow().registers.get(n as usize).copied().unwrap_or(0) as INT
+        });
+        let b = bridge.clone();
+        engine.register_fn("set_reg", move |n: INT, v: INT| {
+            if let Some(r) = b.borrow_mut().registers.get_mut(n as usize) {
+                *r = v as u8;
+            }
+        });
+        let b = bridge.clone();
+        engine.register_fn("reg_i", move || b.borrow().register_i as INT);
+        let b = bridge.clone();
+        engine.register_fn("set_reg_i", move |v: INT| {
+            b.borrow_mut().register_i = v as u16;
+        });
+        let b = bridge.clone();
+        engine.register_fn("pc", move || b.borrow().pc as INT);
+        let b = bridge.clone();
+        engine.register_fn("set_pc", move |v: INT| b.borrow_mut().pc = v as usize);
+        let b = bridge.clone();
+        engine.register_fn("mem", move |addr: INT| {
+            b.borrow().memory.get(addr as usize).copied().unwrap_or(0) as INT
+        });
+        let b = bridge.clone();
+        engine.register_fn("set_mem", move |addr: INT, v: INT| {
+            if let Some(m) = b.borrow_mut().memory.get_mut(addr as usize) {
+                *m = v as u8;
+            }
+        });
+        let b = bridge.clone();
+        engine.register_fn("delay_timer", move || b.borrow().delay_timer as INT);
+        let b = bridge.clone();
+        engine.register_fn("set_delay_timer", move |v: INT| {
+            b.borrow_mut().delay_timer = v as u8;
+        });
+        let b = bridge.clone();
+        engine.register_fn("sound_timer", move || b.borrow().sound_timer as INT);
+        let b = bridge.clone();
+        engine.register_fn("set_sound_timer", move |v: INT| {
+            b.borrow_mut().sound_timer = v as u8;
+        });
+        let b = bridge.clone();
+        engine.register_fn("frame", move || b.borrow().frame as INT);
+        let b = bridge.clone();
+        engine.register_fn("press_key", move |k: INT| {
+            let key = Key::Num((k & 0xF) as u8);
+            b.borrow_mut().key_events.push(KeyEvent::Pressed(key));
+        });
+        let b = bridge.clone();
+        engine.register_fn("release_key", move |k: INT| {
+            let key = Key::Num((k & 0xF) as u8);
+            b.borrow_mut().key_events.push(KeyEvent::Released(key));
+        });
+        let b = bridge.clone();
+        engine.register_fn("screenshot", move |path: &str| {
+            b.borrow_mut().screenshots.push(PathBuf::from(path));
+        });
+        let b = bridge.clone();
+        engine.register_fn("watch_exec", move |addr: INT| {
+            b.borrow_mut().watch.insert(addr as usize);
+        });
+        let b = bridge.clone();
+        engine.register_fn("quit", move || b.borrow_mut().quit = true);
+        engine
+    }
+
+    /// Runs the top level statements and then `init`
+    pub fn init<R: Rng>(&mut self, machine: &mut Machine<R>) -> anyhow::Result<()> {
+        self.export(machine);
+        let res = self
+            .engine
+            .run_ast(&self.ast)
+            .map_err(|e| anyhow::anyhow!("script: {}", e));
+        self.import(machine)?;
+        res?;
+        self.call(machine, INIT, ())
+    }
+
+    pub fn watches(&self, addr: usize) -> bool {
+        self.hooks.contains(ON_EXEC) && self.bridge.borrow().watch.contains(&addr)
+    }
+
+    /// Calls `hook` if the script defines it
+    pub fn call<R: Rng>(
+        &mut self,
+        machine: &mut Machine<R>,
+        hook: &str,
+        args: impl FuncArgs,
+    ) -> anyhow::Result<()> {
+        if !self.hooks.contains(hook) {
+            return Ok(());
+        }
+        self.export(machine);
+        let options = CallFnOptions::new()
+            .eval_ast(false)
+            .bind_this_ptr(&mut self.state);
+        let res = self
+            .engine
+            .call_fn_with_options::<Dynamic>(options, &mut Scope::new(), &self.ast, hook, args)
+            .map_err(|e| anyhow::anyhow!("script {}: {}", hook, e));
+        self.import(machine)?;
+        res.map(|_| ())
+    }
+
+    fn export<R: Rng>(&self, machine: &mut Machine<R>) {
+        let mut b = self.bridge.borrow_mut();
+        for (r, v) in b.registers.iter_mut().enumerate() {
+            *v = machine.get_register(r as u8).unwrap_or(0);
+        }
+        b.register_i = machine.get_register_i();
+        b.pc = machine.pc();
+        b.delay_timer = machine.delay_timer();
+        b.sound_timer = machine.sound_timer();
+        b.frame = machine.frame();
+        machine.swap_memory(&mut b.memory);
+    }
+
+    fn import<R: Rng>(&self, machine: &mut Machine<R>) -> anyhow::Result<()> {
+        let mut b = self.bridge.borrow_mut();
+        machine.swap_memory(&mut b.memory);
+        for (r, v) in b.registers.iter().enumerate() {
+            machine.set_register(r as u8, *v)?;
+        }
+        machine.set_register_i(b.register_i)?;
+        if b.pc != machine.pc() {
+            machine.set_pc(b.pc)?;
+        }
+        machine.set_delay_timer(b.delay_timer);
+        machine.set_sound_timer(b.sound_timer);
+        for ke in b.key_events.drain(..) {
+            machine.input(ke);
+        }
+        for path in b.screenshots.drain(..) {
+            machine.save_screenshot(&path)?;
+        }
+        if b.quit {
+            machine.quit();
+        }
+        Ok(())
+    }
+}
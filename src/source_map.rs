@@ -0,0 +1,98 @@
+//! Maps program addresses to source lines.
+//!
+//! The text format has one entry per line, `ADDRESS FILE LINE`, e.g.
+//! ```text
+//! # generated by the assembler
+//! 0x200 main.8o 12
+//! 0x202 main.8o 13
+//! ```
+//! Relative file names are resolved against the directory of the map file.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceLine {
+    pub file: PathBuf,
+    pub line: u32,
+}
+
+#[derive(Debug, Default)]
+pub struct SourceMap {
+    by_addr: BTreeMap<usize, SourceLine>,
+}
+
+impl SourceMap {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let text = fs::read_to_string(path)?;
+        let base = path.parent().unwrap_or(Path::new("."));
+        Self::parse(&text, base)
+    }
+
+    pub fn parse(text: &str, base: &Path) -> anyhow::Result<Self> {
+        let mut by_addr = BTreeMap::new();
+        for (n, raw) in text.lines().enumerate() {
+            let line = strip_comment(raw);
+            if line.is_empty() {
+                continue;
+            }
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let [addr, file, src_line] = fields[..] else {
+                anyhow::bail!("source map line {}: expected `ADDRESS FILE LINE`", n + 1);
+            };
+            let addr = parse_address(addr)
+                .ok_or_else(|| anyhow::anyhow!("source map line {}: bad address", n + 1))?;
+            let src_line: u32 = src_line.parse()?;
+            by_addr.insert(
+                addr,
+                SourceLine {
+                    file: normalize(&base.join(file)),
+                    line: src_line,
+                },
+            );
+        }
+        Ok(SourceMap { by_addr })
+    }
+
+    /// The source line of the instruction at `addr`
+    pub fn lookup(&self, addr: usize) -> Option<&SourceLine> {
+        self.by_addr.get(&addr)
+    }
+
+    /// The first address generated by `file:line`, or by the closest line after it
+    pub fn address_of(&self, file: &Path, line: u32) -> Option<usize> {
+        let file = normalize(file);
+        self.by_addr
+            .iter()
+            .filter(|(_, s)| s.file == file && s.line >= line)
+            .min_by_key(|(addr, s)| (s.line, **addr))
+            .map(|(addr, _)| *addr)
+    }
+}
+
+fn normalize(path: &Path) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+/// A `#` followed by whitespace starts a comment, `#200` is still an address
+pub fn strip_comment(line: &str) -> &str {
+    let line = line.trim();
+    if line == "#" || line.starts_with("# ") || line.starts_with("#\t") {
+        return "";
+    }
+    match line.find(" # ") {
+        Some(pos) => line[..pos].trim(),
+        None => line,
+    }
+}
+
+/// Accepts `0x200`, `#200`, `$200` and plain hex
+pub fn parse_address(s: &str) -> Option<usize> {
+    let digits = s
+        .strip_prefix("0x")
+        .or_else(|| s.strip_prefix('#'))
+        .or_else(|| s.strip_prefix('$'))
+        .unwrap_or(s);
+    usize::from_str_radix(digits, 16).ok()
+}
@@ -0,0 +1,103 @@
+//! Label to address maps.
+//!
+//! Two formats are read:
+//! - text, one `ADDRESS LABEL` entry per line, e.g. `0x200 main`
+//! - Octo's exported JSON, a `labels` (or `dict`) object of `name: address`
+
+use crate::source_map::{parse_address, strip_comment};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::Path;
+
+#[derive(Debug, Default, Clone)]
+pub struct SymbolMap {
+    by_addr: BTreeMap<usize, String>,
+    by_name: HashMap<String, usize>,
+}
+
+impl SymbolMap {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let text = fs::read_to_string(path)?;
+        if text.trim_start().starts_with('{') {
+            Self::parse_octo(&text)
+        } else {
+            Self::parse(&text)
+        }
+    }
+
+    pub fn parse(text: &str) -> anyhow::Result<Self> {
+        let mut symbols = SymbolMap::default();
+        for (n, raw) in text.lines().enumerate() {
+            let line = strip_comment(raw);
+            if line.is_empty() {
+                continue;
+            }
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let [addr, name] = fields[..] else {
+                anyhow::bail!("symbol file line {}: expected `ADDRESS LABEL`", n + 1);
+            };
+            let addr = parse_address(addr)
+                .ok_or_else(|| anyhow::anyhow!("symbol file line {}: bad address", n + 1))?;
+            symbols.insert(name, addr);
+        }
+        Ok(symbols)
+    }
+
+    pub fn parse_octo(text: &str) -> anyhow::Result<Self> {
+        let json: serde_json::Value = serde_json::from_str(text)?;
+        let Some(labels) = json
+            .get("labels")
+            .or_else(|| json.get("dict"))
+            .and_then(|l| l.as_object())
+        else {
+            anyhow::bail!("octo symbol file has no `labels` object");
+        };
+        let mut symbols = SymbolMap::default();
+        for (name, addr) in labels {
+            let addr = match addr {
+                serde_json::Value::Number(n) => n.as_u64().map(|n| n as usize),
+                serde_json::Value::String(s) => parse_address(s),
+                _ => None,
+            };
+            match addr {
+                Some(addr) => symbols.insert(name, addr),
+                None => anyhow::bail!("octo symbol {} has no address", name),
+            }
+        }
+        Ok(symbols)
+    }
+
+    pub fn insert(&mut self, name: &str, addr: usize) {
+        self.by_addr.entry(addr).or_insert_with(|| name.to_string());
+        self.by_name.insert(name.to_string(), addr);
+    }
+
+    pub fn label(&self, addr: usize) -> Option<&str> {
+        self.by_addr.get(&addr).map(|s| s.as_str())
+    }
+
+    pub fn address(&self, name: &str) -> Option<usize> {
+        self.by_name.get(name).copied()
+    }
+
+    /// Resolves a label, or an address written with a `0x`, `#` or `$` prefix so
+    /// that a misspelt label such as `beef` isn't taken for one
+    pub fn resolve(&self, name_or_addr: &str) -> Option<usize> {
+        self.address(name_or_addr).or_else(|| {
+            ["0x", "#", "$"]
+                .iter()
+                .any(|prefix| name_or_addr.starts_with(prefix))
+                .then(|| parse_address(name_or_addr))
+                .flatten()
+        })
+    }
+
+    /// `main` for a labelled address, `main+4` inside it, otherwise hex
+    pub fn describe(&self, addr: usize) -> String {
+        match self.by_addr.range(..=addr).next_back() {
+            Some((base, name)) if *base == addr => name.clone(),
+            Some((base, name)) => format!("{}+{:x}", name, addr - base),
+            None => format!("{:x}", addr),
+        }
+    }
+}
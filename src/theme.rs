@@ -0,0 +1,171 @@
+use clap::ValueEnum;
+use ratatui::style::Color;
+use serde::Deserialize;
+
+pub type Rgb = [u8; 3];
+
+/// Palette indices, XO-CHIP's second plane and overlap follow at 2 and 3
+pub const BACKGROUND: usize = 0;
+pub const PLANE_1: usize = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ThemeName {
+    /// Light green on the terminal background
+    #[default]
+    Green,
+    /// Amber on the terminal background
+    Amber,
+    /// White on black
+    White,
+    /// Black on white
+    Inverted,
+    /// Octo's default yellow and brown palette
+    Octo,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Theme {
+    /// background, plane 1, plane 2, both planes
+    pub palette: [Rgb; 4],
+    /// leave the terminal's own background behind unlit text cells
+    pub transparent: bool,
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        ThemeName::default().theme()
+    }
+}
+
+impl ThemeName {
+    pub fn theme(&self) -> Theme {
+        let (palette, transparent) = match self {
+            ThemeName::Green => (
+                [
+                    [0x00, 0x00, 0x00],
+                    [0x90, 0xee, 0x90],
+                    [0x2e, 0x8b, 0x57],
+                    [0xd0, 0xff, 0xd0],
+                ],
+                true,
+            ),
+            ThemeName::Amber => (
+                [
+                    [0x00, 0x00, 0x00],
+                    [0xff, 0xb0, 0x00],
+                    [0x80, 0x58, 0x00],
+                    [0xff, 0xd5, 0x80],
+                ],
+                true,
+            ),
+            ThemeName::White => (
+                [
+                    [0x00, 0x00, 0x00],
+                    [0xff, 0xff, 0xff],
+                    [0xaa, 0xaa, 0xaa],
+                    [0x55, 0x55, 0x55],
+                ],
+                false,
+            ),
+            ThemeName::Inverted => (
+                [
+                    [0xff, 0xff, 0xff],
+                    [0x00, 0x00, 0x00],
+                    [0x55, 0x55, 0x55],
+                    [0xaa, 0xaa, 0xaa],
+                ],
+                false,
+            ),
+            ThemeName::Octo => (
+                [
+                    [0x99, 0x66, 0x00],
+                    [0xff, 0xcc, 0x00],
+                    [0xff, 0x66, 0x00],
+                    [0x66, 0x22, 0x00],
+                ],
+                false,
+            ),
+        };
+        Theme {
+            palette,
+            transparent,
+        }
+    }
+}
+
+impl Theme {
+    /// Applies command line overrides, `palette` takes 2 or 4 colours starting at the background
+    pub fn with_overrides(
+        mut self,
+        fg: Option<Rgb>,
+        bg: Option<Rgb>,
+        palette: &[Rgb],
+    ) -> anyhow::Result<Self> {
+        match palette.len() {
+            0 => {}
+            2 | 4 => {
+                self.palette[..palette.len()].copy_from_slice(palette);
+                self.transparent = false;
+            }
+            n => anyhow::bail!("a palette has 2 or 4 colours, got {}", n),
+        }
+        if let Some(fg) = fg {
+            self.palette[PLANE_1] = fg;
+        }
+        if let Some(bg) = bg {
+            self.palette[BACKGROUND] = bg;
+            self.transparent = false;
+        }
+        Ok(self)
+    }
+
+    pub fn rgb(&self, index: usize) -> Rgb {
+        self.palette[index]
+    }
+
+    /// Background faded towards plane 1 by `intensity`
+    pub fn blend(&self, intensity: u8) -> Rgb {
+        let [bg, fg] = [self.palette[BACKGROUND], self.palette[PLANE_1]];
+        let mix = |b: u8, f: u8| {
+            (b as i32 + (f as i32 - b as i32) * intensity as i32 / u8::MAX as i32) as u8
+        };
+        [mix(bg[0], fg[0]), mix(bg[1], fg[1]), mix(bg[2], fg[2])]
+    }
+
+    /// Terminal colour of a pixel lit at `intensity`
+    pub fn intensity_color(&self, intensity: u8) -> Color {
+        match intensity {
+            0 => self.color(BACKGROUND),
+            u8::MAX => self.color(PLANE_1),
+            _ => {
+                let [r, g, b] = self.blend(intensity);
+                Color::Rgb(r, g, b)
+            }
+        }
+    }
+
+    /// Terminal colour of palette entry `index`
+    pub fn color(&self, index: usize) -> Color {
+        if index == BACKGROUND && self.transparent {
+            return Color::Reset;
+        }
+        let [r, g, b] = self.palette[index];
+        Color::Rgb(r, g, b)
+    }
+}
+
+/// Parses `#rrggbb`, `rrggbb` or `#rgb`
+pub fn parse_color(s: &str) -> anyhow::Result<Rgb> {
+    let hex = s.trim().trim_start_matches('#');
+    let digits: Vec<u8> = hex
+        .chars()
+        .map(|c| c.to_digit(16).map(|d| d as u8))
+        .collect::<Option<_>>()
+        .ok_or_else(|| anyhow::anyhow!("invalid colour {}", s))?;
+    match digits[..] {
+        [r, g, b] => Ok([r * 0x11, g * 0x11, b * 0x11]),
+        [r1, r0, g1, g0, b1, b0] => Ok([r1 << 4 | r0, g1 << 4 | g0, b1 << 4 | b0]),
+        _ => anyhow::bail!("invalid colour {}, expected #rrggbb", s),
+    }
+}
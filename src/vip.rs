@@ -0,0 +1,133 @@
+//! Machine code calls the way the COSMAC VIP's CHIP-8 interpreter made them.
+//!
+//! `0NNN` jumped to 1802 code at NNN with R3 as the program counter, and the
+//! code returned to the interpreter with `D4` (`SEP R4`). The interpreter kept
+//! V0-VF at 0xEF0 and the display at 0xF00, one bit per pixel, and I in RA and
+//! the CHIP-8 program counter in R5, so routines worked on them directly. The
+//! interpreter itself sat in 0x000-0x1FF and the digit sprites in the monitor
+//! ROM. The interpreter isn't bundled, so its area is filled with `D4` unless an
+//! image of it is loaded, and a routine that calls into it returns straight to
+//! the CHIP-8 program.
+
+use crate::cdp1802::{Cdp1802, Io};
+use crate::machine::{DISPLAY_HEIGHT, DISPLAY_WIDTH, REGISTER_COUNT};
+
+const REGISTERS: usize = 0xEF0;
+const DISPLAY: usize = 0xF00;
+/// The top of the 1802 stack R2 points at, below the interpreter's work area
+const STACK: u16 = 0xECF;
+/// The interpreter's size, and where programs start
+const INTERPRETER_SIZE: usize = 0x200;
+/// `SEP R4`, which hands control back to the interpreter
+const RETURN: u8 = 0xD4;
+/// Where `FX29` points I, the page of the monitor ROM holding the digits
+pub const DIGITS: usize = 0x8100;
+/// Instructions a routine may run before it is taken to be stuck
+const LIMIT: u64 = 10_000_000;
+
+/// The VIP's hex keypad: `OUT 2` latches the key to test, and EF3 is set while
+/// it is held
+struct Keypad<'a> {
+    keys: &'a [bool; 16],
+    latch: &'a mut u8,
+}
+
+impl Io for Keypad<'_> {
+    fn output(&mut self, port: u8, value: u8) {
+        if port == 2 {
+            *self.latch = value & 0xF;
+        }
+    }
+
+    fn input(&mut self, _port: u8) -> u8 {
+        0
+    }
+
+    fn flag(&mut self, n: u8) -> bool {
+        n == 3 && self.keys[*self.latch as usize]
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Vip {
+    cpu: Cdp1802,
+    latch: u8,
+    /// the interpreter image loaded at 0x000
+    pub interpreter: Vec<u8>,
+}
+
+/// What fills the interpreter's area when no image of it is loaded
+pub fn stand_in() -> Vec<u8> {
+    vec![RETURN; INTERPRETER_SIZE]
+}
+
+impl Vip {
+    pub fn new(interpreter: Vec<u8>) -> anyhow::Result<Self> {
+        if interpreter.len() > INTERPRETER_SIZE {
+            anyhow::bail!(
+                "the interpreter image is {} bytes, the VIP keeps {} for it",
+                interpreter.len(),
+                INTERPRETER_SIZE
+            );
+        }
+        Ok(Vip {
+            interpreter,
+            ..Default::default()
+        })
+    }
+
+    /// Runs the routine at `entry` with the registers set up as the interpreter
+    /// left them, and returns the program counter and I it leaves
+    pub fn call(
+        &mut self,
+        memory: &mut [u8],
+        keys: &[bool; 16],
+        entry: u16,
+        pc: u16,
+        i: u16,
+    ) -> anyhow::Result<(u16, u16)> {
+        let cpu = &mut self.cpu;
+        cpu.r[2] = STACK;
+        cpu.r[3] = entry;
+        cpu.r[5] = pc;
+        cpu.r[0xA] = i;
+        cpu.r[0xB] = DISPLAY as u16;
+        (cpu.p, cpu.x) = (3, 2);
+        let mut keypad = Keypad {
+            keys,
+            latch: &mut self.latch,
+        };
+        cpu.run(memory, &mut keypad, 4, LIMIT)?;
+        Ok((cpu.r[5], cpu.r[0xA]))
+    }
+}
+
+/// Copies V0-VF and the display to where the interpreter kept them
+pub fn store(
+    memory: &mut [u8],
+    registers: &[u8; REGISTER_COUNT],
+    display: &[[bool; DISPLAY_WIDTH]; DISPLAY_HEIGHT],
+) {
+    memory[REGISTERS..REGISTERS + REGISTER_COUNT].copy_from_slice(registers);
+    for (y, row) in display.iter().enumerate() {
+        for (x, pixels) in row.chunks(8).enumerate() {
+            memory[DISPLAY + y * DISPLAY_WIDTH / 8 + x] =
+                pixels.iter().fold(0, |byte, &lit| byte << 1 | lit as u8);
+        }
+    }
+}
+
+/// Reads V0-VF and the display back after a routine ran
+pub fn restore(
+    memory: &[u8],
+    registers: &mut [u8; REGISTER_COUNT],
+    display: &mut [[bool; DISPLAY_WIDTH]; DISPLAY_HEIGHT],
+) {
+    registers.copy_from_slice(&memory[REGISTERS..REGISTERS + REGISTER_COUNT]);
+    for (y, row) in display.iter_mut().enumerate() {
+        for (x, lit) in row.iter_mut().enumerate() {
+            let byte = memory[DISPLAY + y * DISPLAY_WIDTH / 8 + x / 8];
+            *lit = byte & 0x80 >> (x % 8) != 0;
+        }
+    }
+}